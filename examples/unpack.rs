@@ -11,17 +11,105 @@ use std::{
 };
 
 use clap::Parser;
-use oci_unpack::{EventHandler, Reference, Unpacker};
+use oci_unpack::{
+    Blob, Compression, DeviceNodePolicy, EventHandler, ForeignLayerPolicy, IdMapping, Platform,
+    Reference, Unpacker,
+};
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// CPU architecture to download.
+    /// Platform to download, like `linux/arm64`.
+    ///
+    /// It can be repeated to try multiple platforms, in order.
     #[arg(short, long)]
-    arch: Option<String>,
-
-    /// Operating system to download.
+    platform: Vec<Platform>,
+
+    /// Unpack every platform in the image index.
+    ///
+    /// If `--platform` is present, only those platforms are unpacked.
+    #[arg(long)]
+    all_platforms: bool,
+
+    /// What to do with foreign layers: `download`, `skip`, or `reject`.
+    #[arg(long, default_value = "reject", value_parser = parse_foreign_layers)]
+    foreign_layers: ForeignLayerPolicy,
+
+    /// What to do with device nodes that can't be created: `skip`,
+    /// `placeholder`, or `record`.
+    #[arg(long, default_value = "skip", value_parser = parse_device_nodes)]
+    device_nodes: DeviceNodePolicy,
+
+    /// Private key (in PEM format) to decrypt encrypted layers.
+    ///
+    /// It can be repeated to try multiple keys.
+    #[cfg(feature = "encryption")]
+    #[arg(long)]
+    decryption_key: Vec<PathBuf>,
+
+    /// Generate a runtime bundle, to run the image with `runc run`.
     #[arg(short, long)]
-    os: Option<String>,
+    runtime_bundle: bool,
+
+    /// Write a mtree manifest of the unpacked files.
+    #[arg(long)]
+    mtree: bool,
+
+    /// Map user IDs in the image to the host, as `container:host:size`.
+    ///
+    /// It can be repeated to add multiple ranges.
+    #[arg(long, value_parser = parse_id_mapping)]
+    uid_map: Vec<IdMapping>,
+
+    /// Map group IDs in the image to the host, as `container:host:size`.
+    ///
+    /// It can be repeated to add multiple ranges.
+    #[arg(long, value_parser = parse_id_mapping)]
+    gid_map: Vec<IdMapping>,
+
+    /// Fail if the owner of a file can't be changed.
+    #[arg(long)]
+    require_chown: bool,
+
+    /// Store the owners in the `user.rootlesscontainers` xattr, instead
+    /// of changing them.
+    #[arg(long)]
+    rootless_xattr: bool,
+
+    /// Don't extract extended attributes.
+    #[arg(long)]
+    no_xattrs: bool,
+
+    /// Ignore extended attributes whose name starts with this prefix.
+    ///
+    /// It can be repeated to ignore multiple namespaces.
+    #[arg(long)]
+    skip_xattrs: Vec<String>,
+
+    /// Unpack each layer in a subdirectory of this path, to mount the
+    /// image with overlayfs.
+    #[arg(long)]
+    overlay_layers: Option<PathBuf>,
+
+    /// Use `user.overlay.*` xattrs for opaque directories.
+    #[arg(long)]
+    overlay_userxattr: bool,
+
+    /// Share the contents of regular files with other images unpacked
+    /// with the same store.
+    #[arg(long)]
+    content_store: Option<PathBuf>,
+
+    /// Hard-link files to the content store, instead of cloning them.
+    #[arg(long)]
+    content_store_hardlinks: bool,
+
+    /// Extract the next layer while it is downloaded.
+    #[arg(long)]
+    stream_layers: bool,
+
+    /// Number of threads to write the files of a layer.
+    #[arg(long, default_value_t = 1)]
+    extract_threads: usize,
 
     /// Skip sandbox if it can't be created.
     #[arg(short, long)]
@@ -126,6 +214,12 @@ impl EventHandler for Logger {
         }
     }
 
+    fn external_request(&self, url: &str) {
+        if self.debug {
+            println!("GET {url}");
+        }
+    }
+
     fn registry_auth(&self, url: &str) {
         if self.debug {
             println!("AUTH {url}");
@@ -161,6 +255,22 @@ impl EventHandler for Logger {
         println!("{path:?}: {cause}");
     }
 
+    fn xattr_skipped(&self, path: &std::path::Path, name: &str, cause: &dyn fmt::Display) {
+        println!("{path:?}: {name}: {cause}");
+    }
+
+    fn foreign_layer_skipped(&self, blob: &Blob) {
+        println!("Skipped foreign layer {}", blob.digest.source());
+    }
+
+    fn layer_compression_mismatch(&self, blob: &Blob, detected: Compression) {
+        println!(
+            "Layer {} is compressed with {detected}, but its media type is {}",
+            blob.digest.source(),
+            blob.media_type,
+        );
+    }
+
     #[cfg(feature = "sandbox")]
     fn sandbox_status(&self, status: landlock::RestrictionStatus) {
         if self.debug {
@@ -198,21 +308,99 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut unpacker = Unpacker::new(Reference::try_from(args.image.as_str())?)
         .event_handler(event_handler)
-        .require_sandbox(!args.can_skip_sandbox);
+        .require_sandbox(!args.can_skip_sandbox)
+        .foreign_layers(args.foreign_layers)
+        .device_nodes(args.device_nodes)
+        .runtime_bundle(args.runtime_bundle)
+        .mtree(args.mtree)
+        .uid_map(args.uid_map)
+        .gid_map(args.gid_map)
+        .require_chown(args.require_chown)
+        .rootless_xattr(args.rootless_xattr)
+        .xattrs(!args.no_xattrs)
+        .overlay_userxattr(args.overlay_userxattr)
+        .content_store_hardlinks(args.content_store_hardlinks)
+        .stream_layers(args.stream_layers)
+        .extract_threads(args.extract_threads);
+
+    if let Some(store) = args.overlay_layers {
+        unpacker = unpacker.overlay_layers(store);
+    }
 
-    if let Some(arch) = &args.arch {
-        unpacker = unpacker.architecture(arch);
+    if let Some(store) = args.content_store {
+        unpacker = unpacker.content_store(store);
     }
 
-    if let Some(os) = &args.os {
-        unpacker = unpacker.os(os);
+    for prefix in args.skip_xattrs {
+        unpacker = unpacker.skip_xattrs(prefix);
     }
 
-    unpacker.unpack(args.target)?;
+    for platform in args.platform {
+        unpacker = unpacker.platform(platform);
+    }
+
+    #[cfg(feature = "encryption")]
+    for path in args.decryption_key {
+        let pem = std::fs::read_to_string(&path)?;
+        unpacker = unpacker.decryption_key(oci_unpack::DecryptionKey::from_pem(&pem)?);
+    }
+
+    if args.all_platforms {
+        for image in unpacker.unpack_platforms(args.target)? {
+            println!("{}: {}", image.platform, image.path.display());
+        }
+    } else {
+        let image = unpacker.unpack(args.target)?;
+
+        if let Some(digest) = &image.index_digest {
+            println!("index: {}", digest.source());
+        }
+
+        println!("manifest: {}", image.manifest_digest.source());
+        println!("config: {}", image.config_digest.source());
+
+        if !image.lower_dirs.is_empty() {
+            let lower_dirs: Vec<_> = image
+                .lower_dirs
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect();
+            println!("lowerdir: {}", lower_dirs.join(":"));
+        }
+    }
 
     Ok(())
 }
 
+fn parse_foreign_layers(arg: &str) -> Result<ForeignLayerPolicy, String> {
+    match arg {
+        "download" => Ok(ForeignLayerPolicy::Download),
+        "skip" => Ok(ForeignLayerPolicy::Skip),
+        "reject" => Ok(ForeignLayerPolicy::Reject),
+        _ => Err(format!("invalid policy: {arg}")),
+    }
+}
+
+fn parse_device_nodes(arg: &str) -> Result<DeviceNodePolicy, String> {
+    match arg {
+        "skip" => Ok(DeviceNodePolicy::Skip),
+        "placeholder" => Ok(DeviceNodePolicy::Placeholder),
+        "record" => Ok(DeviceNodePolicy::Record),
+        _ => Err(format!("invalid policy: {arg}")),
+    }
+}
+
+fn parse_id_mapping(arg: &str) -> Result<IdMapping, String> {
+    let fields: Vec<_> = arg.split(':').map(str::parse).collect();
+
+    match fields[..] {
+        [Ok(container_id), Ok(host_id), Ok(size)] => {
+            Ok(IdMapping::new(container_id, host_id, size))
+        }
+        _ => Err(format!("invalid mapping: {arg}")),
+    }
+}
+
 fn main() -> ExitCode {
     if let Err(e) = run() {
         eprintln!("{}", e);
@@ -0,0 +1,195 @@
+//! Types for the image configuration.
+//!
+//! See the [OCI specification][spec] for more details.
+//!
+//! [spec]: https://github.com/opencontainers/image-spec/blob/main/config.md
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::Digest;
+
+/// Configuration of an image.
+///
+/// Fields that are missing (or `null`) in the JSON document get
+/// their default value.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct ImageConfig {
+    /// Date and time (RFC 3339) when the image was created.
+    #[serde(default)]
+    pub created: Option<String>,
+
+    /// Name and/or email of the author of the image.
+    #[serde(default)]
+    pub author: Option<String>,
+
+    /// CPU architecture of the binaries in the image.
+    #[serde(default, deserialize_with = "null_default")]
+    pub architecture: String,
+
+    /// Operating system of the image.
+    #[serde(default, deserialize_with = "null_default")]
+    pub os: String,
+
+    /// Variant of the CPU.
+    #[serde(default)]
+    pub variant: Option<String>,
+
+    /// Execution parameters to use when a container is created.
+    #[serde(default, deserialize_with = "null_default")]
+    pub config: ContainerConfig,
+
+    /// Layer content addresses.
+    #[serde(default)]
+    pub rootfs: Option<RootFs>,
+
+    /// History of each layer.
+    #[serde(default, deserialize_with = "null_default")]
+    pub history: Vec<History>,
+}
+
+/// Execution parameters of an image.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase")]
+#[non_exhaustive]
+pub struct ContainerConfig {
+    /// User (name or UID, and optionally a group) to run the process.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Ports to expose, like `80/tcp`.
+    #[serde(default, deserialize_with = "map_keys")]
+    pub exposed_ports: BTreeSet<String>,
+
+    /// Environment variables, in the `NAME=value` format.
+    #[serde(default, deserialize_with = "null_default")]
+    pub env: Vec<String>,
+
+    /// Arguments to use as the command to execute.
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// Default arguments for the entrypoint.
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+
+    /// Directories where the process is likely to write data.
+    #[serde(default, deserialize_with = "map_keys")]
+    pub volumes: BTreeSet<String>,
+
+    /// Current working directory of the process.
+    #[serde(default)]
+    pub working_dir: Option<String>,
+
+    /// Arbitrary metadata for the image.
+    #[serde(default, deserialize_with = "null_default")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Signal to stop the process.
+    #[serde(default)]
+    pub stop_signal: Option<String>,
+}
+
+/// Content addresses of the layers.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct RootFs {
+    /// Always `layers`.
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Digests of the uncompressed layers, from the lowest to the uppermost.
+    #[serde(default, deserialize_with = "null_default")]
+    pub diff_ids: Vec<Digest>,
+}
+
+/// History entry of a layer.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct History {
+    /// Date and time (RFC 3339) when the layer was created.
+    #[serde(default)]
+    pub created: Option<String>,
+
+    /// Command to create the layer.
+    #[serde(default)]
+    pub created_by: Option<String>,
+
+    /// Author of the layer.
+    #[serde(default)]
+    pub author: Option<String>,
+
+    /// Custom message for the layer.
+    #[serde(default)]
+    pub comment: Option<String>,
+
+    /// If `true`, this history entry has no layer in the manifest.
+    #[serde(default)]
+    pub empty_layer: bool,
+}
+
+/// Use the default value if the field is `null`.
+fn null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Collect the keys of an object like `{"80/tcp": {}}`.
+fn map_keys<'de, D>(deserializer: D) -> Result<BTreeSet<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map: Option<BTreeMap<String, serde::de::IgnoredAny>> = Option::deserialize(deserializer)?;
+    Ok(map.map(|m| m.into_keys().collect()).unwrap_or_default())
+}
+
+#[test]
+fn parse_image_config() {
+    let config: ImageConfig = serde_json::from_str(
+        r#"{
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {
+                "User": "1000:1000",
+                "ExposedPorts": { "80/tcp": {}, "443/tcp": {} },
+                "Env": [ "PATH=/usr/bin" ],
+                "Entrypoint": null,
+                "Cmd": [ "/bin/sh" ],
+                "Volumes": null,
+                "WorkingDir": "/app",
+                "Labels": { "a": "b" },
+                "StopSignal": "SIGTERM"
+            },
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": [
+                    "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                ]
+            },
+            "history": [
+                { "created_by": "/bin/sh -c #(nop) ADD file" },
+                { "created_by": "/bin/sh -c #(nop) CMD", "empty_layer": true }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.architecture, "amd64");
+    assert_eq!(config.config.user.as_deref(), Some("1000:1000"));
+    assert_eq!(
+        config.config.exposed_ports.into_iter().collect::<Vec<_>>(),
+        ["443/tcp", "80/tcp"]
+    );
+    assert_eq!(config.config.env, ["PATH=/usr/bin"]);
+    assert_eq!(config.config.entrypoint, None);
+    assert_eq!(config.config.cmd, Some(vec!["/bin/sh".to_string()]));
+    assert!(config.config.volumes.is_empty());
+    assert_eq!(config.config.labels["a"], "b");
+    assert_eq!(config.rootfs.unwrap().diff_ids.len(), 1);
+    assert!(config.history[1].empty_layer);
+}
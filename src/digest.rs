@@ -64,6 +64,33 @@ impl Digest {
         self.algorithm
     }
 
+    /// Compute the SHA256 digest of `data`.
+    pub(crate) fn sha256(data: &[u8]) -> Digest {
+        Digest::from_sha256(sha2::Sha256::digest(data).into())
+    }
+
+    /// Build a digest from a SHA256 hash value.
+    pub(crate) fn from_sha256(hash: [u8; 32]) -> Digest {
+        Digest {
+            hash: format!("sha256:{}", HexString(hash)),
+            algorithm: DigestAlgorithm::SHA256,
+        }
+    }
+
+    /// Return `true` if both digests have the same algorithm and value.
+    ///
+    /// Unlike `==`, the hexadecimal digits are compared ignoring case.
+    pub(crate) fn same_hash(&self, other: &Digest) -> bool {
+        self.algorithm == other.algorithm
+            && self.hash_value().eq_ignore_ascii_case(other.hash_value())
+    }
+
+    /// Verify that `data` has the expected digest.
+    pub(crate) fn verify(&self, data: &[u8]) -> io::Result<()> {
+        io::copy(&mut self.wrap_reader(data), &mut io::sink())?;
+        Ok(())
+    }
+
     /// Return a `Read` instance to compute its digest.
     ///
     /// When all data from `reader` is consumed, it verifies that the
@@ -80,6 +107,7 @@ impl Digest {
             hasher,
             expected: self.hash_value().to_owned(),
             reader,
+            verified: false,
         }
     }
 }
@@ -112,6 +140,9 @@ struct DigestReader<R> {
     hasher: Box<dyn digest::DynDigest>,
     expected: String,
     reader: R,
+
+    /// The digest was verified, so later reads at EOF are ignored.
+    verified: bool,
 }
 
 impl<R: Read> Read for DigestReader<R> {
@@ -120,8 +151,14 @@ impl<R: Read> Read for DigestReader<R> {
         let n = self.reader.read(buf)?;
 
         if n == 0 && buf_len > 0 {
+            if self.verified {
+                return Ok(0);
+            }
+
             // On EOF, compare the computed digest with the expected one.
-            return self.check_hash();
+            self.check_hash()?;
+            self.verified = true;
+            return Ok(0);
         }
 
         self.hasher.update(&buf[..n]);
@@ -168,6 +205,57 @@ impl<R> DigestReader<R> {
     }
 }
 
+/// Compute the digest of the data read from `reader`, without
+/// verifying it.
+pub(crate) struct HashingReader<R> {
+    hasher: Option<(DigestAlgorithm, Box<dyn digest::DynDigest>)>,
+    reader: R,
+}
+
+impl<R> HashingReader<R> {
+    /// Create a new reader. If `algorithm` is `None`, the data is
+    /// not hashed.
+    pub(crate) fn new(algorithm: Option<DigestAlgorithm>, reader: R) -> Self {
+        let hasher = algorithm.map(|algorithm| {
+            let hasher: Box<dyn digest::DynDigest> = match algorithm {
+                DigestAlgorithm::SHA256 => Box::new(sha2::Sha256::new()),
+                DigestAlgorithm::SHA512 => Box::new(sha2::Sha512::new()),
+            };
+
+            (algorithm, hasher)
+        });
+
+        HashingReader { hasher, reader }
+    }
+
+    /// Return the digest of the data read so far.
+    pub(crate) fn digest(self) -> Option<Digest> {
+        let (algorithm, hasher) = self.hasher?;
+
+        let prefix = match algorithm {
+            DigestAlgorithm::SHA256 => "sha256",
+            DigestAlgorithm::SHA512 => "sha512",
+        };
+
+        Some(Digest {
+            hash: format!("{prefix}:{}", HexString(hasher.finalize())),
+            algorithm,
+        })
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+
+        if let Some((_, hasher)) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+
+        Ok(n)
+    }
+}
+
 /// Encode a byte buffer as hex string.
 pub(crate) struct HexString<T>(pub T);
 
@@ -185,6 +273,22 @@ fn encode_hex_bytes() {
     assert_eq!(HexString(b"\x01\x20\xf0").to_string(), "0120f0");
 }
 
+#[test]
+fn compute_digest() {
+    let mut reader = HashingReader::new(Some(DigestAlgorithm::SHA256), &b"abc"[..]);
+    io::copy(&mut reader, &mut io::sink()).unwrap();
+
+    let expected = Digest::try_from(
+        "sha256:BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string(),
+    )
+    .unwrap();
+
+    assert!(reader.digest().unwrap().same_hash(&expected));
+
+    let reader = HashingReader::new(None, &b"abc"[..]);
+    assert!(reader.digest().is_none());
+}
+
 #[test]
 fn reject_invalid_digest() {
     use std::io::Cursor;
@@ -196,13 +300,14 @@ fn reject_invalid_digest() {
     let mut output = Vec::new();
 
     // Accept a valid digest.
-    digest
-        .wrap_reader(Cursor::new("abc"))
-        .read_to_end(&mut output)
-        .unwrap();
+    let mut reader = digest.wrap_reader(Cursor::new("abc"));
+    reader.read_to_end(&mut output).unwrap();
 
     assert_eq!(output, b"abc");
 
+    // Reads after EOF are still valid.
+    assert_eq!(reader.read(&mut [0; 8]).unwrap(), 0);
+
     // Reject an invalid digest.
     output.clear();
     let err = digest
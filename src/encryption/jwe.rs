@@ -0,0 +1,236 @@
+//! Minimal JWE implementation to unwrap the keys of encrypted layers.
+//!
+//! Only the algorithms used by ocicrypt are supported: `RSA-OAEP` and
+//! `ECDH-ES+A256KW` for the key, and `A256GCM` for the content.
+//!
+//! Refs:
+//!
+//! * <https://www.rfc-editor.org/rfc/rfc7516>
+//! * <https://www.rfc-editor.org/rfc/rfc7518>
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest as _, Sha256};
+
+use super::{DecryptionError, DecryptionKey, Key};
+
+type Header = Map<String, Value>;
+
+/// JWE object, in either the general or the flattened JSON serialization.
+#[derive(Deserialize)]
+struct Jwe {
+    protected: Option<String>,
+    unprotected: Option<Header>,
+    header: Option<Header>,
+    encrypted_key: Option<String>,
+    recipients: Option<Vec<Recipient>>,
+    iv: String,
+    ciphertext: String,
+    tag: String,
+    aad: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Recipient {
+    header: Option<Header>,
+    encrypted_key: Option<String>,
+}
+
+/// Decrypt the payload of a JWE object with the first key that can
+/// unwrap the content encryption key of any of its recipients.
+pub(super) fn decrypt(object: &[u8], keys: &[DecryptionKey]) -> Result<Vec<u8>, DecryptionError> {
+    let jwe: Jwe =
+        serde_json::from_slice(object).map_err(|_| DecryptionError::InvalidJwe("invalid JSON"))?;
+
+    let protected: Header = match &jwe.protected {
+        Some(p) => decode(p)
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(DecryptionError::InvalidJwe("invalid protected header"))?,
+
+        None => Header::new(),
+    };
+
+    let recipients = match jwe.recipients {
+        Some(r) => r,
+        None => vec![Recipient {
+            header: jwe.header,
+            encrypted_key: jwe.encrypted_key,
+        }],
+    };
+
+    // Additional authenticated data for the content encryption.
+    let mut aad = jwe.protected.unwrap_or_default();
+    if let Some(extra) = &jwe.aad {
+        aad.push('.');
+        aad.push_str(extra);
+    }
+
+    let (Some(iv), Some(ciphertext), Some(tag)) =
+        (decode(&jwe.iv), decode(&jwe.ciphertext), decode(&jwe.tag))
+    else {
+        return Err(DecryptionError::InvalidJwe("invalid encoding"));
+    };
+
+    if iv.len() != 12 || tag.len() != 16 {
+        return Err(DecryptionError::InvalidJwe("invalid IV or tag"));
+    }
+
+    // Algorithm to report if no recipient can be used.
+    let mut unsupported = None;
+    let mut any_supported = false;
+
+    for recipient in recipients {
+        // Merge all headers for this recipient.
+        let mut header = protected.clone();
+        for h in [&jwe.unprotected, &recipient.header].into_iter().flatten() {
+            header.extend(h.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+
+        let param = |name| header.get(name).and_then(Value::as_str).unwrap_or_default();
+
+        if param("enc") != "A256GCM" {
+            unsupported = Some(param("enc").to_owned());
+            continue;
+        }
+
+        let alg = param("alg");
+        if alg != "RSA-OAEP" && alg != "ECDH-ES+A256KW" {
+            unsupported = Some(alg.to_owned());
+            continue;
+        }
+
+        any_supported = true;
+
+        let encrypted_key = recipient
+            .encrypted_key
+            .as_deref()
+            .and_then(decode)
+            .unwrap_or_default();
+
+        for key in keys {
+            let cek = match alg {
+                "RSA-OAEP" => rsa_oaep(key, &encrypted_key),
+                _ => ecdh_es_a256kw(key, &header, &encrypted_key),
+            };
+
+            let Some(cek) = cek.filter(|k| k.len() == 32) else {
+                continue;
+            };
+
+            // If the key is not the right one, the tag can't be verified.
+            let cipher = Aes256Gcm::new_from_slice(&cek).expect("valid key length");
+            let mut payload = ciphertext.clone();
+            if cipher
+                .decrypt_in_place_detached(
+                    iv.as_slice().into(),
+                    aad.as_bytes(),
+                    &mut payload,
+                    tag.as_slice().into(),
+                )
+                .is_ok()
+            {
+                return Ok(payload);
+            }
+        }
+    }
+
+    match unsupported {
+        Some(alg) if !any_supported => Err(DecryptionError::UnsupportedAlgorithm(alg)),
+        _ => Err(DecryptionError::NoMatchingKey),
+    }
+}
+
+/// Decode a base64url string.
+fn decode(data: &str) -> Option<Vec<u8>> {
+    BASE64_URL.decode(data.trim_end_matches('=')).ok()
+}
+
+/// Unwrap a key with `RSA-OAEP` (SHA-1 for both the hash and MGF1).
+fn rsa_oaep(key: &DecryptionKey, encrypted_key: &[u8]) -> Option<Vec<u8>> {
+    match &key.0 {
+        Key::Rsa(key) => key
+            .decrypt(rsa::Oaep::new::<sha1::Sha1>(), encrypted_key)
+            .ok(),
+
+        _ => None,
+    }
+}
+
+/// Unwrap a key with `ECDH-ES+A256KW`.
+///
+/// The key encryption key is derived with the Concat KDF from the
+/// shared secret between `key` and the ephemeral public key in the
+/// `epk` header.
+fn ecdh_es_a256kw(key: &DecryptionKey, header: &Header, encrypted_key: &[u8]) -> Option<Vec<u8>> {
+    let epk = header.get("epk")?;
+    let coordinate = |name| epk.get(name).and_then(Value::as_str).and_then(decode);
+
+    // Public key as an uncompressed SEC1 point.
+    let mut point = vec![4];
+    point.extend(coordinate("x")?);
+    point.extend(coordinate("y")?);
+
+    let curve = epk.get("crv").and_then(Value::as_str)?;
+
+    let shared_secret = match (&key.0, curve) {
+        (Key::P256(key), "P-256") => p256::ecdh::diffie_hellman(
+            key.to_nonzero_scalar(),
+            p256::PublicKey::from_sec1_bytes(&point).ok()?.as_affine(),
+        )
+        .raw_secret_bytes()
+        .to_vec(),
+
+        (Key::P384(key), "P-384") => p384::ecdh::diffie_hellman(
+            key.to_nonzero_scalar(),
+            p384::PublicKey::from_sec1_bytes(&point).ok()?.as_affine(),
+        )
+        .raw_secret_bytes()
+        .to_vec(),
+
+        (Key::P521(key), "P-521") => p521::ecdh::diffie_hellman(
+            key.to_nonzero_scalar(),
+            p521::PublicKey::from_sec1_bytes(&point).ok()?.as_affine(),
+        )
+        .raw_secret_bytes()
+        .to_vec(),
+
+        _ => return None,
+    };
+
+    let party_info = |name| {
+        header
+            .get(name)
+            .and_then(Value::as_str)
+            .map_or(Some(Vec::new()), decode)
+    };
+
+    let kek = concat_kdf(
+        &shared_secret,
+        "ECDH-ES+A256KW",
+        &party_info("apu")?,
+        &party_info("apv")?,
+    );
+
+    aes_kw::KekAes256::from(kek).unwrap_vec(encrypted_key).ok()
+}
+
+/// Derive a 256-bit key with the Concat KDF (NIST SP 800-56A), as
+/// described in RFC 7518, section 4.6.2.
+fn concat_kdf(shared_secret: &[u8], algorithm: &str, apu: &[u8], apv: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    // A single round is enough for a 256-bit key.
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(shared_secret);
+
+    for field in [algorithm.as_bytes(), apu, apv] {
+        hasher.update((field.len() as u32).to_be_bytes());
+        hasher.update(field);
+    }
+
+    hasher.update(256u32.to_be_bytes());
+
+    hasher.finalize().into()
+}
@@ -0,0 +1,304 @@
+//! Decryption of layers encrypted with [ocicrypt].
+//!
+//! The layer is encrypted with a symmetric key (AES-256-CTR, with an
+//! HMAC-SHA256 of the ciphertext). The key is stored in a JWE object,
+//! wrapped with the public key of each recipient.
+//!
+//! [ocicrypt]: https://github.com/containers/ocicrypt/blob/main/docs/spec.md
+
+mod jwe;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read},
+};
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+
+use crate::{manifests::Blob, Digest, MediaType};
+
+/// Annotation with the JWE objects to get the symmetric key.
+const KEYS_ANNOTATION: &str = "org.opencontainers.image.enc.keys.jwe";
+
+/// Annotation with the public options of the cipher.
+const PUBOPTS_ANNOTATION: &str = "org.opencontainers.image.enc.pubopts";
+
+/// The only cipher defined by ocicrypt.
+const CIPHER_AES_CTR: &str = "AES_256_CTR_HMAC_SHA256";
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// Errors from [`DecryptionKey`] parsers.
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("Invalid or unsupported private key.")]
+    InvalidKey,
+}
+
+/// Errors to decrypt a layer.
+#[derive(thiserror::Error, Debug)]
+pub enum DecryptionError {
+    #[error("Missing annotation: {0}")]
+    MissingAnnotation(&'static str),
+
+    #[error("Invalid annotation: {0}")]
+    InvalidAnnotation(&'static str),
+
+    #[error("Invalid JWE object: {0}")]
+    InvalidJwe(&'static str),
+
+    #[error("Unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Unsupported cipher: {0}")]
+    UnsupportedCipher(String),
+
+    #[error("No key to decrypt the layer.")]
+    NoMatchingKey,
+}
+
+/// Private key to decrypt layers.
+///
+/// RSA keys are used with `RSA-OAEP`, and EC keys (P-256, P-384, and
+/// P-521) are used with `ECDH-ES+A256KW`.
+pub struct DecryptionKey(Key);
+
+enum Key {
+    Rsa(Box<rsa::RsaPrivateKey>),
+    P256(p256::SecretKey),
+    P384(p384::SecretKey),
+    P521(p521::SecretKey),
+}
+
+impl DecryptionKey {
+    /// Parse a private key in PEM format.
+    ///
+    /// The key can be in PKCS #8, PKCS #1 (for RSA keys), or SEC1 (for
+    /// EC keys) formats. Encrypted keys are not supported.
+    pub fn from_pem(pem: &str) -> Result<Self, KeyError> {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+        use rsa::pkcs8::DecodePrivateKey;
+
+        let key = if let Ok(key) = rsa::RsaPrivateKey::from_pkcs8_pem(pem) {
+            Key::Rsa(Box::new(key))
+        } else if let Ok(key) = rsa::RsaPrivateKey::from_pkcs1_pem(pem) {
+            Key::Rsa(Box::new(key))
+        } else if let Ok(key) = p256::SecretKey::from_pkcs8_pem(pem) {
+            Key::P256(key)
+        } else if let Ok(key) = p256::SecretKey::from_sec1_pem(pem) {
+            Key::P256(key)
+        } else if let Ok(key) = p384::SecretKey::from_pkcs8_pem(pem) {
+            Key::P384(key)
+        } else if let Ok(key) = p384::SecretKey::from_sec1_pem(pem) {
+            Key::P384(key)
+        } else if let Ok(key) = p521::SecretKey::from_pkcs8_pem(pem) {
+            Key::P521(key)
+        } else if let Ok(key) = p521::SecretKey::from_sec1_pem(pem) {
+            Key::P521(key)
+        } else {
+            return Err(KeyError::InvalidKey);
+        };
+
+        Ok(DecryptionKey(key))
+    }
+
+    /// Parse a private key in PKCS #8 DER format.
+    pub fn from_der(der: &[u8]) -> Result<Self, KeyError> {
+        use rsa::pkcs8::DecodePrivateKey;
+
+        let key = if let Ok(key) = rsa::RsaPrivateKey::from_pkcs8_der(der) {
+            Key::Rsa(Box::new(key))
+        } else if let Ok(key) = p256::SecretKey::from_pkcs8_der(der) {
+            Key::P256(key)
+        } else if let Ok(key) = p384::SecretKey::from_pkcs8_der(der) {
+            Key::P384(key)
+        } else if let Ok(key) = p521::SecretKey::from_pkcs8_der(der) {
+            Key::P521(key)
+        } else {
+            return Err(KeyError::InvalidKey);
+        };
+
+        Ok(DecryptionKey(key))
+    }
+}
+
+impl fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &self.0 {
+            Key::Rsa(_) => "RSA",
+            Key::P256(_) => "P-256",
+            Key::P384(_) => "P-384",
+            Key::P521(_) => "P-521",
+        };
+
+        write!(f, "DecryptionKey({kind})")
+    }
+}
+
+/// Return the media type of the layer after decrypting it, or `None`
+/// if the layer is not encrypted.
+pub(crate) fn decrypted_media_type(media_type: MediaType) -> Option<MediaType> {
+    match media_type {
+        MediaType::OciFsTarEncrypted => Some(MediaType::OciFsTar),
+        MediaType::OciFsTarGzipEncrypted => Some(MediaType::OciFsTarGzip),
+        MediaType::OciFsTarZstdEncrypted => Some(MediaType::OciFsTarZstd),
+        _ => None,
+    }
+}
+
+/// Options in the `pubopts` annotation.
+#[derive(Deserialize)]
+struct PublicOptions {
+    cipher: String,
+
+    #[serde(deserialize_with = "base64_bytes")]
+    hmac: Vec<u8>,
+}
+
+/// Options in the payload of the JWE object.
+#[derive(Deserialize)]
+struct PrivateOptions {
+    #[serde(deserialize_with = "base64_bytes")]
+    symkey: Vec<u8>,
+
+    /// Digest of the unencrypted layer. It may be empty.
+    #[serde(default)]
+    digest: String,
+
+    #[serde(default, deserialize_with = "base64_map")]
+    cipheroptions: HashMap<String, Vec<u8>>,
+}
+
+/// Return a reader to get the decrypted contents of `blob`.
+///
+/// The symmetric key is taken from the first JWE object that can be
+/// decrypted with any of the `keys`.
+///
+/// The HMAC of the data (and the digest of the decrypted layer, if it
+/// is present in the private options) is verified when `reader` is
+/// fully consumed.
+pub(crate) fn decrypt_layer<'r, R: Read + 'r>(
+    blob: &Blob,
+    keys: &[DecryptionKey],
+    reader: R,
+) -> Result<Box<dyn Read + 'r>, DecryptionError> {
+    let annotation = |name| {
+        blob.annotations
+            .get(name)
+            .ok_or(DecryptionError::MissingAnnotation(name))
+    };
+
+    let public: PublicOptions = BASE64
+        .decode(annotation(PUBOPTS_ANNOTATION)?)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(DecryptionError::InvalidAnnotation(PUBOPTS_ANNOTATION))?;
+
+    if public.cipher != CIPHER_AES_CTR {
+        return Err(DecryptionError::UnsupportedCipher(public.cipher));
+    }
+
+    let mut result = Err(DecryptionError::NoMatchingKey);
+    for item in annotation(KEYS_ANNOTATION)?.split(',') {
+        let Ok(object) = BASE64.decode(item) else {
+            return Err(DecryptionError::InvalidAnnotation(KEYS_ANNOTATION));
+        };
+
+        result = jwe::decrypt(&object, keys);
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    let private: PrivateOptions = serde_json::from_slice(&result?)
+        .map_err(|_| DecryptionError::InvalidJwe("invalid payload"))?;
+
+    let nonce = private
+        .cipheroptions
+        .get("nonce")
+        .ok_or(DecryptionError::InvalidJwe("missing nonce"))?;
+
+    let cipher = Aes256Ctr::new_from_slices(&private.symkey, nonce)
+        .map_err(|_| DecryptionError::InvalidJwe("invalid key or nonce"))?;
+
+    let hmac = Hmac::<Sha256>::new_from_slice(&private.symkey)
+        .map_err(|_| DecryptionError::InvalidJwe("invalid key"))?;
+
+    let decryptor = LayerDecryptor {
+        reader,
+        cipher,
+        hmac,
+        expected_hmac: public.hmac,
+        verified: false,
+    };
+
+    if private.digest.is_empty() {
+        return Ok(Box::new(decryptor));
+    }
+
+    let digest = Digest::try_from(private.digest)
+        .map_err(|_| DecryptionError::InvalidJwe("invalid digest"))?;
+
+    Ok(Box::new(digest.wrap_reader(decryptor)))
+}
+
+/// Decrypt data with AES-256-CTR, and verify its HMAC when the
+/// inner reader is fully consumed.
+struct LayerDecryptor<R> {
+    reader: R,
+    cipher: Aes256Ctr,
+    hmac: Hmac<Sha256>,
+    expected_hmac: Vec<u8>,
+    verified: bool,
+}
+
+impl<R: Read> Read for LayerDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+
+        if n == 0 {
+            if !self.verified {
+                self.hmac
+                    .clone()
+                    .verify_slice(&self.expected_hmac)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid HMAC."))?;
+
+                self.verified = true;
+            }
+
+            return Ok(0);
+        }
+
+        // The HMAC is computed from the encrypted data.
+        self.hmac.update(&buf[..n]);
+        self.cipher.apply_keystream(&mut buf[..n]);
+
+        Ok(n)
+    }
+}
+
+/// Deserialize a base64 string, like the `[]byte` fields in Go.
+fn base64_bytes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    BASE64.decode(value).map_err(serde::de::Error::custom)
+}
+
+/// Deserialize a map of base64 strings, like `map[string][]byte` in Go.
+fn base64_map<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let map = Option::<HashMap<String, String>>::deserialize(deserializer)?.unwrap_or_default();
+
+    map.into_iter()
+        .map(|(k, v)| Ok((k, BASE64.decode(v).map_err(serde::de::Error::custom)?)))
+        .collect()
+}
@@ -7,10 +7,10 @@ use std::{
 };
 
 use rustix::{
-    fd::{AsFd, BorrowedFd, OwnedFd},
+    fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
     fs::{
-        chmodat, chownat, mkdirat, openat, openat2, unlinkat, AtFlags, Gid, Mode, OFlags,
-        ResolveFlags, Uid,
+        chmodat, chownat, lremovexattr, lsetxattr, mkdirat, openat, openat2, unlinkat, AtFlags,
+        Gid, Mode, OFlags, ResolveFlags, Uid, XattrFlags,
     },
     io::Errno,
     path::Arg,
@@ -69,6 +69,21 @@ impl Directory {
         )
     }
 
+    /// Open a file to read it.
+    ///
+    /// Symbolic links are resolved as if this directory were the root.
+    /// The file is opened with `O_NONBLOCK`, so a FIFO does not block
+    /// the caller.
+    pub fn open_file<P: Arg>(&self, path: P) -> Result<OwnedFd, Errno> {
+        openat2(
+            self,
+            path,
+            OFlags::RDONLY | OFlags::NONBLOCK | OFlags::CLOEXEC,
+            Mode::empty(),
+            ResolveFlags::IN_ROOT | ResolveFlags::NO_MAGICLINKS,
+        )
+    }
+
     /// Return a file descriptor for a directory.
     ///
     /// If `create` is `true`, the directory is created if it does not exist.
@@ -136,7 +151,7 @@ impl<'a> DirFdCache<'a> {
     }
 
     /// Get a file descriptor for a directory.
-    pub fn get<P>(&mut self, path: P, create: bool) -> Result<BorrowedFd, Errno>
+    pub fn get<P>(&mut self, path: P, create: bool) -> Result<BorrowedFd<'_>, Errno>
     where
         P: AsRef<Path>,
     {
@@ -199,13 +214,14 @@ pub fn normalize_path<T: AsRef<Path>>(path: T) -> io::Result<(PathBuf, PathBuf)>
 /// If `preserve_mode` is `true`, the mode will be restored if it
 /// contains any SUID flag.
 ///
-/// Errors from `fchownat` are ignored.
+/// Errors from `fchownat` are ignored, unless `require` is `true`.
 pub fn change_owner(
     parent_fd: BorrowedFd,
     file_name: &Path,
     uid: Option<u32>,
     gid: Option<u32>,
     preserve_mode: bool,
+    require: bool,
 ) -> io::Result<()> {
     if uid.is_none() && gid.is_none() {
         return Ok(());
@@ -225,6 +241,10 @@ pub fn change_owner(
         AtFlags::SYMLINK_NOFOLLOW,
     );
 
+    if let (Err(e), true) = (result, require) {
+        return Err(e.into());
+    }
+
     if let (Ok(_), Some(mode)) = (result, orig_mode) {
         // Restore SUID bits, if any.
         if mode & 0o7000 != 0 {
@@ -240,6 +260,93 @@ pub fn change_owner(
     Ok(())
 }
 
+/// Name of the xattr to store the owner of a file in rootless containers.
+const ROOTLESS_XATTR: &str = "user.rootlesscontainers";
+
+/// Store the owner of an entry in the `user.rootlesscontainers` xattr,
+/// as described in the [rootless containers][proto] specification.
+///
+/// The xattr is removed if the owner is `root`, since it is the default
+/// value for files without it.
+///
+/// [proto]: https://github.com/rootless-containers/proto
+pub fn set_rootless_owner(
+    parent_fd: BorrowedFd,
+    file_name: &Path,
+    uid: u32,
+    gid: u32,
+) -> io::Result<()> {
+    let path = xattr_path(parent_fd, file_name);
+
+    if uid == 0 && gid == 0 {
+        return match lremovexattr(&path, ROOTLESS_XATTR) {
+            Ok(_) | Err(Errno::NODATA) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    lsetxattr(
+        &path,
+        ROOTLESS_XATTR,
+        &encode_rootless_resource(uid, gid),
+        XattrFlags::empty(),
+    )?;
+
+    Ok(())
+}
+
+/// Set an extended attribute of an entry. If the entry is a symbolic
+/// link, the attribute is set on the link itself.
+pub fn set_xattr(
+    parent_fd: BorrowedFd,
+    file_name: &Path,
+    name: &str,
+    value: &[u8],
+) -> io::Result<()> {
+    lsetxattr(
+        xattr_path(parent_fd, file_name),
+        name,
+        value,
+        XattrFlags::empty(),
+    )?;
+    Ok(())
+}
+
+/// Return a path to access an entry with the xattr syscalls.
+///
+/// There are no *at variants for those syscalls, so the entry is
+/// accessed through the descriptor of its parent.
+fn xattr_path(parent_fd: BorrowedFd, file_name: &Path) -> PathBuf {
+    let mut path = PathBuf::from(format!("/proc/self/fd/{}", parent_fd.as_raw_fd()));
+    path.push(file_name);
+    path
+}
+
+/// Encode a `Resource` message of the rootless containers specification.
+///
+/// Both fields (`uid = 1` and `gid = 2`) are `uint32`, so they are
+/// encoded as varints, and omitted if they are `0`.
+fn encode_rootless_resource(uid: u32, gid: u32) -> Vec<u8> {
+    let mut message = Vec::new();
+
+    for (tag, mut value) in [(0x08, uid), (0x10, gid)] {
+        if value == 0 {
+            continue;
+        }
+
+        message.push(tag);
+
+        while value >= 0x80 {
+            message.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+
+        message.push(value as u8);
+    }
+
+    message
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum RemovedEntry {
     Directory,
@@ -294,3 +401,14 @@ pub fn remove_subtree(parent_fd: BorrowedFd, path: &Path) -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn encode_rootless_resources() {
+    assert_eq!(encode_rootless_resource(0, 0), b"");
+    assert_eq!(encode_rootless_resource(1000, 0), b"\x08\xe8\x07");
+    assert_eq!(encode_rootless_resource(0, 100), b"\x10\x64");
+    assert_eq!(
+        encode_rootless_resource(u32::MAX, 1),
+        b"\x08\xff\xff\xff\xff\x0f\x10\x01"
+    );
+}
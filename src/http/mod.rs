@@ -17,6 +17,9 @@ pub enum HttpError {
 
     #[error("Invalid JSON: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("URL not allowed: {0}")]
+    ForbiddenUrl(String),
 }
 
 impl From<ureq::Error> for HttpError {
@@ -29,6 +32,8 @@ pub(super) struct Client<'a, E> {
     event_handler: &'a E,
     auth_token: RwLock<Option<String>>,
     host: String,
+    max_document_size: usize,
+    external_hosts: &'a [String],
 }
 
 impl<'a, E> Client<'a, E>
@@ -42,7 +47,10 @@ where
     /// * If it is a loopback IP (like `127.0.0.1`), or if the port
     ///   is `:80`, it uses `http://`.
     /// * In any other case, it uses `https://`.
-    pub fn new(reference: &Reference, event_handler: &'a E) -> Self {
+    ///
+    /// `max_document_size` is the maximum size, in bytes, of the
+    /// manifests and the image configurations.
+    pub fn new(reference: &Reference, event_handler: &'a E, max_document_size: usize) -> Self {
         let host = format!(
             "{}{}/v2/{}",
             guess_scheme(reference.registry),
@@ -54,9 +62,24 @@ where
             event_handler,
             auth_token: Default::default(),
             host,
+            max_document_size,
+            external_hosts: &[],
         }
     }
 
+    /// Set the hosts allowed in [`download_url`](Self::download_url).
+    /// If it is empty, any host is allowed.
+    pub fn external_hosts(mut self, hosts: &'a [String]) -> Self {
+        self.external_hosts = hosts;
+        self
+    }
+
+    /// Maximum size, in bytes, of the manifests and the image
+    /// configurations.
+    pub fn max_document_size(&self) -> usize {
+        self.max_document_size
+    }
+
     /// Send a `GET` request to the registry.
     ///
     /// The path must not include the `v2/$image` prefix.
@@ -76,6 +99,45 @@ where
         Ok(blob.wrap_reader(response.into_reader()))
     }
 
+    /// Send a `GET` request to download a blob from an external URL,
+    /// like the ones in the descriptors of foreign layers.
+    ///
+    /// The authentication token for the registry is not sent. Only
+    /// `http` and `https` URLs, for the hosts allowed in the client,
+    /// can be used.
+    pub fn download_url(&self, url: &str, blob: &Digest) -> Result<impl Read, HttpError> {
+        if !self.is_external_url_allowed(url) {
+            return Err(HttpError::ForbiddenUrl(url.to_owned()));
+        }
+
+        self.event_handler.external_request(url);
+
+        let response = ureq::get(url).set("User-Agent", USER_AGENT).call()?;
+        Ok(blob.wrap_reader(response.into_reader()))
+    }
+
+    /// Return `true` if `url` can be used in [`download_url`](Self::download_url).
+    fn is_external_url_allowed(&self, url: &str) -> bool {
+        let Ok(url) = ureq::get(url).request_url() else {
+            return false;
+        };
+
+        if !matches!(url.scheme(), "http" | "https") {
+            return false;
+        }
+
+        let host = url.host();
+        let host_port = url.port().map(|port| format!("{host}:{port}"));
+
+        self.external_hosts.is_empty()
+            || self.external_hosts.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(host)
+                    || host_port
+                        .as_deref()
+                        .is_some_and(|hp| allowed.eq_ignore_ascii_case(hp))
+            })
+    }
+
     /// Send a request to the registry.
     ///
     /// If it responds with a `401` error, get the token from the
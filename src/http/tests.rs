@@ -94,7 +94,7 @@ fn request_token_after_unauthorized() {
 
     let reference = format!("127.0.0.1:{server_port}/abc/def");
     let reference = Reference::try_from(reference.as_str()).unwrap();
-    let client = crate::http::Client::new(&reference, &VoidHandler);
+    let client = crate::http::Client::new(&reference, &VoidHandler, usize::MAX);
 
     // Send a regular request.
     //
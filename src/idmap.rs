@@ -0,0 +1,138 @@
+//! Map the owners of the files in the image to IDs in the host.
+
+use std::{fs, io, path::Path};
+
+/// Range of IDs mapped from the image to the host.
+///
+/// It has the same meaning as a line in `/proc/<pid>/uid_map`: the IDs from
+/// `container_id` to `container_id + size - 1` in the image are mapped to
+/// the IDs starting at `host_id` in the host.
+///
+/// See [`Unpacker::uid_map`](crate::Unpacker::uid_map) for an example.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+    /// First ID of the range in the image.
+    pub container_id: u32,
+
+    /// First ID of the range in the host.
+    pub host_id: u32,
+
+    /// Number of IDs in the range.
+    pub size: u32,
+}
+
+impl IdMapping {
+    /// Map `size` IDs starting at `container_id` to the IDs starting at `host_id`.
+    pub fn new(container_id: u32, host_id: u32, size: u32) -> Self {
+        IdMapping {
+            container_id,
+            host_id,
+            size,
+        }
+    }
+
+    /// Build the mappings for a rootless container from a file like
+    /// `/etc/subuid` or `/etc/subgid`.
+    ///
+    /// The ID `0` in the image is mapped to `id` (usually, the ID of the
+    /// current user), and the next IDs are mapped to the ranges assigned
+    /// to `user` in the file, like in `podman` or `newuidmap`. Entries in
+    /// the file can contain either the name of the user or `id`.
+    pub fn from_subid_file(
+        path: impl AsRef<Path>,
+        user: &str,
+        id: u32,
+    ) -> io::Result<Vec<IdMapping>> {
+        Ok(Self::from_subid(&fs::read_to_string(path)?, user, id))
+    }
+
+    fn from_subid(data: &str, user: &str, id: u32) -> Vec<IdMapping> {
+        let id_str = id.to_string();
+
+        let mut mappings = vec![IdMapping::new(0, id, 1)];
+        let mut next_id = 1_u32;
+
+        for line in data.lines() {
+            let mut fields = line.trim().split(':');
+
+            let (Some(name), Some(start), Some(size)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+
+            if name != user && name != id_str {
+                continue;
+            }
+
+            if let (Ok(start), Ok(size)) = (start.parse(), size.parse()) {
+                mappings.push(IdMapping::new(next_id, start, size));
+                next_id = next_id.saturating_add(size);
+            }
+        }
+
+        mappings
+    }
+}
+
+/// Map an ID from the image to the host.
+///
+/// If there are no mappings, the ID is not changed.
+pub(crate) fn map_id(mappings: &[IdMapping], id: u64) -> Option<u32> {
+    if mappings.is_empty() {
+        return id.try_into().ok();
+    }
+
+    mappings.iter().find_map(|m| {
+        let offset = id.checked_sub(m.container_id.into())?;
+        if offset < u64::from(m.size) {
+            u32::try_from(u64::from(m.host_id) + offset).ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Map an ID from the host to the image. This is the inverse of [`map_id`].
+pub(crate) fn unmap_id(mappings: &[IdMapping], id: u32) -> Option<u64> {
+    if mappings.is_empty() {
+        return Some(id.into());
+    }
+
+    mappings.iter().find_map(|m| {
+        let offset = id.checked_sub(m.host_id)?;
+        (offset < m.size).then(|| u64::from(m.container_id) + u64::from(offset))
+    })
+}
+
+#[test]
+fn map_ids() {
+    let mappings = [IdMapping::new(0, 1000, 1), IdMapping::new(1, 100000, 65536)];
+
+    assert_eq!(map_id(&mappings, 0), Some(1000));
+    assert_eq!(map_id(&mappings, 1), Some(100000));
+    assert_eq!(map_id(&mappings, 65536), Some(165535));
+    assert_eq!(map_id(&mappings, 65537), None);
+    assert_eq!(map_id(&[], 65537), Some(65537));
+    assert_eq!(map_id(&[], 1 << 40), None);
+
+    assert_eq!(unmap_id(&mappings, 1000), Some(0));
+    assert_eq!(unmap_id(&mappings, 100010), Some(11));
+    assert_eq!(unmap_id(&mappings, 999), None);
+    assert_eq!(unmap_id(&[], 999), Some(999));
+
+    const SUBUID: &str = "\
+        other:200000:65536\n\
+        app:100000:65536\n\
+        1000:300000:10\n\
+        invalid\n";
+
+    assert_eq!(
+        IdMapping::from_subid(SUBUID, "app", 1000),
+        [
+            IdMapping::new(0, 1000, 1),
+            IdMapping::new(1, 100000, 65536),
+            IdMapping::new(65537, 300000, 10),
+        ]
+    );
+}
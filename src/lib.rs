@@ -28,10 +28,26 @@
 //! # }
 //! ```
 //!
+//! The manifests and the configuration of an image can be downloaded, without
+//! its layers, with [`Unpacker::inspect`].
+//!
 //! An instance of [`EventHandler`] can be used to receive notifications during
 //! the download/unpack process. The file `examples/unpack.rs` in the repository
 //! has a full implementation of a handler.
 //!
+//! The image is unpacked in the `rootfs` subdirectory of the target, and its
+//! configuration is written to `config.json`. With [`Unpacker::runtime_bundle`],
+//! the target is a [runtime bundle][bundle], and `config.json` is a runtime
+//! configuration generated from the image configuration.
+//!
+//! [bundle]: https://github.com/opencontainers/runtime-spec/blob/main/bundle.md
+//!
+//! # Repacking
+//!
+//! If the image is unpacked with [`Unpacker::mtree`], the changes made in `rootfs`
+//! can be converted to a new layer with [`Repacker`]. The new image is written to
+//! an [OCI layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md).
+//!
 //! # Sandbox
 //!
 //! Before creating any file in the target directory, [`Unpacker::unpack`] tries
@@ -47,21 +63,73 @@
 //! # Zstd Compression
 //!
 //! The `zstd` feature (enabled by default) is required to support images compressed with zstd.
+//!
+//! # Layer Compression
+//!
+//! The compression of each layer is detected from its first bytes, since some
+//! tools use the wrong media type for it. See [`Unpacker::strict_compression`].
+//!
+//! Layers compressed with xz or bzip2 require the `xz` or `bzip2` features.
+//!
+//! # Schema 1 Manifests
+//!
+//! The `schema1` feature adds support for the deprecated [Docker schema 1][schema1]
+//! manifests, which are still used by some old images. The image configuration is
+//! built from the `v1Compatibility` fields in the manifest.
+//!
+//! [schema1]: https://distribution.github.io/distribution/spec/deprecated-schema-v1/
+//!
+//! # Encrypted Layers
+//!
+//! The `encryption` feature adds support for layers encrypted with
+//! [ocicrypt](https://github.com/containers/ocicrypt). The private keys to decrypt
+//! them are added with [`Unpacker::decryption_key`][decryption_key].
+#![cfg_attr(
+    feature = "encryption",
+    doc = "",
+    doc = "[decryption_key]: Unpacker::decryption_key"
+)]
+#![cfg_attr(
+    not(feature = "encryption"),
+    doc = "",
+    doc = "[decryption_key]: Unpacker"
+)]
 
+pub mod config;
 mod digest;
+#[cfg(feature = "encryption")]
+mod encryption;
 mod fs;
 mod http;
+mod idmap;
 mod manifests;
+mod mtree;
+mod platform;
 mod reference;
+mod repack;
 mod unpacker;
 
+pub use config::ImageConfig;
 pub use digest::{Digest, DigestAlgorithm};
+#[cfg(feature = "encryption")]
+pub use encryption::DecryptionKey;
+pub use idmap::IdMapping;
+pub use manifests::{Annotations, Blob, Index, IndexEntry, Manifest};
+pub use platform::Platform;
 pub use reference::{MediaType, Reference, Repository};
-pub use unpacker::{EventHandler, NoEventHandler, Unpacker};
+pub use repack::{RepackedImage, Repacker};
+pub use unpacker::{
+    Compression, DeviceNodePolicy, EventHandler, ForeignLayerPolicy, ImageInfo, NoEventHandler,
+    UnpackedImage, Unpacker,
+};
 
 /// Errors from the functions in the public API.
 pub mod errors {
     pub use super::digest::DigestError;
+    #[cfg(feature = "encryption")]
+    pub use super::encryption::{DecryptionError, KeyError};
+    pub use super::platform::PlatformError;
     pub use super::reference::ParseError;
+    pub use super::repack::RepackError;
     pub use super::unpacker::UnpackError;
 }
@@ -0,0 +1,680 @@
+#[cfg(feature = "schema1")]
+mod schema1;
+
+use std::{collections::BTreeMap, io::Read, str::FromStr};
+
+use crate::{
+    config::ImageConfig,
+    digest::Digest,
+    unpacker::{try_io, UnpackError},
+    EventHandler, MediaType, Platform, Reference,
+};
+
+/// Annotations in a manifest, an index, or a descriptor.
+pub type Annotations = BTreeMap<String, String>;
+
+/// Descriptor of a blob (the image configuration or a layer)
+/// in a manifest.
+///
+/// See the [OCI specification][spec] for more details.
+///
+/// [spec]: https://github.com/opencontainers/image-spec/blob/main/descriptor.md
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct Blob {
+    /// Media type of the blob.
+    pub media_type: MediaType,
+
+    /// Digest of the blob contents.
+    pub digest: Digest,
+
+    /// Size, in bytes, of the blob.
+    pub size: usize,
+
+    /// Schema 1 manifests don't include the size of the layers.
+    #[cfg(feature = "schema1")]
+    #[serde(skip)]
+    pub(crate) unknown_size: bool,
+
+    /// URLs to download the blob from, in addition to the registry.
+    ///
+    /// Usually present only in [foreign layers](MediaType::is_foreign_layer).
+    #[serde(default)]
+    pub urls: Vec<String>,
+
+    /// Annotations of the blob.
+    #[serde(default)]
+    pub annotations: Annotations,
+}
+
+impl Blob {
+    /// Return the size of the blob, if it is known.
+    pub(crate) fn expected_size(&self) -> Option<usize> {
+        #[cfg(feature = "schema1")]
+        if self.unknown_size {
+            return None;
+        }
+
+        Some(self.size)
+    }
+}
+
+/// Manifest of an image.
+///
+/// See the [OCI specification][spec] for more details.
+///
+/// [spec]: https://github.com/opencontainers/image-spec/blob/main/manifest.md
+#[derive(serde::Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct Manifest {
+    /// Descriptor of the image configuration.
+    pub config: Blob,
+
+    /// Descriptors of the layers, from the lowest to the uppermost.
+    pub layers: Vec<Blob>,
+
+    /// Annotations of the manifest.
+    #[serde(default)]
+    pub annotations: Annotations,
+
+    /// Manifest, as it was received from the registry. It is empty
+    /// for schema 1 manifests.
+    #[serde(skip)]
+    pub(crate) raw: Vec<u8>,
+
+    /// Image configuration built from a schema 1 manifest. Those
+    /// manifests don't have a configuration blob in the registry.
+    #[cfg(feature = "schema1")]
+    #[serde(skip)]
+    pub(crate) inline_config: Option<Vec<u8>>,
+}
+
+/// Entry in a manifest index.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(from = "RawIndexEntry")]
+#[non_exhaustive]
+pub struct IndexEntry {
+    /// Media type of the referenced object.
+    ///
+    /// It is `None` if the field is missing, or if the media type is
+    /// unknown.
+    pub media_type: Option<MediaType>,
+
+    /// Digest of the manifest.
+    pub digest: Digest,
+
+    /// Size, in bytes, of the manifest.
+    pub size: usize,
+
+    /// Platform of the image.
+    ///
+    /// Entries that are not images, like attestations, may not
+    /// have a platform.
+    pub platform: Option<Platform>,
+
+    /// Annotations of the entry.
+    pub annotations: Annotations,
+
+    /// `true` if the entry has a media type, but it is unknown.
+    unknown_media_type: bool,
+}
+
+/// Entry in a manifest index, as it is found in the JSON document.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawIndexEntry {
+    #[serde(default)]
+    media_type: Option<String>,
+
+    digest: Digest,
+
+    size: usize,
+
+    #[serde(default)]
+    platform: Option<Platform>,
+
+    #[serde(default)]
+    annotations: Annotations,
+}
+
+impl From<RawIndexEntry> for IndexEntry {
+    fn from(raw: RawIndexEntry) -> Self {
+        let media_type = raw
+            .media_type
+            .as_deref()
+            .and_then(|m| MediaType::from_str(m).ok());
+
+        IndexEntry {
+            unknown_media_type: media_type.is_none() && raw.media_type.is_some(),
+            media_type,
+            digest: raw.digest,
+            size: raw.size,
+            platform: raw.platform,
+            annotations: raw.annotations,
+        }
+    }
+}
+
+/// Manifest index, to reference images for multiple platforms.
+///
+/// See the [OCI specification][spec] for more details.
+///
+/// [spec]: https://github.com/opencontainers/image-spec/blob/main/image-index.md
+#[derive(serde::Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct Index {
+    /// Entries in the index.
+    pub manifests: Vec<IndexEntry>,
+
+    /// Annotations of the index.
+    #[serde(default)]
+    pub annotations: Annotations,
+}
+
+/// Manifest found by [`get`].
+pub(super) struct Resolved {
+    /// Index used to find the manifest, if any.
+    pub index: Option<(Index, Digest)>,
+
+    /// Platform of the selected entry in the index.
+    pub platform: Option<Platform>,
+
+    pub manifest: Manifest,
+
+    /// Digest of the manifest.
+    pub digest: Digest,
+}
+
+/// Image found by [`get_all`].
+pub(super) struct Image {
+    pub platform: Platform,
+    pub manifest: Manifest,
+    pub digest: Digest,
+}
+
+/// Object returned by the registry for a manifest request.
+enum Response {
+    Index(Index),
+    Manifest(Manifest),
+}
+
+/// Maximum nesting level of manifest indexes.
+const MAX_INDEX_DEPTH: usize = 8;
+
+/// Image found in a manifest index.
+struct Candidate {
+    platform: Platform,
+    digest: Digest,
+    size: usize,
+
+    /// Manifest, if it was already downloaded.
+    manifest: Option<Manifest>,
+}
+
+/// Download the manifest for the `reference`.
+///
+/// If the registry returns a manifest index, it selects the image for
+/// the first item in `platforms` that is found in the index. If
+/// `platforms` is empty, it uses the platform of the current process.
+///
+/// If `reference` contains a digest (like `@sha256:...`), the
+/// manifest index is skipped.
+pub(super) fn get<E: EventHandler>(
+    reference: &Reference,
+    platforms: &[Platform],
+    http_client: &mut crate::http::Client<E>,
+) -> Result<Resolved, UnpackError> {
+    let current_platform;
+    let platforms = match platforms {
+        [] => {
+            current_platform = [Platform::current()];
+            &current_platform[..]
+        }
+
+        p => p,
+    };
+
+    let (index, index_digest) = match fetch_reference(reference, http_client)? {
+        (Response::Index(index), digest) => (index, digest),
+        (Response::Manifest(manifest), digest) => {
+            return Ok(Resolved {
+                index: None,
+                platform: None,
+                manifest,
+                digest,
+            })
+        }
+    };
+
+    let mut candidates = Vec::new();
+    find_images(
+        &index,
+        platforms,
+        None,
+        1,
+        &mut Vec::new(),
+        &mut candidates,
+        http_client,
+    )?;
+
+    let position = select_platform(platforms, &candidates)?;
+    let image = candidates.swap_remove(position).fetch(http_client)?;
+
+    Ok(Resolved {
+        index: Some((index, index_digest)),
+        platform: Some(image.platform),
+        manifest: image.manifest,
+        digest: image.digest,
+    })
+}
+
+/// Download the configuration of the image described by `manifest`.
+///
+/// Returns both the parsed configuration and its original contents.
+pub(super) fn get_config<E: EventHandler>(
+    manifest: &Manifest,
+    http_client: &crate::http::Client<E>,
+) -> Result<(ImageConfig, Vec<u8>), UnpackError> {
+    #[cfg(feature = "schema1")]
+    if let Some(data) = &manifest.inline_config {
+        return Ok((serde_json::from_slice(data)?, data.clone()));
+    }
+
+    let blob = &manifest.config;
+
+    let data = read_document(
+        http_client.download_blob(&blob.digest)?,
+        blob.digest.source(),
+        blob.expected_size().map(|s| (&blob.digest, s)),
+        http_client.max_document_size(),
+    )?;
+
+    let config = serde_json::from_slice(&data)?;
+    Ok((config, data))
+}
+
+/// Download the manifests for every image in the index of `reference`.
+///
+/// If `platforms` is not empty, only the images for those platforms
+/// are returned. Entries for unknown platforms (like attestations)
+/// are ignored. If multiple entries have the same platform, only the
+/// first one is returned.
+///
+/// Returns the digest of the index, and the images found in it.
+pub(super) fn get_all<E: EventHandler>(
+    reference: &Reference,
+    platforms: &[Platform],
+    http_client: &mut crate::http::Client<E>,
+) -> Result<(Digest, Vec<Image>), UnpackError> {
+    let (index, index_digest) = match fetch_reference(reference, http_client)? {
+        (Response::Index(index), digest) => (index, digest),
+        (Response::Manifest(_), _) => return Err(UnpackError::MissingIndex),
+    };
+
+    let mut candidates = Vec::new();
+    find_images(
+        &index,
+        platforms,
+        None,
+        1,
+        &mut Vec::new(),
+        &mut candidates,
+        http_client,
+    )?;
+
+    let mut manifests = Vec::new();
+    let mut found_platforms = Vec::new();
+
+    for candidate in candidates {
+        if !platforms.is_empty() && !platforms.iter().any(|p| p.matches(&candidate.platform)) {
+            continue;
+        }
+
+        // The features are not used to name the target of the image,
+        // so they are ignored when looking for duplicates.
+        let platform = Platform {
+            os_features: Vec::new(),
+            ..candidate.platform.clone().normalize()
+        };
+
+        if found_platforms.contains(&platform) {
+            continue;
+        }
+
+        found_platforms.push(platform);
+        manifests.push(candidate.fetch(http_client)?);
+    }
+
+    Ok((index_digest, manifests))
+}
+
+/// Collect the images referenced by `index`, including the ones in
+/// nested indexes.
+///
+/// Nested indexes are downloaded only if their platform is unknown, or
+/// if it matches any of the items in `platforms`. If an entry in a nested
+/// index has no platform, it inherits the one from its parent.
+///
+/// `visited` contains the digests of the indexes that are being
+/// processed, to detect cycles.
+fn find_images<E: EventHandler>(
+    index: &Index,
+    platforms: &[Platform],
+    parent_platform: Option<&Platform>,
+    depth: usize,
+    visited: &mut Vec<Digest>,
+    candidates: &mut Vec<Candidate>,
+    http_client: &mut crate::http::Client<E>,
+) -> Result<(), UnpackError> {
+    for entry in &index.manifests {
+        // Ignore artifacts and other objects with a media type that
+        // is not supported, even if they have a platform.
+        if entry.unknown_media_type {
+            continue;
+        }
+
+        // Entries without a media type are used only if they have their
+        // own platform.
+        let platform = match entry.media_type {
+            Some(_) => entry.platform.as_ref().or(parent_platform),
+            None => entry.platform.as_ref(),
+        };
+
+        // Ignore attestations and other objects that are not images.
+        if platform.is_some_and(|p| p.os == "unknown") {
+            continue;
+        }
+
+        let is_index = matches!(
+            entry.media_type,
+            Some(MediaType::DockerManifestList | MediaType::OciImageIndex)
+        );
+
+        if !is_index {
+            if let Some(platform) = platform {
+                candidates.push(Candidate {
+                    platform: platform.clone(),
+                    digest: entry.digest.clone(),
+                    size: entry.size,
+                    manifest: None,
+                });
+            }
+
+            continue;
+        }
+
+        // The platform of a nested index can be less specific than the
+        // platforms of its images (for example, `linux/arm` for all ARM
+        // variants), so only the OS and the architecture are compared.
+        if let Some(platform) = platform {
+            let platform = platform.clone().normalize();
+            if !platforms.is_empty()
+                && !platforms.iter().any(|p| {
+                    let p = p.clone().normalize();
+                    p.os == platform.os && p.architecture == platform.architecture
+                })
+            {
+                continue;
+            }
+        }
+
+        if depth >= MAX_INDEX_DEPTH {
+            return Err(UnpackError::IndexTooDeep);
+        }
+
+        if visited.contains(&entry.digest) {
+            return Err(UnpackError::IndexCycle(entry.digest.clone()));
+        }
+
+        let descriptor = Some((&entry.digest, Some(entry.size)));
+        match fetch(entry.digest.source(), descriptor, http_client)?.0 {
+            Response::Index(nested) => {
+                visited.push(entry.digest.clone());
+                find_images(
+                    &nested,
+                    platforms,
+                    platform,
+                    depth + 1,
+                    visited,
+                    candidates,
+                    http_client,
+                )?;
+                visited.pop();
+            }
+
+            Response::Manifest(manifest) => {
+                if let Some(platform) = platform {
+                    candidates.push(Candidate {
+                        platform: platform.clone(),
+                        digest: entry.digest.clone(),
+                        size: entry.size,
+                        manifest: Some(manifest),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Candidate {
+    /// Return the image for this candidate, downloading its manifest
+    /// if needed.
+    fn fetch<E: EventHandler>(
+        self,
+        http_client: &mut crate::http::Client<E>,
+    ) -> Result<Image, UnpackError> {
+        let manifest = match self.manifest {
+            Some(manifest) => manifest,
+            None => match fetch(
+                self.digest.source(),
+                Some((&self.digest, Some(self.size))),
+                http_client,
+            )?
+            .0
+            {
+                Response::Manifest(manifest) => manifest,
+                Response::Index(_) => {
+                    return Err(UnpackError::InvalidContentType(MediaType::OciImageIndex))
+                }
+            },
+        };
+
+        Ok(Image {
+            platform: self.platform,
+            manifest,
+            digest: self.digest,
+        })
+    }
+}
+
+/// Send a request to get the manifest for `reference`.
+fn fetch_reference<E: EventHandler>(
+    reference: &Reference,
+    http_client: &mut crate::http::Client<E>,
+) -> Result<(Response, Digest), UnpackError> {
+    match &reference.digest {
+        Some(digest) => fetch(digest.source(), Some((digest, None)), http_client),
+        None => fetch(reference.tag, None, http_client),
+    }
+}
+
+/// Send a request to get the manifest identified by `path`.
+///
+/// If we have a descriptor for the manifest, its digest is verified
+/// when the download is completed, and its size (if known) must match
+/// the size of the body. If the response contains the
+/// `Docker-Content-Digest` header, the body is verified against it too.
+///
+/// Returns the parsed object and its digest.
+fn fetch<E: EventHandler>(
+    path: &str,
+    descriptor: Option<(&Digest, Option<usize>)>,
+    http_client: &mut crate::http::Client<E>,
+) -> Result<(Response, Digest), UnpackError> {
+    let accept = MediaType::ALL.join(", ");
+
+    let response = http_client.get(&format!("manifests/{}", path), Some(&accept))?;
+
+    let content_type = response
+        .header("Content-Type")
+        .and_then(|h| MediaType::from_str(h).ok())
+        .ok_or(UnpackError::MissingContentType)?;
+
+    let content_digest = response
+        .header("Docker-Content-Digest")
+        .map(|h| Digest::try_from(h.to_owned()))
+        .transpose()?;
+
+    let body = read_document(
+        response.into_reader(),
+        path,
+        descriptor.and_then(|(d, s)| Some((d, s?))),
+        http_client.max_document_size(),
+    )?;
+
+    // The digest of a signed manifest is computed without its signatures.
+    #[cfg(feature = "schema1")]
+    let body = match content_type {
+        MediaType::DockerManifestV1Signed => schema1::strip_signatures(&body)?,
+        _ => body,
+    };
+
+    for digest in descriptor.map(|d| d.0).into_iter().chain(&content_digest) {
+        try_io!(path, digest.verify(&body));
+    }
+
+    let digest = match (descriptor, content_digest) {
+        (Some((digest, _)), _) => digest.clone(),
+        (None, Some(digest)) => digest,
+        (None, None) => Digest::sha256(&body),
+    };
+
+    let response = match content_type {
+        MediaType::DockerManifestList | MediaType::OciImageIndex => {
+            // https://distribution.github.io/distribution/spec/manifest-v2-2/#manifest-list
+            // https://github.com/opencontainers/image-spec/blob/main/image-index.md
+            Ok(Response::Index(serde_json::from_slice(&body)?))
+        }
+
+        MediaType::DockerManifestV2 | MediaType::OciManifestV1 => {
+            // https://distribution.github.io/distribution/spec/manifest-v2-2/
+            let mut manifest: Manifest = serde_json::from_slice(&body)?;
+            manifest.raw = body;
+            Ok(Response::Manifest(manifest))
+        }
+
+        #[cfg(feature = "schema1")]
+        MediaType::DockerManifestV1 | MediaType::DockerManifestV1Signed => {
+            Ok(Response::Manifest(schema1::parse(&body)?))
+        }
+
+        unknown => Err(UnpackError::InvalidContentType(unknown)),
+    };
+
+    Ok((response?, digest))
+}
+
+/// Read a document (a manifest, an index, or an image configuration)
+/// from `reader`.
+///
+/// The document can't be larger than `limit`. If `expected` contains
+/// the digest and the size from its descriptor, the document must
+/// have that size.
+fn read_document(
+    reader: impl Read,
+    path: &str,
+    expected: Option<(&Digest, usize)>,
+    limit: usize,
+) -> Result<Vec<u8>, UnpackError> {
+    let max_len = match expected {
+        Some((_, size)) if size > limit => {
+            return Err(UnpackError::DocumentTooLarge(path.to_owned()))
+        }
+
+        Some((_, size)) => size,
+        None => limit,
+    };
+
+    let mut data = Vec::new();
+    try_io!(path, reader.take(max_len as u64 + 1).read_to_end(&mut data));
+
+    match expected {
+        Some((digest, size)) if data.len() != size => {
+            Err(UnpackError::InvalidBlobSize(digest.clone()))
+        }
+
+        None if data.len() > limit => Err(UnpackError::DocumentTooLarge(path.to_owned())),
+
+        _ => Ok(data),
+    }
+}
+
+/// Get the position of the first candidate that matches the first
+/// platform in `platforms`.
+fn select_platform(platforms: &[Platform], candidates: &[Candidate]) -> Result<usize, UnpackError> {
+    platforms
+        .iter()
+        .find_map(|p| candidates.iter().position(|c| p.matches(&c.platform)))
+        .ok_or(UnpackError::MissingArchitecture)
+}
+
+#[test]
+fn select_platform_from_index() {
+    const INDEX: &str = r#"{
+        "manifests": [
+            {
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000001",
+                "size": 100,
+                "platform": { "architecture": "arm", "os": "linux", "variant": "v6" }
+            },
+            {
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000002",
+                "size": 100,
+                "platform": { "architecture": "arm", "os": "linux", "variant": "v7" }
+            },
+            {
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000003",
+                "size": 100,
+                "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" }
+            },
+            {
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000004",
+                "size": 100,
+                "platform": { "architecture": "386", "os": "linux" }
+            }
+        ]
+    }"#;
+
+    let select = |platforms: &[&str]| {
+        let platforms: Vec<Platform> = platforms.iter().map(|p| p.parse().unwrap()).collect();
+        let index: Index = serde_json::from_str(INDEX).unwrap();
+
+        let candidates: Vec<_> = index
+            .manifests
+            .into_iter()
+            .map(|e| Candidate {
+                platform: e.platform.unwrap(),
+                digest: e.digest,
+                size: e.size,
+                manifest: None,
+            })
+            .collect();
+
+        select_platform(&platforms, &candidates).map(|i| candidates[i].digest.source().to_owned())
+    };
+
+    assert!(select(&["linux/arm/v7"]).unwrap().ends_with("2"));
+    assert!(select(&["linux/arm/v6"]).unwrap().ends_with("1"));
+    assert!(select(&["linux/aarch64"]).unwrap().ends_with("3"));
+    assert!(select(&["linux/amd64", "linux/386"])
+        .unwrap()
+        .ends_with("4"));
+
+    assert!(matches!(
+        select(&["linux/amd64"]),
+        Err(UnpackError::MissingArchitecture)
+    ));
+}
@@ -0,0 +1,287 @@
+//! Support for the deprecated Docker schema 1 manifests.
+//!
+//! Refs:
+//!
+//! * https://distribution.github.io/distribution/spec/deprecated-schema-v1/
+//! * https://github.com/containerd/containerd/blob/main/core/remotes/docker/schema1/converter.go
+
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use serde_json::Value;
+
+use crate::{digest::Digest, unpacker::UnpackError, MediaType};
+
+use super::{Blob, Manifest};
+
+/// Keys from the `v1Compatibility` object that are not copied to the
+/// image configuration.
+const IGNORED_KEYS: &[&str] = &["id", "parent", "Size", "parent_id", "layer_id", "throwaway"];
+
+/// Decoder for the base64url strings in the JWS signatures.
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Remove the JWS signatures from a signed manifest, so its digest
+/// can be verified.
+///
+/// The payload is built with the `formatLength` and `formatTail`
+/// fields from the protected header of the first signature.
+pub(super) fn strip_signatures(body: &[u8]) -> Result<Vec<u8>, UnpackError> {
+    #[derive(serde::Deserialize)]
+    struct Signed {
+        signatures: Vec<Signature>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Signature {
+        protected: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Protected {
+        format_length: usize,
+        format_tail: String,
+    }
+
+    let Signed { signatures } = serde_json::from_slice(body)?;
+
+    let signature = signatures
+        .first()
+        .ok_or(UnpackError::InvalidSchema1("missing signatures"))?;
+
+    let protected: Protected = BASE64_URL
+        .decode(&signature.protected)
+        .map_err(|_| UnpackError::InvalidSchema1("invalid protected header"))
+        .and_then(|header| Ok(serde_json::from_slice(&header)?))?;
+
+    let tail = BASE64_URL
+        .decode(&protected.format_tail)
+        .map_err(|_| UnpackError::InvalidSchema1("invalid format tail"))?;
+
+    let mut payload = body
+        .get(..protected.format_length)
+        .ok_or(UnpackError::InvalidSchema1("invalid format length"))?
+        .to_vec();
+
+    payload.extend_from_slice(&tail);
+    Ok(payload)
+}
+
+/// Convert a schema 1 manifest to the internal representation.
+///
+/// Layers are sorted from the lowest to the uppermost, and layers marked
+/// as `throwaway` are discarded.
+///
+/// The image configuration is built from the `v1Compatibility` object
+/// of the uppermost layer. Since the uncompressed digests of the layers
+/// are not available, it does not contain the `rootfs` field.
+pub(super) fn parse(payload: &[u8]) -> Result<Manifest, UnpackError> {
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ManifestV1 {
+        architecture: Option<String>,
+        fs_layers: Vec<FsLayer>,
+        history: Vec<HistoryV1>,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct FsLayer {
+        blob_sum: Digest,
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct HistoryV1 {
+        v1_compatibility: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct V1Compatibility {
+        #[serde(default)]
+        throwaway: bool,
+        created: Option<String>,
+        author: Option<String>,
+        comment: Option<String>,
+        container_config: Option<ContainerConfigV1>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct ContainerConfigV1 {
+        #[serde(rename = "Cmd")]
+        cmd: Option<Vec<String>>,
+    }
+
+    let manifest: ManifestV1 = serde_json::from_slice(payload)?;
+
+    if manifest.fs_layers.len() != manifest.history.len() {
+        return Err(UnpackError::InvalidSchema1("history does not match layers"));
+    }
+
+    let mut layers = Vec::with_capacity(manifest.fs_layers.len());
+    let mut history = Vec::with_capacity(manifest.history.len());
+
+    for (layer, entry) in manifest.fs_layers.iter().zip(&manifest.history).rev() {
+        let v1: V1Compatibility = serde_json::from_str(&entry.v1_compatibility)?;
+
+        let created_by = v1
+            .container_config
+            .and_then(|c| c.cmd)
+            .map(|cmd| cmd.join(" "));
+
+        let mut item = serde_json::Map::new();
+        for (key, value) in [
+            ("created", v1.created),
+            ("author", v1.author),
+            ("created_by", created_by),
+            ("comment", v1.comment),
+        ] {
+            if let Some(value) = value {
+                item.insert(key.into(), value.into());
+            }
+        }
+
+        if v1.throwaway {
+            item.insert("empty_layer".into(), true.into());
+        } else {
+            layers.push(Blob {
+                media_type: MediaType::DockerFsTarGzip,
+                digest: layer.blob_sum.clone(),
+                size: 0,
+                unknown_size: true,
+                urls: Vec::new(),
+                annotations: Default::default(),
+            });
+        }
+
+        history.push(Value::Object(item));
+    }
+
+    // Build the image configuration from the uppermost layer.
+
+    let mut config = match manifest.history.first() {
+        Some(h) => serde_json::from_str(&h.v1_compatibility)?,
+        None => serde_json::Map::new(),
+    };
+
+    for key in IGNORED_KEYS {
+        config.remove(*key);
+    }
+
+    if let Some(arch) = manifest.architecture {
+        config.entry("architecture").or_insert(arch.into());
+    }
+
+    config.insert("history".into(), history.into());
+
+    let config = serde_json::to_vec(&config)?;
+
+    Ok(Manifest {
+        config: Blob {
+            media_type: MediaType::DockerImageV1,
+            digest: Digest::sha256(&config),
+            size: config.len(),
+            unknown_size: false,
+            urls: Vec::new(),
+            annotations: Default::default(),
+        },
+        layers,
+        annotations: Default::default(),
+        raw: Vec::new(),
+        inline_config: Some(config),
+    })
+}
+
+#[cfg(test)]
+const TEST_MANIFEST: &str = r##"{
+   "schemaVersion": 1,
+   "name": "foo/bar",
+   "tag": "latest",
+   "architecture": "amd64",
+   "fsLayers": [
+      { "blobSum": "sha256:0000000000000000000000000000000000000000000000000000000000000003" },
+      { "blobSum": "sha256:0000000000000000000000000000000000000000000000000000000000000002" },
+      { "blobSum": "sha256:0000000000000000000000000000000000000000000000000000000000000001" }
+   ],
+   "history": [
+      { "v1Compatibility": "{\"id\":\"c\",\"parent\":\"b\",\"os\":\"linux\",\"config\":{\"Cmd\":[\"/bin/sh\"],\"Env\":[\"A=1\"]},\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"#(nop) CMD\"]},\"throwaway\":true}" },
+      { "v1Compatibility": "{\"id\":\"b\",\"parent\":\"a\",\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"touch x\"]}}" },
+      { "v1Compatibility": "{\"id\":\"a\",\"created\":\"2015-01-01T00:00:00Z\"}" }
+   ]
+}"##;
+
+#[test]
+fn parse_schema1_manifest() {
+    let manifest = parse(TEST_MANIFEST.as_bytes()).unwrap();
+
+    let layers: Vec<_> = manifest.layers.iter().map(|l| l.digest.source()).collect();
+    assert_eq!(
+        layers,
+        [
+            "sha256:0000000000000000000000000000000000000000000000000000000000000001",
+            "sha256:0000000000000000000000000000000000000000000000000000000000000002",
+        ]
+    );
+
+    let config_data = manifest.inline_config.unwrap();
+    manifest.config.digest.verify(&config_data).unwrap();
+
+    let config: crate::ImageConfig = serde_json::from_slice(&config_data).unwrap();
+    assert_eq!(config.architecture, "amd64");
+    assert_eq!(config.os, "linux");
+    assert_eq!(config.config.cmd.unwrap(), ["/bin/sh"]);
+    assert_eq!(config.config.env, ["A=1"]);
+    assert!(config.rootfs.is_none());
+
+    let history: Vec<_> = config
+        .history
+        .iter()
+        .map(|h| (h.created_by.as_deref(), h.empty_layer))
+        .collect();
+
+    assert_eq!(
+        history,
+        [
+            (None, false),
+            (Some("/bin/sh -c touch x"), false),
+            (Some("/bin/sh -c #(nop) CMD"), true),
+        ]
+    );
+}
+
+#[test]
+fn strip_jws_signatures() {
+    // Build a signed manifest like libtrust does: the signatures are
+    // inserted before the last `\n}`.
+    let format_length = TEST_MANIFEST.rfind("\n}").unwrap();
+    let (head, tail) = TEST_MANIFEST.split_at(format_length);
+
+    let protected = BASE64_URL.encode(format!(
+        r#"{{"formatLength":{format_length},"formatTail":"{}","time":"2015-01-01T00:00:00Z"}}"#,
+        BASE64_URL.encode(tail)
+    ));
+
+    let signed = format!(
+        r#"{head},
+   "signatures": [
+      {{
+         "header": {{ "alg": "ES256" }},
+         "signature": "AAAA",
+         "protected": "{protected}"
+      }}
+   ]{tail}"#
+    );
+
+    let payload = strip_signatures(signed.as_bytes()).unwrap();
+    assert_eq!(payload, TEST_MANIFEST.as_bytes());
+
+    Digest::sha256(TEST_MANIFEST.as_bytes())
+        .verify(&payload)
+        .unwrap();
+}
@@ -0,0 +1,568 @@
+//! Manifests of the unpacked files, in the [mtree] format.
+//!
+//! The manifest is built while the layers are unpacked, so the contents
+//! of the files are hashed when they are written to the disk. It is read
+//! again by [`Repacker`](crate::Repacker) to detect changes in the files.
+//!
+//! [mtree]: https://man.freebsd.org/cgi/man.cgi?mtree(5)
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt,
+    io::{self, Write},
+    ops::Bound,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::{digest::HexString, fs::Directory};
+
+/// Type of an entry in the manifest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EntryType {
+    File,
+    Dir,
+    Link,
+    Char,
+    Block,
+    Fifo,
+}
+
+impl EntryType {
+    /// Name of the type in the `type` keyword.
+    fn name(self) -> &'static str {
+        match self {
+            EntryType::File => "file",
+            EntryType::Dir => "dir",
+            EntryType::Link => "link",
+            EntryType::Char => "char",
+            EntryType::Block => "block",
+            EntryType::Fifo => "fifo",
+        }
+    }
+}
+
+/// Metadata of a file in the manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub kind: EntryType,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    pub size: u64,
+
+    /// Seconds and nanoseconds of the modification time.
+    pub mtime: (u64, u32),
+
+    pub link: Option<PathBuf>,
+    pub sha256: Option<[u8; 32]>,
+
+    /// Major and minor numbers of a device node.
+    pub device: Option<(u32, u32)>,
+
+    /// The entry is in the image, but it may be missing in the root
+    /// filesystem, or replaced by an empty file. Used for device nodes
+    /// that could not be created.
+    pub optional: bool,
+}
+
+/// Manifest of the files in a root filesystem.
+///
+/// Paths are relative to the root, and they always start with `/`.
+#[derive(Default)]
+pub(crate) struct Mtree {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Mtree {
+    /// Add an entry. Any previous entry in the same path is replaced.
+    ///
+    /// If the new entry is not a directory, all descendants of `path`
+    /// are removed.
+    pub(crate) fn insert(&mut self, path: PathBuf, entry: Entry) {
+        if entry.kind != EntryType::Dir {
+            self.remove_children(&path);
+        }
+
+        self.entries.insert(path, entry);
+    }
+
+    /// Add a hard link to `target`. It is ignored if `target` is not
+    /// in the manifest.
+    pub(crate) fn insert_hardlink(&mut self, path: PathBuf, target: &Path) {
+        if let Some(entry) = self.entries.get(target).cloned() {
+            self.insert(path, entry);
+        }
+    }
+
+    /// Remove all descendants of `path`.
+    pub(crate) fn remove_children(&mut self, path: &Path) {
+        // Paths are compared by components, so the descendants of
+        // `path` are sorted right after it.
+        let children: Vec<_> = self
+            .entries
+            .range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+
+        for child in children {
+            self.entries.remove(&child);
+        }
+    }
+
+    /// Keep only the entries for which `f` returns `true`.
+    pub(crate) fn retain(&mut self, mut f: impl FnMut(&Path) -> bool) {
+        self.entries.retain(|p, _| f(p));
+    }
+
+    /// Return the entry for `path`, if any.
+    pub(crate) fn get(&self, path: &Path) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    /// Return all entries, sorted by their paths.
+    pub(crate) fn entries(&self) -> &BTreeMap<PathBuf, Entry> {
+        &self.entries
+    }
+
+    /// Parse a manifest written by [`write`](Self::write).
+    ///
+    /// Only the keywords generated by this module are supported.
+    pub(crate) fn parse(data: &str) -> Result<Mtree, String> {
+        let mut entries = BTreeMap::new();
+
+        for (number, line) in data.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |msg| format!("line {}: {msg}", number + 1);
+
+            let mut fields = line.split(' ');
+
+            let path = match fields.next().and_then(|p| p.strip_prefix("./")) {
+                Some(path) => Path::new("/").join(OsStr::from_bytes(&unescape(path))),
+                None => return Err(error("invalid path")),
+            };
+
+            let mut entry = Entry {
+                kind: EntryType::File,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                mtime: (0, 0),
+                link: None,
+                sha256: None,
+                device: None,
+                optional: false,
+            };
+
+            let mut kind = None;
+
+            for field in fields {
+                if field == "optional" {
+                    entry.optional = true;
+                    continue;
+                }
+
+                let (key, value) = field.split_once('=').ok_or_else(|| error(field))?;
+
+                let parsed = match key {
+                    "type" => {
+                        kind = [
+                            EntryType::File,
+                            EntryType::Dir,
+                            EntryType::Link,
+                            EntryType::Char,
+                            EntryType::Block,
+                            EntryType::Fifo,
+                        ]
+                        .into_iter()
+                        .find(|k| k.name() == value);
+
+                        kind.is_some()
+                    }
+
+                    "mode" => u32::from_str_radix(value, 8)
+                        .map(|m| entry.mode = m)
+                        .is_ok(),
+                    "uid" => value.parse().map(|n| entry.uid = n).is_ok(),
+                    "gid" => value.parse().map(|n| entry.gid = n).is_ok(),
+                    "size" => value.parse().map(|n| entry.size = n).is_ok(),
+
+                    "time" => parse_time(value).map(|t| entry.mtime = t).is_some(),
+
+                    "link" => {
+                        entry.link = Some(OsStr::from_bytes(&unescape(value)).into());
+                        true
+                    }
+
+                    "device" => {
+                        entry.device = parse_device(value);
+                        entry.device.is_some()
+                    }
+
+                    "sha256digest" => {
+                        entry.sha256 = decode_sha256(value);
+                        entry.sha256.is_some()
+                    }
+
+                    _ => true,
+                };
+
+                if !parsed {
+                    return Err(error(field));
+                }
+            }
+
+            entry.kind = kind.ok_or_else(|| error("missing type"))?;
+            entries.insert(path, entry);
+        }
+
+        Ok(Mtree { entries })
+    }
+
+    /// Write the manifest to `output`.
+    ///
+    /// Directories that were created implicitly (because they are not
+    /// in the archives) are read from `rootfs`. Their owner is always
+    /// `0:0`, like in the image, instead of the owner in the host.
+    pub(crate) fn write(&mut self, rootfs: &Directory, mut output: impl Write) -> io::Result<()> {
+        // Add missing parents.
+        let mut missing = Vec::new();
+        for path in self.entries.keys() {
+            for parent in path.ancestors().skip(1) {
+                if parent != Path::new("/") && !self.entries.contains_key(parent) {
+                    missing.push(parent.to_owned());
+                }
+            }
+        }
+
+        for path in missing {
+            if self.entries.contains_key(&path) {
+                continue;
+            }
+
+            let relative = path.strip_prefix("/").unwrap_or(&path);
+            let stat = rustix::fs::fstat(rootfs.open_directory(relative, false)?)?;
+
+            // `st_mtime` is signed in some architectures.
+            #[allow(clippy::useless_conversion)]
+            let mtime = (
+                stat.st_mtime.try_into().unwrap_or_default(),
+                stat.st_mtime_nsec.try_into().unwrap_or_default(),
+            );
+
+            let entry = Entry {
+                kind: EntryType::Dir,
+                mode: stat.st_mode & 0o7777,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                mtime,
+                link: None,
+                sha256: None,
+                device: None,
+                optional: false,
+            };
+
+            self.entries.insert(path, entry);
+        }
+
+        writeln!(output, "#mtree")?;
+
+        for (path, entry) in &self.entries {
+            write!(output, ".{}", Escaped(path.as_os_str().as_bytes()))?;
+
+            write!(
+                output,
+                " type={} mode={:04o} uid={} gid={}",
+                entry.kind.name(),
+                entry.mode,
+                entry.uid,
+                entry.gid
+            )?;
+
+            if entry.kind == EntryType::File {
+                write!(output, " size={}", entry.size)?;
+            }
+
+            write!(output, " time={}.{:09}", entry.mtime.0, entry.mtime.1)?;
+
+            if let Some(link) = &entry.link {
+                write!(output, " link={}", Escaped(link.as_os_str().as_bytes()))?;
+            }
+
+            if let Some(sha256) = &entry.sha256 {
+                write!(output, " sha256digest={}", HexString(sha256))?;
+            }
+
+            if let Some((major, minor)) = entry.device {
+                write!(output, " device=native,{major},{minor}")?;
+            }
+
+            if entry.optional {
+                write!(output, " optional")?;
+            }
+
+            writeln!(output)?;
+        }
+
+        output.flush()
+    }
+}
+
+/// Compute the SHA256 digest of the data written to `inner`.
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Return the digest of the data written so far.
+    pub fn digest(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+
+    /// Return the inner writer, and the digest of the data written to it.
+    pub fn into_inner(self) -> (W, [u8; 32]) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode a path with the `vis(3)` style used by mtree, so special
+/// characters are written as octal escapes.
+struct Escaped<'a>(&'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use fmt::Write;
+
+        for &byte in self.0 {
+            if byte.is_ascii_graphic() && !matches!(byte, b'\\' | b'#' | b'=') {
+                f.write_char(byte as char)?;
+            } else {
+                write!(f, "\\{byte:03o}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode the escapes generated by [`Escaped`].
+fn unescape(data: &str) -> Vec<u8> {
+    let mut bytes = data.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+
+    while let Some((&byte, rest)) = bytes.split_first() {
+        let code = rest
+            .get(..3)
+            .and_then(|code| u8::from_str_radix(std::str::from_utf8(code).ok()?, 8).ok());
+
+        match (byte, code) {
+            (b'\\', Some(code)) => {
+                output.push(code);
+                bytes = &rest[3..];
+            }
+
+            _ => {
+                output.push(byte);
+                bytes = rest;
+            }
+        }
+    }
+
+    output
+}
+
+/// Parse the value of the `time` keyword, as `seconds.nanoseconds`.
+fn parse_time(value: &str) -> Option<(u64, u32)> {
+    let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+
+    let nanos = match nanos.len() {
+        1..=9 if nanos.bytes().all(|b| b.is_ascii_digit()) => {
+            nanos.parse::<u32>().ok()? * 10u32.pow(9 - nanos.len() as u32)
+        }
+        _ => return None,
+    };
+
+    Some((secs.parse().ok()?, nanos))
+}
+
+/// Parse the value of the `device` keyword, as `native,major,minor`.
+fn parse_device(value: &str) -> Option<(u32, u32)> {
+    let mut fields = value.strip_prefix("native,")?.split(',');
+    let major = fields.next()?.parse().ok()?;
+    let minor = fields.next()?.parse().ok()?;
+    fields.next().is_none().then_some((major, minor))
+}
+
+/// Decode a SHA256 digest in hexadecimal.
+fn decode_sha256(hex: &str) -> Option<[u8; 32]> {
+    let mut digest = [0; 32];
+
+    if hex.len() != digest.len() * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+#[test]
+fn build_manifest() {
+    let file = |size| Entry {
+        kind: EntryType::File,
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        size,
+        mtime: (1, 0),
+        link: None,
+        sha256: None,
+        device: None,
+        optional: false,
+    };
+
+    let dir = Entry {
+        kind: EntryType::Dir,
+        mode: 0o755,
+        size: 0,
+        ..file(0)
+    };
+
+    let mut mtree = Mtree::default();
+    mtree.insert("/a".into(), dir.clone());
+    mtree.insert("/a/b c".into(), file(1));
+    mtree.insert("/a/d".into(), dir.clone());
+    mtree.insert("/a/d/e".into(), file(2));
+    mtree.insert_hardlink("/f".into(), Path::new("/a/d/e"));
+
+    // Replace a directory with a file.
+    mtree.insert("/a/d".into(), file(3));
+    assert!(!mtree.entries.contains_key(Path::new("/a/d/e")));
+
+    mtree.retain(|p| p != Path::new("/a/b c"));
+    assert_eq!(mtree.entries.len(), 3);
+    assert_eq!(mtree.entries[Path::new("/f")], file(2));
+
+    mtree.insert("/a/x=#".into(), file(4));
+    mtree.remove_children(Path::new("/a"));
+    assert_eq!(mtree.entries.len(), 2);
+
+    assert_eq!(Escaped(b"a b=\\#\n").to_string(), r"a\040b\075\134\043\012");
+}
+
+#[test]
+fn remove_children_with_many_siblings() {
+    let file = Entry {
+        kind: EntryType::File,
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        size: 0,
+        mtime: (0, 0),
+        link: None,
+        sha256: None,
+        device: None,
+        optional: false,
+    };
+
+    let dir = Entry {
+        kind: EntryType::Dir,
+        ..file.clone()
+    };
+
+    let mut mtree = Mtree::default();
+    mtree.insert("/a".into(), dir.clone());
+    mtree.insert("/a/b".into(), dir.clone());
+
+    for n in 0..100_000 {
+        mtree.insert(format!("/a/b/{n}").into(), file.clone());
+    }
+
+    // Paths with `/a/b` as a string prefix are not its descendants.
+    mtree.insert("/a/b c".into(), file.clone());
+    mtree.insert("/a/bc".into(), file.clone());
+    mtree.insert("/a/b.d/e".into(), file.clone());
+    assert_eq!(mtree.entries.len(), 100_005);
+
+    mtree.insert("/a/b".into(), file);
+
+    let paths: Vec<_> = mtree.entries.keys().map(|p| p.to_str().unwrap()).collect();
+    assert_eq!(paths, ["/a", "/a/b", "/a/b c", "/a/b.d/e", "/a/bc"]);
+}
+
+#[test]
+fn parse_manifest() {
+    let data = "\
+        #mtree\n\
+        ./a type=dir mode=0755 uid=0 gid=0 time=1.000000000\n\
+        ./a/b\\040c type=file mode=0644 uid=1 gid=2 size=3 time=4.000000500 \
+            sha256digest=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n\
+        ./a/l type=link mode=0777 uid=0 gid=0 time=5.000000000 link=b\\040c\n\
+        ./a/null type=char mode=0666 uid=0 gid=0 time=6.000000000 device=native,1,3 optional\n";
+
+    let mtree = Mtree::parse(data).unwrap();
+    assert_eq!(mtree.entries.len(), 4);
+
+    let file = mtree.get(Path::new("/a/b c")).unwrap();
+    assert_eq!(file.kind, EntryType::File);
+    assert_eq!((file.mode, file.uid, file.gid), (0o644, 1, 2));
+    assert_eq!((file.size, file.mtime), (3, (4, 500)));
+    assert_eq!(file.sha256.unwrap()[..2], [0xba, 0x78]);
+
+    let link = mtree.get(Path::new("/a/l")).unwrap();
+    assert_eq!(link.link.as_deref(), Some(Path::new("b c")));
+
+    let null = mtree.get(Path::new("/a/null")).unwrap();
+    assert_eq!(null.kind, EntryType::Char);
+    assert_eq!(null.device, Some((1, 3)));
+    assert!(null.optional);
+
+    // Round-trip.
+    let mut output = Vec::new();
+    Mtree::parse(data)
+        .unwrap()
+        .write(&Directory::new("/").unwrap(), &mut output)
+        .unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), data);
+
+    assert!(Mtree::parse("./a mode=0644\n").is_err());
+    assert!(Mtree::parse("./a type=file mode=9\n").is_err());
+    assert!(Mtree::parse("a type=file\n").is_err());
+    assert!(Mtree::parse("./a type=file time=1.x\n").is_err());
+    assert_eq!(
+        Mtree::parse("./a type=file time=1.5\n").unwrap().entries[Path::new("/a")].mtime,
+        (1, 500_000_000)
+    );
+}
@@ -0,0 +1,300 @@
+use std::{env::consts, fmt, str::FromStr};
+
+/// Errors from [`Platform::from_str`].
+#[derive(thiserror::Error, Debug)]
+pub enum PlatformError {
+    #[error("Missing operating system.")]
+    MissingOs,
+
+    #[error("Missing architecture.")]
+    MissingArchitecture,
+
+    #[error("Too many components in the platform.")]
+    TooManyComponents,
+}
+
+/// Platform (operating system and CPU architecture) of an image.
+///
+/// It can be parsed from strings with the `os/architecture[/variant]`
+/// format used by `docker pull --platform`.
+///
+/// Values are normalized the same way [containerd] does, so aliases
+/// like `x86_64` or `aarch64` are translated to their Go names, and
+/// the default variants (like `v8` for `arm64`) are omitted.
+///
+/// [containerd]: https://github.com/containerd/platforms
+///
+/// # Examples
+///
+/// ```
+/// # use oci_unpack::*;
+/// let platform: Platform = "linux/arm/v7".parse().unwrap();
+/// assert_eq!(platform.os, "linux");
+/// assert_eq!(platform.architecture, "arm");
+/// assert_eq!(platform.variant.as_deref(), Some("v7"));
+///
+/// let platform: Platform = "linux/aarch64".parse().unwrap();
+/// assert_eq!(platform.to_string(), "linux/arm64");
+/// assert!(platform.matches(&"linux/arm64/v8".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, serde::Deserialize)]
+pub struct Platform {
+    /// Operating system, like `linux` or `windows`.
+    pub os: String,
+
+    /// CPU architecture, like `amd64` or `arm64`.
+    pub architecture: String,
+
+    /// Variant of the CPU, like `v7` for `arm`.
+    #[serde(default)]
+    pub variant: Option<String>,
+
+    /// Version of the operating system. Used by Windows images.
+    #[serde(default, rename = "os.version")]
+    pub os_version: Option<String>,
+
+    /// Required features of the operating system.
+    #[serde(default, rename = "os.features")]
+    pub os_features: Vec<String>,
+}
+
+impl Platform {
+    /// Create a new platform for the `os`/`architecture` pair.
+    pub fn new(os: impl Into<String>, architecture: impl Into<String>) -> Self {
+        Platform {
+            os: os.into(),
+            architecture: architecture.into(),
+            ..Platform::default()
+        }
+        .normalize()
+    }
+
+    /// Return the platform of the current process.
+    pub fn current() -> Self {
+        Platform::new(consts::OS, consts::ARCH)
+    }
+
+    /// Set the CPU variant.
+    pub fn with_variant(mut self, variant: impl Into<String>) -> Self {
+        self.variant = Some(variant.into());
+        self.normalize()
+    }
+
+    /// Set the version of the operating system.
+    pub fn with_os_version(mut self, os_version: impl Into<String>) -> Self {
+        self.os_version = Some(os_version.into());
+        self
+    }
+
+    /// Translate aliases to the canonical names, and remove the
+    /// variant if it is the default one for the architecture.
+    ///
+    /// It follows the rules in the `Normalize` function from
+    /// containerd.
+    pub fn normalize(mut self) -> Self {
+        self.os = self.os.to_lowercase();
+        if self.os == "macos" {
+            self.os = "darwin".into();
+        }
+
+        let architecture = self.architecture.to_lowercase();
+        let variant = self.variant.take().map(|v| v.to_lowercase());
+
+        let (architecture, variant) = match (architecture.as_str(), variant) {
+            ("i386" | "x86", v) => ("386", v),
+
+            ("x86_64" | "x86-64" | "amd64", Some(v)) if v == "v1" => ("amd64", None),
+            ("x86_64" | "x86-64" | "amd64", v) => ("amd64", v),
+
+            ("aarch64" | "arm64", Some(v)) => {
+                let number = v.strip_prefix('v').unwrap_or(&v);
+                let number = number.strip_suffix(".0").unwrap_or(number);
+                if number == "8" {
+                    ("arm64", None)
+                } else if number.parse::<f32>().is_ok() {
+                    ("arm64", Some(format!("v{number}")))
+                } else {
+                    ("arm64", Some(v))
+                }
+            }
+            ("aarch64" | "arm64", None) => ("arm64", None),
+
+            ("armhf", _) => ("arm", Some("v7".into())),
+            ("armel", _) => ("arm", Some("v6".into())),
+            ("arm", None) => ("arm", Some("v7".into())),
+            ("arm", Some(v)) if matches!(v.as_str(), "5" | "6" | "7" | "8") => {
+                ("arm", Some(format!("v{v}")))
+            }
+
+            (arch, v) => (arch, v),
+        };
+
+        self.architecture = architecture.to_owned();
+        self.variant = variant;
+        self
+    }
+
+    /// Return `true` if an image for the `other` platform can be used
+    /// when this platform is requested.
+    ///
+    /// Both platforms are normalized before the comparison. If this
+    /// platform contains an OS version, its dot-separated components
+    /// must be a prefix of the version in `other`. All features in this
+    /// platform must be present in `other`.
+    pub fn matches(&self, other: &Platform) -> bool {
+        let this = self.clone().normalize();
+        let other = other.clone().normalize();
+
+        if this.os != other.os
+            || this.architecture != other.architecture
+            || this.variant != other.variant
+        {
+            return false;
+        }
+
+        if let Some(version) = &this.os_version {
+            match &other.os_version {
+                Some(v) if version_prefix(version, v) => (),
+                _ => return false,
+            }
+        }
+
+        this.os_features
+            .iter()
+            .all(|f| other.os_features.contains(f))
+    }
+}
+
+/// Return `true` if every component of `prefix` is equal to the
+/// component at the same position in `version`.
+fn version_prefix(prefix: &str, version: &str) -> bool {
+    let mut version = version.split('.');
+    prefix.split('.').all(|c| version.next() == Some(c))
+}
+
+impl FromStr for Platform {
+    type Err = PlatformError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+
+        let os = match parts.next() {
+            Some(os) if !os.is_empty() => os,
+            _ => return Err(PlatformError::MissingOs),
+        };
+
+        let architecture = match parts.next() {
+            Some(arch) if !arch.is_empty() => arch,
+            _ => return Err(PlatformError::MissingArchitecture),
+        };
+
+        let variant = parts.next().filter(|v| !v.is_empty());
+
+        if parts.next().is_some() {
+            return Err(PlatformError::TooManyComponents);
+        }
+
+        let platform = Platform::new(os, architecture);
+        Ok(match variant {
+            Some(v) => platform.with_variant(v),
+            None => platform,
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_platforms() {
+    macro_rules! check {
+        ($input:expr, $expected:expr) => {
+            assert_eq!(
+                Platform::from_str($input).unwrap().to_string(),
+                $expected,
+                "{}",
+                $input
+            );
+        };
+    }
+
+    check!("linux/amd64", "linux/amd64");
+    check!("linux/x86_64", "linux/amd64");
+    check!("linux/amd64/v1", "linux/amd64");
+    check!("linux/amd64/v3", "linux/amd64/v3");
+    check!("linux/aarch64", "linux/arm64");
+    check!("linux/arm64/v8", "linux/arm64");
+    check!("linux/arm64/8", "linux/arm64");
+    check!("linux/arm64/9", "linux/arm64/v9");
+    check!("linux/arm64/9.0", "linux/arm64/v9");
+    check!("linux/arm64/v9.0", "linux/arm64/v9");
+    check!("linux/arm64/v8.2", "linux/arm64/v8.2");
+    check!("linux/arm", "linux/arm/v7");
+    check!("linux/arm/6", "linux/arm/v6");
+    check!("linux/armhf", "linux/arm/v7");
+    check!("linux/armel", "linux/arm/v6");
+    check!("linux/i386", "linux/386");
+    check!("Linux/s390x", "linux/s390x");
+
+    assert!(matches!(
+        Platform::from_str("/amd64"),
+        Err(PlatformError::MissingOs)
+    ));
+
+    assert!(matches!(
+        Platform::from_str("linux"),
+        Err(PlatformError::MissingArchitecture)
+    ));
+
+    assert!(matches!(
+        Platform::from_str("linux/arm/v7/x"),
+        Err(PlatformError::TooManyComponents)
+    ));
+}
+
+#[test]
+fn match_platforms() {
+    let p = |s: &str| Platform::from_str(s).unwrap();
+
+    assert!(p("linux/arm64").matches(&p("linux/arm64/v8")));
+    assert!(p("linux/amd64").matches(&p("linux/amd64/v1")));
+    assert!(p("linux/arm/v7").matches(&p("linux/arm")));
+    assert!(!p("linux/arm/v7").matches(&p("linux/arm/v6")));
+    assert!(!p("linux/amd64").matches(&p("windows/amd64")));
+
+    // Index entries are not normalized by the parser.
+    let entry: Platform = serde_json::from_str(
+        r#"{
+            "architecture": "amd64",
+            "os": "windows",
+            "os.version": "10.0.17763.1234",
+            "os.features": ["win32k"]
+        }"#,
+    )
+    .unwrap();
+
+    assert!(p("windows/amd64").matches(&entry));
+    assert!(p("windows/amd64")
+        .with_os_version("10.0.17763")
+        .matches(&entry));
+    assert!(!p("windows/amd64")
+        .with_os_version("10.0.20348")
+        .matches(&entry));
+    assert!(!p("windows/amd64").with_os_version("10.0.1").matches(&entry));
+
+    let mut with_features = p("windows/amd64");
+    with_features.os_features.push("win32k".into());
+    assert!(with_features.matches(&entry));
+
+    with_features.os_features.push("other".into());
+    assert!(!with_features.matches(&entry));
+}
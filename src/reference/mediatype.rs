@@ -3,25 +3,29 @@ use std::{fmt, str::FromStr};
 /// Generate the `MediaType` enum, its `FromStr` and `Display`
 /// implementations, and the associated constant `ALL` with all
 /// the valid values.
+///
+/// Each variant can have attributes, like `#[cfg(...)]`, that are
+/// applied to every item generated for it.
 macro_rules! media_types {
-    ($($variant:ident = $mediatype:expr,)*) => {
+    ($($(#[$attr:meta])* $variant:ident = $mediatype:expr,)*) => {
         /// Known media types.
         #[non_exhaustive]
         #[derive(Copy, Clone, PartialEq, Debug)]
         pub enum MediaType {
             $(
                 #[doc = concat!("Variant for `", $mediatype, "`.")]
+                $(#[$attr])*
                 $variant,
             )*
         }
 
         impl MediaType {
             /// List with all known media types.
-            pub(crate) const ALL: &[&str] = &[ $($mediatype),* ];
+            pub(crate) const ALL: &[&str] = &[ $($(#[$attr])* $mediatype),* ];
 
             pub fn as_str(&self) -> &'static str {
                 match self {
-                    $(MediaType::$variant => $mediatype,)*
+                    $($(#[$attr])* MediaType::$variant => $mediatype,)*
                 }
             }
         }
@@ -31,7 +35,7 @@ macro_rules! media_types {
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 match s {
-                    $($mediatype => Ok(MediaType::$variant),)*
+                    $($(#[$attr])* $mediatype => Ok(MediaType::$variant),)*
                     _ => Err(InvalidMediaType),
                 }
             }
@@ -46,18 +50,49 @@ macro_rules! media_types {
 }
 
 media_types!(
+    DockerForeignFsTarGzip = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
     DockerFsTarGzip = "application/vnd.docker.image.rootfs.diff.tar.gzip",
     DockerImageV1 = "application/vnd.docker.container.image.v1+json",
     DockerManifestList = "application/vnd.docker.distribution.manifest.list.v2+json",
+    #[cfg(feature = "schema1")]
+    DockerManifestV1 = "application/vnd.docker.distribution.manifest.v1+json",
+    #[cfg(feature = "schema1")]
+    DockerManifestV1Signed = "application/vnd.docker.distribution.manifest.v1+prettyjws",
     DockerManifestV2 = "application/vnd.docker.distribution.manifest.v2+json",
     OciConfig = "application/vnd.oci.image.config.v1+json",
     OciFsTar = "application/vnd.oci.image.layer.v1.tar",
+    #[cfg(feature = "encryption")]
+    OciFsTarEncrypted = "application/vnd.oci.image.layer.v1.tar+encrypted",
     OciFsTarGzip = "application/vnd.oci.image.layer.v1.tar+gzip",
+    #[cfg(feature = "encryption")]
+    OciFsTarGzipEncrypted = "application/vnd.oci.image.layer.v1.tar+gzip+encrypted",
     OciFsTarZstd = "application/vnd.oci.image.layer.v1.tar+zstd",
+    #[cfg(feature = "encryption")]
+    OciFsTarZstdEncrypted = "application/vnd.oci.image.layer.v1.tar+zstd+encrypted",
+    OciFsTarNondistributable = "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    OciFsTarGzipNondistributable = "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    OciFsTarZstdNondistributable = "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
     OciImageIndex = "application/vnd.oci.image.index.v1+json",
     OciManifestV1 = "application/vnd.oci.image.manifest.v1+json",
 );
 
+impl MediaType {
+    /// Return `true` if this is a layer that the registry may not be
+    /// allowed to distribute, like the Windows base layers.
+    ///
+    /// Those layers are usually downloaded from the URLs in their
+    /// descriptors.
+    pub fn is_foreign_layer(&self) -> bool {
+        matches!(
+            self,
+            MediaType::DockerForeignFsTarGzip
+                | MediaType::OciFsTarNondistributable
+                | MediaType::OciFsTarGzipNondistributable
+                | MediaType::OciFsTarZstdNondistributable
+        )
+    }
+}
+
 pub struct InvalidMediaType;
 
 struct MediaTypeVisitor;
@@ -0,0 +1,1014 @@
+//! Generate a new layer from the changes made to an unpacked image.
+//!
+//! The state of the root filesystem after unpacking the image is read
+//! from the mtree manifest written by [`Unpacker::mtree`](crate::Unpacker::mtree).
+//! The files in `rootfs` are compared with it to build a layer with the
+//! differences, which is added to a copy of the image in an
+//! [OCI layout](https://github.com/opencontainers/image-spec/blob/main/image-layout.md).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    fs::Directory,
+    idmap::{self, IdMapping},
+    mtree::{Entry, EntryType, HashingWriter, Mtree},
+    unpacker::{blob_path, CONFIG_PATH, IMAGE_CONFIG_PATH, MANIFEST_PATH, ROOTFS_PATH},
+    Compression, Digest, MediaType,
+};
+
+/// Prefix for the name of whiteout files.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the marker for opaque directories.
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// Annotation for the name of an image in `index.json`.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Errors from [`Repacker::repack`].
+#[derive(thiserror::Error, Debug)]
+pub enum RepackError {
+    #[error("I/O error: {1}: {0}")]
+    Io(io::Error, PathBuf),
+
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Missing {0}. The image must be unpacked with Unpacker::mtree.")]
+    MissingState(PathBuf),
+
+    #[error("Invalid mtree manifest: {0}")]
+    InvalidMtree(String),
+
+    #[error("Invalid image: {0}")]
+    InvalidImage(&'static str),
+
+    #[error("Unsupported compression for the new layer: {0}")]
+    UnsupportedCompression(Compression),
+}
+
+/// Return a function to wrap an [`io::Error`] with the path related
+/// to the I/O operation.
+fn io_error(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> RepackError {
+    let path = path.into();
+    move |e| RepackError::Io(e, path)
+}
+
+/// Generate a new layer from the changes made to an image unpacked
+/// by [`Unpacker`](crate::Unpacker).
+///
+/// The image must be unpacked with [`Unpacker::mtree`](crate::Unpacker::mtree),
+/// so the original state of the files is known.
+///
+/// The new layer contains the files added or modified in `rootfs`, and
+/// whiteouts for the deleted files. If all the files of a directory are
+/// deleted, the directory is marked as opaque.
+///
+/// Extended attributes are not tracked in the mtree manifest, so they are
+/// not copied to the new layer, and changes to them are not detected.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use oci_unpack::*;
+/// # fn f(reference: Reference) {
+/// Unpacker::new(reference)
+///     .mtree(true)
+///     .unpack("/tmp/image")
+///     .unwrap();
+///
+/// // Modify the files in /tmp/image/rootfs.
+///
+/// Repacker::new("/tmp/image")
+///     .created_by("install packages")
+///     .tag("latest")
+///     .repack("/tmp/layout")
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Repacker {
+    target: PathBuf,
+    compression: Compression,
+    created_by: Option<String>,
+    tag: Option<String>,
+    uid_map: Vec<IdMapping>,
+    gid_map: Vec<IdMapping>,
+}
+
+/// Image generated by [`Repacker::repack`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RepackedImage {
+    /// Digest of the new manifest.
+    pub manifest_digest: Digest,
+
+    /// Digest of the new image configuration.
+    pub config_digest: Digest,
+
+    /// Digest of the new layer.
+    pub layer_digest: Digest,
+
+    /// Digest of the uncompressed layer, added to the `diff_ids`
+    /// of the image configuration.
+    pub diff_id: Digest,
+}
+
+impl Repacker {
+    /// Create a new instance for the image unpacked in `target`.
+    pub fn new(target: impl Into<PathBuf>) -> Self {
+        Repacker {
+            target: target.into(),
+            compression: Compression::Gzip,
+            created_by: None,
+            tag: None,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+        }
+    }
+
+    /// Compression for the new layer.
+    ///
+    /// It can be [`Compression::None`], [`Compression::Gzip`], or, if the
+    /// crate is built with the `zstd` feature, [`Compression::Zstd`]. Docker
+    /// manifests only support gzip.
+    ///
+    /// The default value is [`Compression::Gzip`].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Command to describe the changes, for the `created_by` field in the
+    /// history of the image.
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Name for the new image in the `index.json` file of the layout.
+    ///
+    /// Any other image with the same name is removed from the index.
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Set the mappings used to unpack the image with
+    /// [`Unpacker::uid_map`](crate::Unpacker::uid_map), so the owners
+    /// of the files are translated back to the user IDs in the image.
+    ///
+    /// Files owned by IDs out of the mappings are added to the layer
+    /// with the overflow ID (`65534`).
+    pub fn uid_map(mut self, mappings: impl IntoIterator<Item = IdMapping>) -> Self {
+        self.uid_map = mappings.into_iter().collect();
+        self
+    }
+
+    /// Like [`uid_map`](Self::uid_map), for the group IDs.
+    pub fn gid_map(mut self, mappings: impl IntoIterator<Item = IdMapping>) -> Self {
+        self.gid_map = mappings.into_iter().collect();
+        self
+    }
+
+    /// Generate the new layer, and write the new image to the OCI
+    /// layout in `layout`.
+    ///
+    /// The blobs of the previous layers are hard-linked (or copied, if
+    /// the layout is in another filesystem) from the target directory.
+    /// Foreign layers are only added if they were downloaded.
+    ///
+    /// After the image is written, the files in the target directory are
+    /// updated, so the next call to `repack` only includes the changes
+    /// made after this one.
+    pub fn repack(&self, layout: impl AsRef<Path>) -> Result<RepackedImage, RepackError> {
+        let layout = layout.as_ref();
+        let target = &self.target;
+
+        let raw_manifest = read_state(&target.join(MANIFEST_PATH))?;
+        let manifest_digest = Digest::sha256(&raw_manifest);
+        let mut manifest: Value = serde_json::from_slice(&raw_manifest)?;
+
+        let mtree_path = target.join(mtree_file_name(&manifest_digest));
+        let original = String::from_utf8_lossy(&read_state(&mtree_path)?).into_owned();
+        let original = Mtree::parse(&original).map_err(RepackError::InvalidMtree)?;
+
+        let config_path = match target.join(IMAGE_CONFIG_PATH).exists() {
+            true => target.join(IMAGE_CONFIG_PATH),
+            false => target.join(CONFIG_PATH),
+        };
+
+        let mut config: Value = serde_json::from_slice(&read_state(&config_path)?)?;
+
+        let manifest_type = manifest
+            .get("mediaType")
+            .and_then(Value::as_str)
+            .unwrap_or(MediaType::OciManifestV1.as_str())
+            .to_owned();
+
+        let layer_type = self.layer_media_type(&manifest_type)?;
+
+        // Compare the files in the root filesystem with the original state.
+
+        let rootfs = target.join(ROOTFS_PATH);
+
+        let owners = Owners {
+            from_disk: rustix::process::geteuid().is_root(),
+            uid_map: &self.uid_map,
+            gid_map: &self.gid_map,
+        };
+
+        // Files changed after the manifest was written are always hashed,
+        // even if their size and mtime are the same.
+        let mtree_metadata = fs::metadata(&mtree_path).map_err(io_error(&mtree_path))?;
+        let mtree_time = (mtree_metadata.mtime(), mtree_metadata.mtime_nsec());
+
+        let mut current = BTreeMap::new();
+        scan_directory(
+            &rootfs,
+            Path::new("/"),
+            &original,
+            &owners,
+            mtree_time,
+            &mut current,
+        )?;
+
+        let changes = Changes::new(&original, &current);
+
+        let blobs_dir = layout.join("blobs/sha256");
+        fs::create_dir_all(&blobs_dir).map_err(io_error(&blobs_dir))?;
+
+        // Add the previous layers, so every blob referenced by the new
+        // manifest is in the layout.
+
+        let Some(Value::Array(layers)) = manifest.get("layers") else {
+            return Err(RepackError::InvalidImage("missing layers in the manifest"));
+        };
+
+        for layer in layers {
+            add_layer(target, layout, layer)?;
+        }
+
+        // Write the layer.
+
+        let layer_file = TempFile::new(&blobs_dir);
+        let layer_path = &layer_file.0;
+        let (layer_hash, diff_hash) = write_layer(
+            &rootfs,
+            &current,
+            &changes,
+            self.compression,
+            File::create(layer_path).map_err(io_error(layer_path))?,
+        )
+        .map_err(io_error(layer_path))?;
+
+        let layer_digest = Digest::from_sha256(layer_hash);
+        let layer_size = fs::metadata(layer_path)
+            .map_err(io_error(layer_path))?
+            .len();
+
+        let layer_blob = layout.join(blob_path(&layer_digest));
+        layer_file.persist(&layer_blob)?;
+
+        // Add the layer to the configuration and the manifest.
+
+        let diff_id = Digest::from_sha256(diff_hash);
+        let now = format_timestamp(SystemTime::now());
+
+        let diff_ids = config
+            .pointer_mut("/rootfs/diff_ids")
+            .and_then(Value::as_array_mut)
+            .ok_or(RepackError::InvalidImage(
+                "missing rootfs.diff_ids in the configuration",
+            ))?;
+
+        diff_ids.push(diff_id.source().into());
+
+        let mut history = json!({ "created": now });
+        if let Some(created_by) = &self.created_by {
+            history["created_by"] = created_by.as_str().into();
+        }
+
+        let config_object = config
+            .as_object_mut()
+            .ok_or(RepackError::InvalidImage("invalid configuration"))?;
+
+        config_object.insert("created".into(), now.into());
+        match config_object.entry("history").or_insert_with(|| json!([])) {
+            Value::Array(items) => items.push(history),
+            _ => {
+                return Err(RepackError::InvalidImage(
+                    "invalid history in the configuration",
+                ))
+            }
+        }
+
+        let raw_config = serde_json::to_vec(&config)?;
+        let config_digest = write_blob(&blobs_dir, &raw_config)?;
+
+        manifest["config"]["digest"] = config_digest.source().into();
+        manifest["config"]["size"] = raw_config.len().into();
+
+        match manifest.get_mut("layers") {
+            Some(Value::Array(layers)) => layers.push(json!({
+                "mediaType": layer_type.as_str(),
+                "digest": layer_digest.source(),
+                "size": layer_size,
+            })),
+
+            _ => return Err(RepackError::InvalidImage("missing layers in the manifest")),
+        }
+
+        let raw_manifest = serde_json::to_vec(&manifest)?;
+        let manifest_digest = write_blob(&blobs_dir, &raw_manifest)?;
+
+        self.update_index(layout, &manifest_type, &manifest_digest, raw_manifest.len())?;
+
+        // Update the target directory with the new image.
+
+        let mut mtree = Mtree::default();
+        for (path, file) in current {
+            mtree.insert(path, file.entry);
+        }
+
+        // Keep the device nodes that were not created in the root
+        // filesystem, if their directories still exist.
+        for (path, entry) in original.entries() {
+            let parent_exists = path
+                .parent()
+                .is_some_and(|p| p == Path::new("/") || mtree.get(p).is_some());
+
+            if entry.optional && parent_exists && mtree.get(path).is_none() {
+                mtree.insert(path.clone(), entry.clone());
+            }
+        }
+
+        // Keep the new layer for the next repack.
+        link_or_copy(&layer_blob, &target.join(blob_path(&layer_digest)))?;
+
+        let new_mtree_path = target.join(mtree_file_name(&manifest_digest));
+        let rootfs_dir = Directory::new(&rootfs).map_err(|e| RepackError::Io(e.into(), rootfs))?;
+
+        File::create(&new_mtree_path)
+            .and_then(|file| mtree.write(&rootfs_dir, BufWriter::new(file)))
+            .map_err(io_error(&new_mtree_path))?;
+
+        for (path, data) in [
+            (&config_path, &raw_config),
+            (&target.join(MANIFEST_PATH), &raw_manifest),
+        ] {
+            fs::write(path, data).map_err(io_error(path))?;
+        }
+
+        if new_mtree_path != mtree_path {
+            fs::remove_file(&mtree_path).map_err(io_error(&mtree_path))?;
+        }
+
+        Ok(RepackedImage {
+            manifest_digest,
+            config_digest,
+            layer_digest,
+            diff_id,
+        })
+    }
+
+    /// Return the media type for the new layer.
+    fn layer_media_type(&self, manifest_type: &str) -> Result<MediaType, RepackError> {
+        let docker = manifest_type == MediaType::DockerManifestV2.as_str();
+
+        let media_type = match (docker, self.compression) {
+            (true, Compression::Gzip) => MediaType::DockerFsTarGzip,
+            (false, Compression::None) => MediaType::OciFsTar,
+            (false, Compression::Gzip) => MediaType::OciFsTarGzip,
+
+            #[cfg(feature = "zstd")]
+            (false, Compression::Zstd) => MediaType::OciFsTarZstd,
+
+            (_, compression) => return Err(RepackError::UnsupportedCompression(compression)),
+        };
+
+        Ok(media_type)
+    }
+
+    /// Add the manifest to the `index.json` file of the layout.
+    fn update_index(
+        &self,
+        layout: &Path,
+        media_type: &str,
+        digest: &Digest,
+        size: usize,
+    ) -> Result<(), RepackError> {
+        let index_path = layout.join("index.json");
+
+        let mut index: Value = match fs::read(&index_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+
+            Err(e) if e.kind() == io::ErrorKind::NotFound => json!({
+                "schemaVersion": 2,
+                "mediaType": MediaType::OciImageIndex.as_str(),
+                "manifests": [],
+            }),
+
+            Err(e) => return Err(RepackError::Io(e, index_path)),
+        };
+
+        let mut entry = json!({
+            "mediaType": media_type,
+            "digest": digest.source(),
+            "size": size,
+        });
+
+        if let Some(tag) = &self.tag {
+            entry["annotations"] = json!({ REF_NAME_ANNOTATION: tag });
+        }
+
+        let Some(Value::Array(manifests)) = index.get_mut("manifests") else {
+            return Err(RepackError::InvalidImage(
+                "invalid index.json in the layout",
+            ));
+        };
+
+        if let Some(tag) = &self.tag {
+            manifests.retain(|m| {
+                m.pointer(&format!(
+                    "/annotations/{}",
+                    REF_NAME_ANNOTATION.replace('/', "~1")
+                ))
+                .and_then(Value::as_str)
+                    != Some(tag)
+            });
+        }
+
+        manifests.push(entry);
+
+        let layout_file = layout.join("oci-layout");
+        fs::write(&layout_file, r#"{"imageLayoutVersion":"1.0.0"}"#)
+            .map_err(io_error(&layout_file))?;
+
+        fs::write(&index_path, serde_json::to_vec(&index)?).map_err(io_error(&index_path))
+    }
+}
+
+/// Read a file written by the unpacker.
+fn read_state(path: &Path) -> Result<Vec<u8>, RepackError> {
+    match fs::read(path) {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(RepackError::MissingState(path.into()))
+        }
+        Err(e) => Err(RepackError::Io(e, path.into())),
+    }
+}
+
+/// Name of the mtree manifest for the image with `digest`.
+fn mtree_file_name(digest: &Digest) -> String {
+    format!("{}.mtree", digest.source().replace(':', "_"))
+}
+
+/// Write a blob in the `blobs/sha256` directory of the layout.
+fn write_blob(blobs_dir: &Path, data: &[u8]) -> Result<Digest, RepackError> {
+    let digest = Digest::sha256(data);
+    let path = blobs_dir.join(digest.hash_value());
+    fs::write(&path, data).map_err(io_error(path))?;
+    Ok(digest)
+}
+
+/// Add the blob of a layer in the original image to the layout.
+///
+/// Foreign layers that are not in the target directory are ignored,
+/// since they are downloaded from their URLs.
+fn add_layer(target: &Path, layout: &Path, layer: &Value) -> Result<(), RepackError> {
+    let digest = layer
+        .get("digest")
+        .and_then(Value::as_str)
+        .and_then(|d| Digest::try_from(d.to_owned()).ok())
+        .ok_or(RepackError::InvalidImage(
+            "invalid layer digest in the manifest",
+        ))?;
+
+    let source = target.join(blob_path(&digest));
+
+    if !source.exists() {
+        let is_foreign = layer
+            .get("mediaType")
+            .and_then(Value::as_str)
+            .and_then(|m| m.parse::<MediaType>().ok())
+            .is_some_and(|m| m.is_foreign_layer());
+
+        return match is_foreign {
+            true => Ok(()),
+            false => Err(RepackError::MissingState(source)),
+        };
+    }
+
+    link_or_copy(&source, &layout.join(blob_path(&digest)))
+}
+
+/// Create a hard link to the blob in `source`, or copy it if the link
+/// can't be created. Nothing is done if `destination` already exists.
+fn link_or_copy(source: &Path, destination: &Path) -> Result<(), RepackError> {
+    let dir = destination.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).map_err(io_error(dir))?;
+
+    match fs::hard_link(source, destination) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(_) => (),
+    }
+
+    let file = TempFile::new(dir);
+    fs::copy(source, &file.0).map_err(io_error(source))?;
+    file.persist(destination)
+}
+
+/// Temporary file in the layout. It is removed if it is not renamed
+/// to its final path.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Return a unique path in `dir`, so multiple processes can write
+    /// to the same layout.
+    fn new(dir: &Path) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        TempFile(dir.join(format!(".repack.{}.{n}.tmp", std::process::id())))
+    }
+
+    /// Move the file to `path`.
+    fn persist(self, path: &Path) -> Result<(), RepackError> {
+        fs::rename(&self.0, path).map_err(io_error(path))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// File found in the root filesystem.
+struct CurrentFile {
+    entry: Entry,
+
+    /// Device and inode, to detect hard links.
+    inode: (u64, u64),
+    nlink: u64,
+}
+
+/// Source of the owners of the files in the root filesystem.
+///
+/// Owners are only read from the filesystem when the process runs as
+/// root. Otherwise, the unpacker could not change them, so the original
+/// owners are kept.
+struct Owners<'a> {
+    from_disk: bool,
+    uid_map: &'a [IdMapping],
+    gid_map: &'a [IdMapping],
+}
+
+/// ID for the files owned by IDs out of the mappings.
+const OVERFLOW_ID: u64 = 65534;
+
+/// Read the metadata of the files in `rootfs`.
+///
+/// The digest of a regular file in the original state is reused if its
+/// size and mtime are the same, and its ctime is older than `mtree_time`
+/// (the time when the original state was written). Otherwise, its
+/// contents are hashed.
+fn scan_directory(
+    rootfs: &Path,
+    directory: &Path,
+    original: &Mtree,
+    owners: &Owners,
+    mtree_time: (i64, i64),
+    files: &mut BTreeMap<PathBuf, CurrentFile>,
+) -> Result<(), RepackError> {
+    let disk_path = rootfs.join(directory.strip_prefix("/").unwrap_or(directory));
+
+    let mut children = Vec::new();
+    for dirent in fs::read_dir(&disk_path).map_err(io_error(&disk_path))? {
+        children.push(dirent.map_err(io_error(&disk_path))?);
+    }
+
+    for dirent in children {
+        let path = directory.join(dirent.file_name());
+        let disk_path = dirent.path();
+
+        let metadata = fs::symlink_metadata(&disk_path).map_err(io_error(&disk_path))?;
+        let file_type = metadata.file_type();
+
+        let kind = if file_type.is_dir() {
+            EntryType::Dir
+        } else if file_type.is_file() {
+            EntryType::File
+        } else if file_type.is_symlink() {
+            EntryType::Link
+        } else if file_type.is_char_device() {
+            EntryType::Char
+        } else if file_type.is_block_device() {
+            EntryType::Block
+        } else if file_type.is_fifo() {
+            EntryType::Fifo
+        } else {
+            // Other file types are not supported.
+            continue;
+        };
+
+        // An empty file can be the placeholder of a device node that
+        // could not be created.
+        if let Some(node) = original.get(&path) {
+            if node.optional && kind == EntryType::File && metadata.len() == 0 {
+                let file = CurrentFile {
+                    entry: node.clone(),
+                    inode: (metadata.dev(), metadata.ino()),
+                    nlink: metadata.nlink(),
+                };
+
+                files.insert(path, file);
+                continue;
+            }
+        }
+
+        let previous = original.get(&path).filter(|e| e.kind == kind);
+
+        let mut entry = Entry {
+            kind,
+            mode: metadata.mode() & 0o7777,
+            uid: idmap::unmap_id(owners.uid_map, metadata.uid()).unwrap_or(OVERFLOW_ID),
+            gid: idmap::unmap_id(owners.gid_map, metadata.gid()).unwrap_or(OVERFLOW_ID),
+            size: 0,
+            mtime: (
+                metadata.mtime().try_into().unwrap_or_default(),
+                metadata.mtime_nsec().try_into().unwrap_or_default(),
+            ),
+            link: None,
+            sha256: None,
+            device: None,
+            optional: false,
+        };
+
+        if !owners.from_disk {
+            let (uid, gid) = original.get(&path).map_or((0, 0), |e| (e.uid, e.gid));
+            entry.uid = uid;
+            entry.gid = gid;
+        }
+
+        match kind {
+            EntryType::File => {
+                entry.size = metadata.len();
+                let unchanged = (metadata.ctime(), metadata.ctime_nsec()) < mtree_time;
+
+                entry.sha256 = match previous {
+                    Some(p)
+                        if unchanged
+                            && p.size == entry.size
+                            && p.mtime == entry.mtime
+                            && p.sha256.is_some() =>
+                    {
+                        p.sha256
+                    }
+
+                    _ => {
+                        let mut writer = HashingWriter::new(io::sink());
+                        File::open(&disk_path)
+                            .and_then(|mut file| io::copy(&mut file, &mut writer))
+                            .map_err(io_error(&disk_path))?;
+
+                        Some(writer.digest())
+                    }
+                }
+            }
+
+            EntryType::Link => {
+                // The mode of a symbolic link can't be changed in Linux.
+                entry.mode = previous.map_or(0o777, |p| p.mode);
+                entry.link = Some(fs::read_link(&disk_path).map_err(io_error(&disk_path))?);
+            }
+
+            EntryType::Char | EntryType::Block => {
+                let rdev = metadata.rdev();
+                entry.device = Some((rustix::fs::major(rdev), rustix::fs::minor(rdev)));
+            }
+
+            EntryType::Dir | EntryType::Fifo => (),
+        }
+
+        let file = CurrentFile {
+            entry,
+            inode: (metadata.dev(), metadata.ino()),
+            nlink: metadata.nlink(),
+        };
+
+        files.insert(path.clone(), file);
+
+        if kind == EntryType::Dir {
+            scan_directory(rootfs, &path, original, owners, mtree_time, files)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Differences between the original state and the current files.
+struct Changes<'a> {
+    /// Paths to delete, as whiteout files in the layer.
+    whiteouts: Vec<PathBuf>,
+
+    /// Files added or modified.
+    modified: Vec<&'a Path>,
+}
+
+impl<'a> Changes<'a> {
+    fn new(original: &Mtree, current: &'a BTreeMap<PathBuf, CurrentFile>) -> Self {
+        let is_dir = |path: &Path| {
+            let root = path == Path::new("/");
+            let original_dir = original.get(path).is_some_and(|e| e.kind == EntryType::Dir);
+            let current_dir = current
+                .get(path)
+                .is_some_and(|f| f.entry.kind == EntryType::Dir);
+            root || (original_dir && current_dir)
+        };
+
+        // Count how many of the original files in each directory are
+        // still present. If none of them are, the directory is opaque.
+        let mut children: HashMap<&Path, (usize, usize)> = HashMap::new();
+        for (path, entry) in original.entries() {
+            if let Some(parent) = path.parent() {
+                let counter = children.entry(parent).or_default();
+                counter.0 += 1;
+                counter.1 += (entry.optional || current.contains_key(path)) as usize;
+            }
+        }
+
+        let opaque: Vec<&Path> = children
+            .iter()
+            .filter(|(path, (_, present))| *present == 0 && is_dir(path))
+            .map(|(path, _)| *path)
+            .collect();
+
+        let mut whiteouts: Vec<_> = opaque.iter().map(|dir| dir.join(WHITEOUT_OPAQUE)).collect();
+
+        for (path, entry) in original.entries() {
+            let Some((parent, name)) = path.parent().zip(path.file_name()) else {
+                continue;
+            };
+
+            // Files in deleted directories are removed with the
+            // whiteout of the directory.
+            if !is_dir(parent) || opaque.contains(&parent) {
+                continue;
+            }
+
+            // A directory replaced by a non-directory, or the opposite,
+            // has to be deleted before adding the new file.
+            let deleted = match current.get(path) {
+                None => !entry.optional,
+                Some(file) => {
+                    file.entry.kind != entry.kind
+                        && (file.entry.kind == EntryType::Dir || entry.kind == EntryType::Dir)
+                }
+            };
+
+            if deleted {
+                let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+                whiteout.push(name);
+                whiteouts.push(parent.join(whiteout));
+            }
+        }
+
+        let modified = current
+            .iter()
+            .filter(|(path, file)| original.get(path) != Some(&file.entry))
+            .map(|(path, _)| path.as_path())
+            .collect();
+
+        whiteouts.sort();
+
+        Changes {
+            whiteouts,
+            modified,
+        }
+    }
+}
+
+/// Write the layer with the `changes` to `output`.
+///
+/// Returns the digest of the layer, and the digest of the uncompressed
+/// archive (its `diff_id`).
+fn write_layer(
+    rootfs: &Path,
+    current: &BTreeMap<PathBuf, CurrentFile>,
+    changes: &Changes,
+    compression: Compression,
+    output: File,
+) -> io::Result<([u8; 32], [u8; 32])> {
+    let blob_writer = HashingWriter::new(BufWriter::new(output));
+    let encoder = Encoder::new(compression, blob_writer)?;
+
+    let mut builder = tar::Builder::new(HashingWriter::new(encoder));
+
+    // Whiteouts are written before any other file, so they don't
+    // delete the files added by this layer.
+    for whiteout in &changes.whiteouts {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(0);
+        header.set_mtime(0);
+
+        builder.append_data(&mut header, relative(whiteout), io::empty())?;
+    }
+
+    // Paths of the hard links added to the archive.
+    let mut inodes = HashMap::new();
+
+    for &path in &changes.modified {
+        let file = &current[path];
+        let entry = &file.entry;
+        let name = relative(path);
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(entry.mode);
+        header.set_uid(entry.uid);
+        header.set_gid(entry.gid);
+        header.set_mtime(entry.mtime.0);
+        header.set_size(0);
+
+        // The header can only store seconds, so the full timestamp
+        // is written in a PAX record.
+        if entry.mtime.1 != 0 {
+            let mtime = format!("{}.{:09}", entry.mtime.0, entry.mtime.1);
+            builder.append_pax_extensions([("mtime", mtime.as_bytes())])?;
+        }
+
+        match entry.kind {
+            EntryType::Dir => {
+                header.set_entry_type(tar::EntryType::Directory);
+                builder.append_data(&mut header, name, io::empty())?;
+            }
+
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                let entry_type = match entry.kind {
+                    EntryType::Char => tar::EntryType::Char,
+                    EntryType::Block => tar::EntryType::Block,
+                    _ => tar::EntryType::Fifo,
+                };
+
+                let (major, minor) = entry.device.unwrap_or_default();
+                header.set_entry_type(entry_type);
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+                builder.append_data(&mut header, name, io::empty())?;
+            }
+
+            EntryType::Link => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                builder.append_link(
+                    &mut header,
+                    name,
+                    entry.link.as_deref().unwrap_or(Path::new("")),
+                )?;
+            }
+
+            EntryType::File => {
+                if file.nlink > 1 {
+                    if let Some(target) = inodes.get(&file.inode) {
+                        header.set_entry_type(tar::EntryType::Link);
+                        builder.append_link(&mut header, name, target)?;
+                        continue;
+                    }
+
+                    inodes.insert(file.inode, name);
+                }
+
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(entry.size);
+
+                let data = File::open(rootfs.join(name))?;
+                builder.append_data(&mut header, name, data.take(entry.size))?;
+            }
+        }
+    }
+
+    let (encoder, diff_hash) = builder.into_inner()?.into_inner();
+    let (output, layer_hash) = encoder.finish()?.into_inner();
+    output.into_inner()?.sync_all()?;
+
+    Ok((layer_hash, diff_hash))
+}
+
+/// Path relative to the root of the archive.
+fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+/// Compress the data of a layer.
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(compression: Compression, output: W) -> io::Result<Self> {
+        let encoder = match compression {
+            Compression::Gzip => {
+                Encoder::Gzip(flate2::write::GzEncoder::new(output, Default::default()))
+            }
+
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(output, 0)?),
+
+            _ => Encoder::None(output),
+        };
+
+        Ok(encoder)
+    }
+
+    /// Write any pending data, and return the inner writer.
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(output) => Ok(output),
+            Encoder::Gzip(encoder) => encoder.finish(),
+
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(output) => output.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(output) => output.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Format a timestamp as RFC 3339, in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    // Convert days to a civil date. See
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let doe = days % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    let time = secs % 86400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[test]
+fn format_timestamps() {
+    use std::time::Duration;
+
+    let format = |secs| format_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+
+    assert_eq!(format(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format(951782400), "2000-02-29T00:00:00Z");
+    assert_eq!(format(1700000000), "2023-11-14T22:13:20Z");
+    assert_eq!(format(4102444799), "2099-12-31T23:59:59Z");
+}
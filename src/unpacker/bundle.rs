@@ -0,0 +1,364 @@
+//! Generate the configuration of an [OCI runtime bundle][bundle] from the
+//! image configuration.
+//!
+//! The conversion follows the rules in the [image specification][conversion].
+//! Namespaces, mounts, and capabilities are the same defaults used by
+//! `runc spec`.
+//!
+//! [bundle]: https://github.com/opencontainers/runtime-spec/blob/main/bundle.md
+//! [conversion]: https://github.com/opencontainers/image-spec/blob/main/conversion.md
+
+use std::{fs::File, io::Read};
+
+use rustix::fs::FileType;
+use serde_json::{json, Map, Value};
+
+use crate::{config::ImageConfig, fs::Directory};
+
+use super::{try_io, UnpackError};
+
+/// Version of the runtime specification for the generated file.
+const OCI_VERSION: &str = "1.0.2";
+
+/// `PATH` variable if the image does not define one.
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Command to execute if the image has no `Entrypoint` or `Cmd`.
+const DEFAULT_COMMAND: &str = "sh";
+
+/// Capabilities for the process.
+const CAPABILITIES: &[&str] = &["CAP_AUDIT_WRITE", "CAP_KILL", "CAP_NET_BIND_SERVICE"];
+
+/// Maximum size of `/etc/passwd` and `/etc/group`.
+const MAX_DATABASE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Paths in the container that are masked.
+const MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/sys/firmware",
+    "/proc/scsi",
+];
+
+/// Paths in the container that are read-only.
+const READONLY_PATHS: &[&str] = &[
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+/// Build the `config.json` file for a runtime bundle.
+///
+/// `rootfs` is the directory where the image was unpacked. It is used to
+/// resolve user and group names in `/etc/passwd` and `/etc/group`.
+pub(super) fn runtime_config(
+    config: &ImageConfig,
+    rootfs: &Directory,
+) -> Result<Value, UnpackError> {
+    let container = &config.config;
+
+    let mut args: Vec<String> = container
+        .entrypoint
+        .iter()
+        .chain(&container.cmd)
+        .flatten()
+        .cloned()
+        .collect();
+
+    if args.is_empty() {
+        args.push(DEFAULT_COMMAND.to_owned());
+    }
+
+    let mut env = container.env.clone();
+    if !env.iter().any(|e| e.starts_with("PATH=")) {
+        env.insert(0, DEFAULT_PATH.to_owned());
+    }
+
+    let cwd = match container.working_dir.as_deref() {
+        Some(dir) if dir.starts_with('/') => dir.to_owned(),
+        Some(dir) if !dir.is_empty() => format!("/{dir}"),
+        _ => "/".to_owned(),
+    };
+
+    let user = resolve_user(container.user.as_deref().unwrap_or_default(), rootfs)?;
+
+    let mut process_user = json!({ "uid": user.uid, "gid": user.gid });
+    if !user.additional_gids.is_empty() {
+        process_user["additionalGids"] = user.additional_gids.into();
+    }
+
+    let namespaces: Vec<_> = ["pid", "network", "ipc", "uts", "mount", "cgroup"]
+        .iter()
+        .map(|ns| json!({ "type": ns }))
+        .collect();
+
+    Ok(json!({
+        "ociVersion": OCI_VERSION,
+        "process": {
+            "terminal": false,
+            "user": process_user,
+            "args": args,
+            "env": env,
+            "cwd": cwd,
+            "capabilities": {
+                "bounding": CAPABILITIES,
+                "effective": CAPABILITIES,
+                "permitted": CAPABILITIES,
+            },
+            "rlimits": [
+                { "type": "RLIMIT_NOFILE", "hard": 1024, "soft": 1024 },
+            ],
+            "noNewPrivileges": true,
+        },
+        "root": {
+            "path": super::images::ROOTFS_PATH,
+            "readonly": false,
+        },
+        "mounts": mounts(),
+        "annotations": annotations(config),
+        "linux": {
+            "namespaces": namespaces,
+            "maskedPaths": MASKED_PATHS,
+            "readonlyPaths": READONLY_PATHS,
+        },
+    }))
+}
+
+/// Default mounts for the container.
+fn mounts() -> Value {
+    json!([
+        {
+            "destination": "/proc",
+            "type": "proc",
+            "source": "proc",
+        },
+        {
+            "destination": "/dev",
+            "type": "tmpfs",
+            "source": "tmpfs",
+            "options": ["nosuid", "strictatime", "mode=755", "size=65536k"],
+        },
+        {
+            "destination": "/dev/pts",
+            "type": "devpts",
+            "source": "devpts",
+            "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"],
+        },
+        {
+            "destination": "/dev/shm",
+            "type": "tmpfs",
+            "source": "shm",
+            "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"],
+        },
+        {
+            "destination": "/dev/mqueue",
+            "type": "mqueue",
+            "source": "mqueue",
+            "options": ["nosuid", "noexec", "nodev"],
+        },
+        {
+            "destination": "/sys",
+            "type": "sysfs",
+            "source": "sysfs",
+            "options": ["nosuid", "noexec", "nodev", "ro"],
+        },
+        {
+            "destination": "/sys/fs/cgroup",
+            "type": "cgroup",
+            "source": "cgroup",
+            "options": ["nosuid", "noexec", "nodev", "relatime", "ro"],
+        },
+    ])
+}
+
+/// Annotations for the container, from the labels and the properties
+/// of the image.
+fn annotations(config: &ImageConfig) -> Map<String, Value> {
+    let mut annotations: Map<_, _> = config
+        .config
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone().into()))
+        .collect();
+
+    let mut add = |key: &str, value: Option<&str>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            annotations.insert(format!("org.opencontainers.image.{key}"), value.into());
+        }
+    };
+
+    let exposed_ports = Vec::from_iter(config.config.exposed_ports.iter().map(String::as_str));
+
+    add("os", Some(&config.os));
+    add("architecture", Some(&config.architecture));
+    add("variant", config.variant.as_deref());
+    add("author", config.author.as_deref());
+    add("created", config.created.as_deref());
+    add("stopSignal", config.config.stop_signal.as_deref());
+    add("exposedPorts", Some(&exposed_ports.join(",")));
+
+    annotations
+}
+
+/// User and groups for the process.
+#[derive(Debug, PartialEq)]
+struct User {
+    uid: u32,
+    gid: u32,
+    additional_gids: Vec<u32>,
+}
+
+/// Resolve the `User` field of the image configuration.
+///
+/// It can be `user`, `uid`, `user:group`, `uid:gid`, `user:gid`, or
+/// `uid:group`. Names are resolved with the files in the image.
+fn resolve_user(spec: &str, rootfs: &Directory) -> Result<User, UnpackError> {
+    if spec.is_empty() {
+        return Ok(User {
+            uid: 0,
+            gid: 0,
+            additional_gids: Vec::new(),
+        });
+    }
+
+    let passwd = read_database(rootfs, "etc/passwd")?;
+    let group = read_database(rootfs, "etc/group")?;
+
+    resolve_user_from(spec, &passwd, &group)
+}
+
+fn resolve_user_from(spec: &str, passwd: &str, group: &str) -> Result<User, UnpackError> {
+    let (user, group_spec) = match spec.split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (spec, None),
+    };
+
+    // Entry in /etc/passwd for the user, if any.
+    let entry = entries(passwd).find(|e| match user.parse::<u32>() {
+        Ok(uid) => e.get(2).and_then(|id| id.parse().ok()) == Some(uid),
+        Err(_) => e[0] == user,
+    });
+
+    let uid = match (user.parse(), &entry) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(e)) => parse_id(e.get(2), spec)?,
+        (Err(_), None) => return Err(UnpackError::InvalidUser(spec.to_owned())),
+    };
+
+    let gid = match group_spec {
+        Some(g) => match g.parse() {
+            Ok(gid) => gid,
+            Err(_) => match entries(group).find(|e| e[0] == g) {
+                Some(e) => parse_id(e.get(2), spec)?,
+                None => return Err(UnpackError::InvalidUser(spec.to_owned())),
+            },
+        },
+
+        None => match &entry {
+            Some(e) => parse_id(e.get(3), spec)?,
+            None => 0,
+        },
+    };
+
+    // Supplementary groups are only added when the group is not
+    // explicitly set, like in `docker run`.
+    let mut additional_gids = Vec::new();
+    if let (None, Some(entry)) = (group_spec, &entry) {
+        for g in entries(group) {
+            let is_member = g
+                .get(3)
+                .is_some_and(|members| members.split(',').any(|m| m == entry[0]));
+
+            if is_member {
+                let id = parse_id(g.get(2), spec)?;
+                if id != gid && !additional_gids.contains(&id) {
+                    additional_gids.push(id);
+                }
+            }
+        }
+    }
+
+    Ok(User {
+        uid,
+        gid,
+        additional_gids,
+    })
+}
+
+/// Read a file like `/etc/passwd` in the image. Returns an empty
+/// string if the file does not exist, or if it is not a regular file.
+fn read_database(rootfs: &Directory, path: &str) -> Result<String, UnpackError> {
+    let fd = match rootfs.open_file(path) {
+        Ok(fd) => fd,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(String::new()),
+        Err(e) => return Err(UnpackError::Io(e.into(), path.into())),
+    };
+
+    let stat = try_io!(path, rustix::fs::fstat(&fd));
+    if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile {
+        return Ok(String::new());
+    }
+
+    let mut data = String::new();
+    try_io!(
+        path,
+        File::from(fd)
+            .take(MAX_DATABASE_SIZE + 1)
+            .read_to_string(&mut data)
+    );
+
+    if data.len() as u64 > MAX_DATABASE_SIZE {
+        return Err(UnpackError::DocumentTooLarge(path.into()));
+    }
+
+    Ok(data)
+}
+
+/// Parse the lines of a `/etc/passwd` or `/etc/group` file.
+fn entries(data: &str) -> impl Iterator<Item = Vec<&str>> {
+    data.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').collect())
+}
+
+fn parse_id(field: Option<&&str>, spec: &str) -> Result<u32, UnpackError> {
+    field
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| UnpackError::InvalidUser(spec.to_owned()))
+}
+
+#[test]
+fn resolve_users() {
+    const PASSWD: &str = "\
+        root:x:0:0:root:/root:/bin/sh\n\
+        # comment\n\
+        www:x:33:33:www:/var/www:/bin/false\n\
+        app:x:1000:1000::/home/app:/bin/sh\n";
+
+    const GROUP: &str = "\
+        root:x:0:\n\
+        www:x:33:app\n\
+        app:x:1000:\n\
+        docker:x:999:app,www\n";
+
+    let user =
+        |spec| resolve_user_from(spec, PASSWD, GROUP).map(|u| (u.uid, u.gid, u.additional_gids));
+
+    assert_eq!(user("app").unwrap(), (1000, 1000, vec![33, 999]));
+    assert_eq!(user("1000").unwrap(), (1000, 1000, vec![33, 999]));
+    assert_eq!(user("app:www").unwrap(), (1000, 33, vec![]));
+    assert_eq!(user("www:10").unwrap(), (33, 10, vec![]));
+    assert_eq!(user("5000").unwrap(), (5000, 0, vec![]));
+    assert_eq!(user("5000:docker").unwrap(), (5000, 999, vec![]));
+
+    assert!(user("missing").is_err());
+    assert!(user("app:missing").is_err());
+}
@@ -0,0 +1,150 @@
+use std::{fmt, io::Read};
+
+use crate::MediaType;
+
+use super::UnpackError;
+
+/// Number of bytes needed to detect the compression of a layer.
+///
+/// Plain tar archives are detected by the `ustar` magic, which is at
+/// offset 257 of the first header.
+pub(super) const HEADER_SIZE: usize = 512;
+
+/// Compression algorithm of a layer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Uncompressed tar archive.
+    None,
+
+    /// Tar archive compressed with gzip.
+    Gzip,
+
+    /// Only supported if the crate is built with the `zstd` feature,
+    /// which is enabled by default.
+    Zstd,
+
+    /// Only supported if the crate is built with the `xz` feature.
+    Xz,
+
+    /// Only supported if the crate is built with the `bzip2` feature.
+    Bzip2,
+}
+
+impl Compression {
+    /// Return the compression declared by the media type of a layer,
+    /// or `None` if it is not a layer.
+    pub(super) fn from_media_type(media_type: MediaType) -> Option<Compression> {
+        match media_type {
+            MediaType::OciFsTar | MediaType::OciFsTarNondistributable => Some(Compression::None),
+
+            MediaType::DockerFsTarGzip
+            | MediaType::DockerForeignFsTarGzip
+            | MediaType::OciFsTarGzip
+            | MediaType::OciFsTarGzipNondistributable => Some(Compression::Gzip),
+
+            MediaType::OciFsTarZstd | MediaType::OciFsTarZstdNondistributable => {
+                Some(Compression::Zstd)
+            }
+
+            _ => None,
+        }
+    }
+
+    /// Detect the compression from the first bytes of a layer.
+    ///
+    /// Returns `None` if there is no known signature in `header`.
+    pub(super) fn detect(header: &[u8]) -> Option<Compression> {
+        // The magic of the tar header is checked first, because the
+        // name of the first entry could start with any of the other
+        // signatures.
+        if header.get(257..262) == Some(b"ustar") {
+            return Some(Compression::None);
+        }
+
+        if header.starts_with(&[0x1F, 0x8B]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if header.starts_with(b"BZh") && matches!(header.get(3), Some(b'1'..=b'9')) {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    /// Return the name of the compression algorithm.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+
+    /// Return a reader to uncompress the data from `reader`.
+    #[cfg_attr(not(feature = "zstd"), expect(unused_variables))]
+    pub(super) fn decoder<'a, R: Read + 'a>(
+        self,
+        blob_id: &str,
+        reader: R,
+    ) -> Result<Box<dyn Read + 'a>, UnpackError> {
+        let reader: Box<dyn Read> = match self {
+            Compression::None => Box::new(std::io::BufReader::new(reader)),
+
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(reader)
+                    .map_err(|e| UnpackError::Io(e, format!("blob:{blob_id}").into()))?,
+            ),
+
+            #[cfg(feature = "xz")]
+            Compression::Xz => Box::new(liblzma::read::XzDecoder::new(reader)),
+
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+
+            #[allow(unreachable_patterns)]
+            unsupported => return Err(UnpackError::UnsupportedCompression(unsupported)),
+        };
+
+        Ok(reader)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[test]
+fn detect_compression() {
+    let mut tar = [0; HEADER_SIZE];
+    tar[..4].copy_from_slice(b"BZh9");
+    tar[257..263].copy_from_slice(b"ustar\0");
+
+    assert_eq!(Compression::detect(&tar), Some(Compression::None));
+    assert_eq!(Compression::detect(&tar[..257]), Some(Compression::Bzip2));
+
+    assert_eq!(
+        Compression::detect(&[0x1F, 0x8B, 8, 0]),
+        Some(Compression::Gzip)
+    );
+
+    assert_eq!(
+        Compression::detect(&[0x28, 0xB5, 0x2F, 0xFD, 0]),
+        Some(Compression::Zstd)
+    );
+
+    assert_eq!(Compression::detect(b"\xFD7zXZ\0\0"), Some(Compression::Xz));
+
+    assert_eq!(Compression::detect(b"BZh0"), None);
+    assert_eq!(Compression::detect(b""), None);
+}
@@ -0,0 +1,173 @@
+//! Share the contents of regular files between unpacked images.
+//!
+//! See [`Unpacker::content_store`](super::Unpacker::content_store).
+
+use std::{fs::File, io, path::Path};
+
+use rustix::{
+    fd::{AsFd, BorrowedFd},
+    fs::{self, AtFlags, Mode, OFlags, RenameFlags, ResolveFlags, Timespec},
+    io::Errno,
+};
+
+use crate::{digest::HexString, fs::Directory};
+
+/// Directory with a file for every unpacked content, named after its
+/// SHA256 digest.
+pub(super) struct ContentStore {
+    dir: Directory,
+    hardlinks: bool,
+}
+
+/// Metadata that a file in the store must have to be hard-linked.
+pub(super) struct LinkMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: Timespec,
+}
+
+impl ContentStore {
+    pub fn open(path: &Path, hardlinks: bool) -> io::Result<Self> {
+        Ok(ContentStore {
+            dir: Directory::new(path)?,
+            hardlinks,
+        })
+    }
+
+    /// Return `true` if files can be hard-linked to the store.
+    pub fn hardlinks(&self) -> bool {
+        self.hardlinks
+    }
+
+    /// Replace the file `file_name` with a hard link to the entry for
+    /// `sha256`, if it exists and its metadata is `metadata`.
+    ///
+    /// Returns `true` if the file was replaced.
+    pub fn link_to(
+        &self,
+        sha256: &[u8; 32],
+        metadata: &LinkMetadata,
+        parent_fd: BorrowedFd,
+        file_name: &Path,
+    ) -> io::Result<bool> {
+        let name = entry_name(sha256);
+
+        let stat = match fs::statat(&self.dir, &name, AtFlags::SYMLINK_NOFOLLOW) {
+            Ok(stat) => stat,
+            Err(Errno::NOENT) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        // `st_mtime` is signed in some architectures.
+        #[allow(clippy::useless_conversion)]
+        let same_metadata = stat.st_mode & 0o7777 == metadata.mode
+            && stat.st_uid == metadata.uid
+            && stat.st_gid == metadata.gid
+            && i64::try_from(stat.st_mtime).ok() == Some(metadata.mtime.tv_sec)
+            && i64::try_from(stat.st_mtime_nsec).ok() == Some(metadata.mtime.tv_nsec.into());
+
+        if !same_metadata {
+            return Ok(false);
+        }
+
+        fs::unlinkat(parent_fd, file_name, AtFlags::empty())?;
+        fs::linkat(&self.dir, &name, parent_fd, file_name, AtFlags::empty())?;
+
+        Ok(true)
+    }
+
+    /// Replace the contents of `file` with a clone of the entry for
+    /// `sha256`, so both files share the same extents.
+    ///
+    /// Returns `false` if there is no entry for `sha256`, or if the
+    /// filesystem does not support cloning files.
+    pub fn clone_to(&self, sha256: &[u8; 32], file: &File) -> io::Result<bool> {
+        let source = fs::openat2(
+            &self.dir,
+            entry_name(sha256),
+            OFlags::RDONLY | OFlags::NOFOLLOW,
+            Mode::empty(),
+            ResolveFlags::BENEATH,
+        );
+
+        let source = match source {
+            Ok(fd) => fd,
+            Err(Errno::NOENT | Errno::ACCESS) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        clone_file(file, source)
+    }
+
+    /// Add the contents of `file` to the store.
+    ///
+    /// With hard links, the file itself is added. Otherwise, the store
+    /// gets a clone of it. Nothing is added if the filesystem does not
+    /// support cloning files.
+    pub fn insert(
+        &self,
+        sha256: &[u8; 32],
+        file: &File,
+        parent_fd: BorrowedFd,
+        file_name: &Path,
+    ) -> io::Result<()> {
+        let name = entry_name(sha256);
+
+        if self.hardlinks {
+            return match fs::linkat(parent_fd, file_name, &self.dir, &name, AtFlags::empty()) {
+                Ok(_) | Err(Errno::EXIST) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        // The clone is written to a temporary file, so other processes
+        // never see an incomplete entry.
+        let tmp_name = format!(".{name}.{}", std::process::id());
+
+        let tmp = match self.dir.create(&tmp_name, Mode::RUSR) {
+            Ok(fd) => fd,
+            Err(Errno::EXIST) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let result = clone_file(tmp, file).and_then(|cloned| {
+            if cloned {
+                match fs::renameat_with(
+                    &self.dir,
+                    &tmp_name,
+                    &self.dir,
+                    &name,
+                    RenameFlags::NOREPLACE,
+                ) {
+                    Ok(_) => return Ok(()),
+                    Err(Errno::EXIST) => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            Ok(())
+        });
+
+        match fs::unlinkat(&self.dir, &tmp_name, AtFlags::empty()) {
+            Ok(_) | Err(Errno::NOENT) => result,
+            Err(e) => result.and(Err(e.into())),
+        }
+    }
+}
+
+/// Name of the entry for a SHA256 digest.
+fn entry_name(sha256: &[u8; 32]) -> String {
+    format!("sha256_{}", HexString(sha256))
+}
+
+/// Clone the contents of `source` to `target` with `FICLONE`.
+///
+/// Returns `false` if the filesystem does not support it.
+fn clone_file(target: impl AsFd, source: impl AsFd) -> io::Result<bool> {
+    match fs::ioctl_ficlone(target, source) {
+        Ok(_) => Ok(true),
+        Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::INVAL | Errno::NOTTY) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
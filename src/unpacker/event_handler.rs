@@ -1,5 +1,7 @@
 use std::{fmt::Display, path::Path};
 
+use crate::{Blob, Compression};
+
 /// Handler to receive notifications for events during the unpack process.
 ///
 /// All methods are optional.
@@ -13,6 +15,10 @@ pub trait EventHandler: Sync + 'static {
     /// [token]: https://distribution.github.io/distribution/spec/auth/token/
     fn registry_auth(&self, url: &str) {}
 
+    /// HTTP request to a URL outside the registry, to download a
+    /// foreign layer.
+    fn external_request(&self, url: &str) {}
+
     /// Start to download the blobs of the image.
     ///
     /// `layers` is the number of layers to download.
@@ -39,11 +45,26 @@ pub trait EventHandler: Sync + 'static {
     /// unpacked.
     fn layer_progress(&self, archive_position: usize) {}
 
+    /// The compression of a layer, detected from its first bytes, does
+    /// not match its media type. The layer is uncompressed with the
+    /// `detected` algorithm.
+    fn layer_compression_mismatch(&self, blob: &Blob, detected: Compression) {}
+
     /// An entry in the archive's layer is skipped.
     ///
     /// For example, if it is an invalid entry type, like a block device.
     fn layer_entry_skipped(&self, path: &Path, cause: &dyn Display) {}
 
+    /// An extended attribute of an entry could not be written.
+    ///
+    /// For example, if the process does not have the privileges to write
+    /// `security.*` attributes, or the filesystem does not support them.
+    fn xattr_skipped(&self, path: &Path, name: &str, cause: &dyn Display) {}
+
+    /// A foreign layer is not downloaded, because the policy is
+    /// [`ForeignLayerPolicy::Skip`](crate::ForeignLayerPolicy::Skip).
+    fn foreign_layer_skipped(&self, blob: &Blob) {}
+
     /// All layers have been unpacked.
     fn finished(&self) {}
 
@@ -1,9 +1,9 @@
 use std::{
     cmp::min,
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
-    io::{self, BufWriter, Read, Write},
-    path::Path,
+    io::{self, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Condvar, Mutex,
@@ -11,50 +11,215 @@ use std::{
     thread,
 };
 
-use rustix::fs::Mode;
+use rustix::{fd::AsFd, fs::Mode};
 
 use crate::{
+    config::ImageConfig,
     fs::{normalize_path, DirFdCache, Directory},
     manifests::{Blob, Manifest},
-    EventHandler,
+    Digest, EventHandler,
 };
 
-use super::{layers::unpack_layer, try_io, UnpackError};
+use super::{
+    layers::{unpack_layer, LayerSource},
+    try_io, DirectoryMetadata, Options, RootfsState, UnpackError,
+};
 
 /// Maximum number of threads to download blobs in parallel.
 const QUEUE_LIMIT: usize = 8;
 
 /// File to store the configuration.
-const CONFIG_PATH: &str = "config.json";
+///
+/// If a runtime bundle is generated, this file contains the runtime
+/// configuration, and the image configuration is in [`IMAGE_CONFIG_PATH`].
+pub(crate) const CONFIG_PATH: &str = "config.json";
+
+/// File to store the image configuration in a runtime bundle.
+pub(crate) const IMAGE_CONFIG_PATH: &str = "image-config.json";
+
+/// File to store the image manifest, if the mtree manifest is written.
+pub(crate) const MANIFEST_PATH: &str = "manifest.json";
 
 /// Directory to store layers.
-const ROOTFS_PATH: &str = "rootfs";
+pub(crate) const ROOTFS_PATH: &str = "rootfs";
+
+/// Directory to store the blobs of the layers, if the mtree manifest
+/// is written.
+pub(crate) const BLOBS_PATH: &str = "blobs";
+
+/// Layers shared by multiple images, so they are downloaded only once.
+///
+/// Each entry contains the file with the layer, and how many images
+/// still need it. The file is released when the last image that needs
+/// it is unpacked.
+pub(crate) struct BlobCache {
+    files: Mutex<HashMap<String, (Option<File>, usize)>>,
+}
+
+impl BlobCache {
+    /// Create a cache for the layers that appear in more than one
+    /// manifest.
+    pub fn new<'a>(manifests: impl Iterator<Item = &'a Manifest>) -> Self {
+        let mut files = HashMap::new();
+
+        for manifest in manifests {
+            for digest in Self::digests(manifest) {
+                files.entry(digest.to_owned()).or_insert((None, 0)).1 += 1;
+            }
+        }
+
+        files.retain(|_, (_, count)| *count > 1);
+
+        BlobCache {
+            files: Mutex::new(files),
+        }
+    }
+
+    /// Digests of the layers in `manifest`, without duplicates.
+    fn digests(manifest: &Manifest) -> HashSet<&str> {
+        manifest.layers.iter().map(|l| l.digest.source()).collect()
+    }
+
+    /// Return a previously downloaded file for `blob`.
+    fn take(&self, blob: &Blob) -> Option<io::Result<File>> {
+        let files = self.files.lock().unwrap();
+        let (file, _) = files.get(blob.digest.source())?;
+        Some(file.as_ref()?.try_clone())
+    }
+
+    /// Return `true` if `blob` is needed by other images, so its file
+    /// has to be kept.
+    fn is_shared(&self, blob: &Blob) -> bool {
+        let files = self.files.lock().unwrap();
+        matches!(files.get(blob.digest.source()), Some((None, count)) if *count > 1)
+    }
+
+    /// Keep a downloaded file if it is needed by other images.
+    ///
+    /// If the file was already inserted, the new one is discarded.
+    fn insert(&self, blob: &Blob, file: &File) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+
+        if let Some((slot @ None, count)) = files.get_mut(blob.digest.source()) {
+            if *count > 1 {
+                *slot = Some(file.try_clone()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release the layers of an image after it is unpacked.
+    ///
+    /// The file of a layer is closed when no other image needs it.
+    pub fn release(&self, manifest: &Manifest) {
+        let mut files = self.files.lock().unwrap();
+
+        for digest in Self::digests(manifest) {
+            if let Some((_, count)) = files.get_mut(digest) {
+                *count -= 1;
+                if *count == 0 {
+                    files.remove(digest);
+                }
+            }
+        }
+    }
+}
+
+/// Image to download and unpack.
+pub(crate) struct ImageData<'a> {
+    pub manifest: &'a Manifest,
+
+    /// Digest of the manifest.
+    pub digest: &'a Digest,
+
+    /// Parsed image configuration.
+    pub config: &'a ImageConfig,
+
+    /// Image configuration, as it was received from the registry.
+    pub raw_config: &'a [u8],
+}
 
 pub(crate) fn get<E: EventHandler>(
-    http_client: crate::http::Client<E>,
-    manifest: Manifest,
+    http_client: &crate::http::Client<E>,
+    image: &ImageData,
     target: &Path,
     event_handler: &E,
-) -> Result<(), UnpackError> {
+    options: &Options,
+    blob_cache: Option<&BlobCache>,
+) -> Result<Vec<PathBuf>, UnpackError> {
     let is_alive = AtomicBool::new(true);
 
     let target = try_io!(target, Directory::new(target));
 
+    let manifest = image.manifest;
+    let diff_ids = super::check_diff_ids(manifest, image.config)?;
+
+    let config_path = match options.runtime_bundle {
+        true => IMAGE_CONFIG_PATH,
+        false => CONFIG_PATH,
+    };
+
+    try_io!(config_path, {
+        let fd = target.create(config_path, Mode::RUSR | Mode::WUSR)?;
+        File::from(fd).write_all(image.raw_config)?;
+    });
+
+    let layer_store = match &options.layer_store {
+        Some(path) => Some((path, try_io!(path, Directory::new(path)))),
+        None => None,
+    };
+
+    // Directories of the layers in the store, from the lowest one.
+    let mut layer_dirs = Vec::new();
+
+    let mut download_tasks = Vec::with_capacity(manifest.layers.len());
+    for (index, layer) in manifest.layers.iter().enumerate() {
+        if !options.want_layer(layer) {
+            event_handler.foreign_layer_skipped(layer);
+            continue;
+        }
+
+        let diff_id = diff_ids.map(|d| &d[index]);
+
+        if let Some((path, store)) = &layer_store {
+            let name = layer_dir_name(layer, diff_id);
+            layer_dirs.push(path.join(&name));
+
+            // Layers in the store are reused.
+            if store.open_directory(&name, false).is_ok() {
+                continue;
+            }
+        }
+
+        download_tasks.push(Download::new(layer, diff_id));
+    }
+
+    // Reuse layers downloaded for a previous image.
+    let mut pending = VecDeque::new();
+    for task in &download_tasks {
+        match blob_cache.and_then(|c| c.take(task.blob)) {
+            Some(file) => task
+                .complete(file.map_err(|e| UnpackError::Io(e, task.blob.digest.source().into()))),
+            None => pending.push_back(task),
+        }
+    }
+
     event_handler.download_start(
-        manifest.layers.len(),
-        manifest.config.size + manifest.layers.iter().fold(0, |a, l| a + l.size),
+        download_tasks.len(),
+        pending.iter().fold(0, |a, t| a + t.blob.size),
     );
 
-    let download_tasks: Vec<_> = [(&manifest.config, Some(Path::new(CONFIG_PATH)))]
-        .into_iter()
-        .chain(manifest.layers.iter().map(|l| (l, None)))
-        .map(|(blob, filename)| Download::new(blob, filename))
-        .collect();
-
     // Download blobs in a thread pool.
-    let pending: VecDeque<_> = download_tasks.iter().collect();
     let pending = Mutex::new(pending);
 
+    // Only the first layer is streamed, and it is taken before the thread
+    // pool is started. Later layers are downloaded in parallel while the
+    // previous ones are extracted.
+    if let Some(task) = download_tasks.first() {
+        claim_stream(task, &pending, options, blob_cache);
+    }
+
     // Disable umask.
     let _umask_guard = UmaskGuard(rustix::process::umask(Mode::empty()));
 
@@ -62,38 +227,83 @@ pub(crate) fn get<E: EventHandler>(
         let alive_tracker = AliveTracker(&is_alive);
 
         // Launch a thread pool to download the blobs.
-        for _ in 0..min(QUEUE_LIMIT, download_tasks.len()) {
+        for _ in 0..min(QUEUE_LIMIT, pending.lock().unwrap().len()) {
             scope.spawn(|| {
                 while let Ok(Some(task)) = pending.lock().map(|mut q| q.pop_front()) {
-                    task.complete(run_download(
-                        &target,
-                        task,
-                        &http_client,
-                        event_handler,
-                        &is_alive,
-                    ));
+                    let mut result =
+                        run_download(&target, task, http_client, event_handler, &is_alive);
+
+                    if let (Some(cache), Ok(file)) = (blob_cache, &result) {
+                        if let Err(e) = cache.insert(task.blob, file) {
+                            result = Err(UnpackError::Io(e, task.blob.digest.source().into()));
+                        }
+                    }
+
+                    task.complete(result);
                 }
             });
         }
 
         // Get downloaded files and extract them.
 
+        if let Some((_, store)) = &layer_store {
+            for task in &download_tasks {
+                let source = layer_source(task, http_client, event_handler)?;
+                unpack_overlay_layer(event_handler, store, task, source, options)?;
+            }
+
+            drop(alive_tracker);
+            event_handler.finished();
+
+            layer_dirs.reverse();
+            return Ok(layer_dirs);
+        }
+
         let rootfs = Directory::from(try_io!(
             ROOTFS_PATH,
             target.open_directory(ROOTFS_PATH, true)
         ));
 
-        let mut dirs_mtimes = Default::default();
+        let mut state = RootfsState::new(options)?;
 
         for task in &download_tasks {
-            unpack_layer(
-                task.blob.digest.source(),
+            let source = layer_source(task, http_client, event_handler)?;
+            let streamed = matches!(source, LayerSource::Stream(_));
+
+            let blob_file = match &source {
+                LayerSource::File(file) if options.mtree => {
+                    Some(try_io!(task.blob.digest.source(), file.try_clone()))
+                }
+                _ => None,
+            };
+
+            let result = unpack_layer(
                 event_handler,
                 &rootfs,
                 task.blob,
-                task.get()?,
-                &mut dirs_mtimes,
-            )?;
+                task.diff_id,
+                source,
+                &mut state,
+                options,
+            );
+
+            if let Err(e) = result {
+                // Files from a streamed layer were written before its
+                // digest was verified, so the rootfs can't be trusted.
+                if streamed {
+                    drop(rootfs);
+                    let _ = crate::fs::remove_entry(target.as_fd(), Path::new(ROOTFS_PATH));
+                }
+
+                return Err(task.stream_error(e));
+            }
+
+            // The blob is needed to write a complete OCI layout when
+            // the image is repacked.
+            if let Some(file) = blob_file {
+                let path = blob_path(&task.blob.digest);
+                try_io!(&path, save_blob(&target, &path, file));
+            }
         }
 
         drop(alive_tracker);
@@ -102,46 +312,152 @@ pub(crate) fn get<E: EventHandler>(
         //
         // This can't be done before because extracting new files updates
         // the mtime of the parent directory.
+        apply_dirs_metadata(&rootfs, state.dirs_metadata, options)?;
+
+        if let Some(mut mtree) = state.mtree {
+            let path = format!("{}.mtree", image.digest.source().replace(':', "_"));
 
-        let mut dirs_cache = DirFdCache::new(&rootfs);
-        for ((_, path), entry) in dirs_mtimes {
-            let mut update = || -> io::Result<()> {
-                use rustix::fs;
+            try_io!(&path, {
+                let fd = target.create(&path, Mode::RUSR | Mode::WUSR)?;
+                mtree.write(&rootfs, BufWriter::new(File::from(fd)))?;
+            });
 
-                let (parent_path, file_name) = normalize_path(&path)?;
+            // The manifest is needed to repack the image.
+            if !manifest.raw.is_empty() {
+                try_io!(MANIFEST_PATH, {
+                    let fd = target.create(MANIFEST_PATH, Mode::RUSR | Mode::WUSR)?;
+                    File::from(fd).write_all(&manifest.raw)?;
+                });
+            }
+        }
 
-                let parent = dirs_cache.get(&parent_path, false)?;
+        if options.runtime_bundle {
+            let runtime_config = super::bundle::runtime_config(image.config, &rootfs)?;
+
+            try_io!(CONFIG_PATH, {
+                let fd = target.create(CONFIG_PATH, Mode::RUSR | Mode::WUSR)?;
+                let mut output = BufWriter::new(File::from(fd));
+                serde_json::to_writer_pretty(&mut output, &runtime_config)?;
+                output.flush()?;
+            });
+        }
 
-                let mtime = fs::Timespec {
-                    tv_sec: entry.mtime as i64,
-                    tv_nsec: 0,
-                };
+        event_handler.finished();
 
-                let times = fs::Timestamps {
-                    last_access: mtime,
-                    last_modification: mtime,
-                };
+        Ok(Vec::new())
+    })
+}
 
-                crate::fs::change_owner(parent, &file_name, entry.uid, entry.gid, false)?;
-                fs::chmodat(parent, &file_name, entry.mode, fs::AtFlags::empty())?;
-                fs::utimensat(parent, &file_name, &times, fs::AtFlags::SYMLINK_NOFOLLOW)?;
+/// Apply the metadata of the directories, and other entries with
+/// deferred metadata, in `root`.
+fn apply_dirs_metadata(
+    root: &Directory,
+    dirs_metadata: DirectoryMetadata,
+    options: &Options,
+) -> Result<(), UnpackError> {
+    let mut dirs_cache = DirFdCache::new(root);
+    for ((_, path), entry) in dirs_metadata {
+        let mut update = || -> io::Result<()> {
+            use rustix::fs;
+
+            let (parent_path, file_name) = normalize_path(&path)?;
+
+            let parent = dirs_cache.get(&parent_path, false)?;
+
+            crate::fs::change_owner(
+                parent,
+                &file_name,
+                entry.uid,
+                entry.gid,
+                false,
+                options.require_chown,
+            )?;
+            fs::chmodat(parent, &file_name, entry.mode, fs::AtFlags::empty())?;
+            fs::utimensat(
+                parent,
+                &file_name,
+                &entry.times,
+                fs::AtFlags::SYMLINK_NOFOLLOW,
+            )?;
 
-                Ok(())
-            };
+            Ok(())
+        };
 
-            // Ignore NotFound errors. Those may happen because whiteout entries
-            // removed directories created by lower layers.
-            if let Err(e) = update() {
-                if e.kind() != io::ErrorKind::NotFound {
-                    return Err(UnpackError::Io(e, path));
-                }
+        // Ignore NotFound errors. Those may happen because whiteout entries
+        // removed directories created by lower layers.
+        if let Err(e) = update() {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(UnpackError::Io(e, path));
             }
         }
+    }
 
-        event_handler.finished();
+    Ok(())
+}
 
-        Ok(())
-    })
+/// Name of the directory for a layer in the store of
+/// [`Unpacker::overlay_layers`](super::Unpacker::overlay_layers).
+fn layer_dir_name(blob: &Blob, diff_id: Option<&Digest>) -> String {
+    diff_id.unwrap_or(&blob.digest).source().replace(':', "_")
+}
+
+/// Unpack a layer in its own directory of the layer store.
+///
+/// The layer is extracted to a temporary directory, which is renamed
+/// when it is complete, so an incomplete layer is never reused.
+fn unpack_overlay_layer<E: EventHandler>(
+    event_handler: &E,
+    store: &Directory,
+    task: &Download,
+    source: LayerSource,
+    options: &Options,
+) -> Result<(), UnpackError> {
+    use rustix::fs::{renameat_with, RenameFlags};
+
+    let name = layer_dir_name(task.blob, task.diff_id);
+    let tmp_name = format!(".{name}.{}", std::process::id());
+
+    // Remove any leftover from a previous failure.
+    try_io!(
+        &tmp_name,
+        crate::fs::remove_entry(store.as_fd(), Path::new(&tmp_name))
+    );
+
+    let layer_dir = Directory::from(try_io!(&tmp_name, store.open_directory(&tmp_name, true)));
+
+    let mut state = RootfsState::new(options)?;
+    let result = unpack_layer(
+        event_handler,
+        &layer_dir,
+        task.blob,
+        task.diff_id,
+        source,
+        &mut state,
+        options,
+    )
+    .and_then(|_| apply_dirs_metadata(&layer_dir, state.dirs_metadata, options));
+
+    // Never leave an incomplete layer in the store.
+    if let Err(e) = result {
+        drop(layer_dir);
+        let _ = crate::fs::remove_entry(store.as_fd(), Path::new(&tmp_name));
+        return Err(task.stream_error(e));
+    }
+
+    match renameat_with(store, &tmp_name, store, &name, RenameFlags::NOREPLACE) {
+        Ok(_) => Ok(()),
+
+        // The layer was unpacked by another process.
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            try_io!(
+                &tmp_name,
+                crate::fs::remove_entry(store.as_fd(), Path::new(&tmp_name))
+            );
+            Ok(())
+        }
+
+        Err(e) => Err(UnpackError::Io(e.into(), name.into())),
+    }
 }
 
 /// Store the previous value for umask, to restore it on drop.
@@ -165,18 +481,38 @@ impl Drop for AliveTracker<'_> {
 
 struct Download<'a> {
     blob: &'a Blob,
-    filename: Option<&'a Path>,
+    diff_id: Option<&'a Digest>,
     result: Mutex<Option<Result<File, UnpackError>>>,
     notifier: Condvar,
+
+    /// The blob is streamed to the extractor, instead of being
+    /// downloaded by the thread pool.
+    streamed: AtomicBool,
+
+    /// A streamed blob is larger or smaller than its descriptor.
+    invalid_size: AtomicBool,
 }
 
 impl<'a> Download<'a> {
-    fn new(blob: &'a Blob, filename: Option<&'a Path>) -> Self {
+    fn new(blob: &'a Blob, diff_id: Option<&'a Digest>) -> Self {
         Self {
             blob,
-            filename,
+            diff_id,
             result: Default::default(),
             notifier: Condvar::new(),
+            streamed: AtomicBool::new(false),
+            invalid_size: AtomicBool::new(false),
+        }
+    }
+
+    /// Replace the I/O error caused by an invalid size of a streamed blob.
+    fn stream_error(&self, error: UnpackError) -> UnpackError {
+        match error {
+            UnpackError::Io(..) if self.invalid_size.load(Ordering::Relaxed) => {
+                UnpackError::InvalidBlobSize(self.blob.digest.clone())
+            }
+
+            e => e,
         }
     }
 
@@ -200,6 +536,112 @@ impl<'a> Download<'a> {
     }
 }
 
+/// Path of a blob in the target directory, relative to it.
+pub(crate) fn blob_path(digest: &Digest) -> String {
+    format!("{BLOBS_PATH}/{}", digest.source().replace(':', "/"))
+}
+
+/// Copy a downloaded blob to `path`. Nothing is copied if the blob
+/// is already there.
+fn save_blob(target: &Directory, path: &str, mut file: File) -> io::Result<()> {
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        target.open_directory(parent, true)?;
+    }
+
+    let output = match target.create(path, Mode::from_raw_mode(0o644)) {
+        Ok(fd) => fd,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    file.rewind()?;
+    io::copy(&mut file, &mut File::from(output))?;
+    Ok(())
+}
+
+/// Take `task` from the queue of the thread pool, so it is streamed to
+/// the extractor.
+fn claim_stream(
+    task: &Download,
+    pending: &Mutex<VecDeque<&Download>>,
+    options: &Options,
+    blob_cache: Option<&BlobCache>,
+) {
+    // Blobs needed by other images, or by the repacker, must be kept
+    // in a file.
+    if !options.stream_layers || options.mtree || blob_cache.is_some_and(|c| c.is_shared(task.blob))
+    {
+        return;
+    }
+
+    let mut pending = pending.lock().unwrap();
+    if let Some(index) = pending.iter().position(|t| std::ptr::eq(*t, task)) {
+        pending.remove(index);
+        task.streamed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Return the data of the layer for `task`.
+///
+/// If the layer is streamed, the request to download it is sent now.
+/// Otherwise, wait until the thread pool downloads it.
+fn layer_source<'a, E: EventHandler>(
+    task: &'a Download,
+    http_client: &crate::http::Client<E>,
+    event_handler: &'a E,
+) -> Result<LayerSource<'a>, UnpackError> {
+    if !task.streamed.load(Ordering::Relaxed) {
+        return task.get().map(LayerSource::File);
+    }
+
+    let reader = StreamReader {
+        input: open_blob(task.blob, http_client)?,
+        task,
+        event_handler,
+        received: 0,
+    };
+
+    Ok(LayerSource::Stream(Box::new(reader)))
+}
+
+/// Reader for a blob streamed from the HTTP server.
+///
+/// Like [`run_download`], it sends progress notifications, and checks
+/// the size of the blob.
+struct StreamReader<'a, E> {
+    input: Box<dyn Read>,
+    task: &'a Download<'a>,
+    event_handler: &'a E,
+    received: usize,
+}
+
+impl<E: EventHandler> Read for StreamReader<'_, E> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+
+        self.received += n;
+
+        let expected_size = self.task.blob.expected_size();
+        let valid_size = match (n, expected_size) {
+            (_, None) => true,
+            (0, Some(size)) => buf.is_empty() || size == self.received,
+            (_, Some(size)) => size >= self.received,
+        };
+
+        if !valid_size {
+            self.task.invalid_size.store(true, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid blob size",
+            ));
+        }
+
+        self.event_handler.download_progress_bytes(n);
+
+        Ok(n)
+    }
+}
+
 /// Download a blob from the HTTP server. Return the file where
 /// its contents are written.
 fn run_download<E: EventHandler>(
@@ -211,21 +653,17 @@ fn run_download<E: EventHandler>(
 ) -> Result<File, UnpackError> {
     let digest = &task.blob.digest;
 
-    let mut input = http_client.download_blob(digest)?;
+    let mut input = open_blob(task.blob, http_client)?;
 
-    let fd = try_io!(
-        digest.source(),
-        match task.filename {
-            Some(n) => target.create(n, Mode::RUSR | Mode::WUSR),
-            None => target.tmpfile(),
-        },
-    );
-
-    let mut file = File::from(fd);
+    let mut file = File::from(try_io!(digest.source(), target.tmpfile()));
 
     let mut data = [0u8; 8 * 1024];
     let mut output = BufWriter::new(&mut file);
 
+    // Abort the download if the blob is larger than its descriptor.
+    let expected_size = task.blob.expected_size();
+    let mut received = 0;
+
     loop {
         if !is_alive.load(Ordering::Relaxed) {
             return Err(UnpackError::Interrupted);
@@ -234,12 +672,102 @@ fn run_download<E: EventHandler>(
         let n = try_io!(digest.source(), input.read(&mut data[..]));
 
         if n == 0 {
+            if expected_size.is_some_and(|s| s != received) {
+                return Err(UnpackError::InvalidBlobSize(digest.clone()));
+            }
+
             drop(output);
             return Ok(file);
         }
 
+        received += n;
+        if expected_size.is_some_and(|s| s < received) {
+            return Err(UnpackError::InvalidBlobSize(digest.clone()));
+        }
+
         event_handler.download_progress_bytes(n);
 
         try_io!(digest.source(), output.write_all(&data[..n]));
     }
 }
+
+/// Send the request to download a blob.
+///
+/// Foreign layers are downloaded from the URLs in their descriptors.
+/// If none of them works, the blob is requested to the registry, and,
+/// if it fails too, the error from the first URL is returned.
+fn open_blob<E: EventHandler>(
+    blob: &Blob,
+    http_client: &crate::http::Client<E>,
+) -> Result<Box<dyn Read>, UnpackError> {
+    let mut first_error = None;
+
+    if blob.media_type.is_foreign_layer() {
+        for url in &blob.urls {
+            match http_client.download_url(url, &blob.digest) {
+                Ok(input) => return Ok(Box::new(input)),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+    }
+
+    match http_client.download_blob(&blob.digest) {
+        Ok(input) => Ok(Box::new(input)),
+        Err(e) => Err(first_error.unwrap_or(e).into()),
+    }
+}
+
+#[test]
+fn release_shared_layers() {
+    let manifest = |layers: &[&str]| -> Manifest {
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|l| {
+                serde_json::json!({
+                    "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                    "digest": format!("sha256:{}", l.repeat(64)),
+                    "size": 1,
+                })
+            })
+            .collect();
+
+        serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": format!("sha256:{}", "0".repeat(64)),
+                "size": 1,
+            },
+            "layers": layers,
+        }))
+        .unwrap()
+    };
+
+    // The layer `a` is repeated in the first image.
+    let first = manifest(&["a", "b", "a"]);
+    let second = manifest(&["a", "c"]);
+
+    let cache = BlobCache::new([&first, &second].into_iter());
+    assert_eq!(cache.files.lock().unwrap().len(), 1);
+
+    let layer = &first.layers[0];
+    let file = tempfile::tempfile().unwrap();
+
+    assert!(cache.is_shared(layer));
+    assert!(cache.take(layer).is_none());
+
+    // Both downloads of `a` in the first image are inserted.
+    cache.insert(layer, &file).unwrap();
+    cache.insert(layer, &file).unwrap();
+    assert!(!cache.is_shared(layer));
+    cache.release(&first);
+
+    assert!(cache.take(&second.layers[0]).unwrap().is_ok());
+    assert!(cache.take(&second.layers[0]).unwrap().is_ok());
+    cache.release(&second);
+
+    assert!(cache.files.lock().unwrap().is_empty());
+}
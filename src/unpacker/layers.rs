@@ -1,47 +1,202 @@
 use std::io::{
-    self, BufReader,
+    self,
     ErrorKind::{AlreadyExists, NotFound},
     Read, Seek,
 };
 
 use std::{
+    borrow::Cow,
     cell::Cell,
+    collections::BTreeSet,
     ffi::OsStr,
     fs::File,
+    ops::Bound,
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    thread,
 };
 
 use rustix::{
     fd::{AsFd, BorrowedFd, OwnedFd},
-    fs::Mode,
+    fs::{Mode, Timespec, Timestamps},
 };
 
 use crate::{
-    fs::{normalize_path, DirFdCache, Directory},
+    digest::HashingReader,
+    fs::{normalize_path, DirFdCache, Directory, RemovedEntry},
+    idmap::{self, IdMapping},
     manifests::Blob,
-    EventHandler, MediaType,
+    mtree::{self, HashingWriter, Mtree},
+    Digest, EventHandler,
 };
 
-use super::{try_io, DirectoryMetadata, UnpackError};
+use super::{
+    compression::{Compression, HEADER_SIZE},
+    content_store::{ContentStore, LinkMetadata},
+    sparse::{self, PaxSparse, SparseMap},
+    try_io,
+    workers::{FileJob, FileWorkers, MAX_BUFFERED_FILE},
+    DeviceNodePolicy, DirectoryMetadata, Options, RootfsState, UnpackError,
+};
 
 const WHITEOUT_PREFIX: &[u8] = b".wh.";
 
 const WHITEOUT_OPAQUE: &[u8] = b".wh..opq";
 
+/// Prefix of the files used by AUFS to store its metadata, like
+/// `.wh..wh.plnk` for hard links.
+const AUFS_METADATA_PREFIX: &[u8] = b".wh..wh.";
+
+/// Whiteout entry in a layer.
+#[derive(Copy, Clone)]
+enum Whiteout<'a> {
+    /// Remove a file from the lower layers. It is either a `.wh.<name>`
+    /// file, or a `0/0` character device, as in overlayfs.
+    Entry(&'a OsStr),
+
+    /// Remove the contents of the directory from the lower layers.
+    Opaque,
+}
+
+impl<'a> Whiteout<'a> {
+    fn from_entry(file_name: &'a Path, header: &tar::Header) -> Option<Self> {
+        let name = file_name.as_os_str();
+
+        match name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+            Some(WHITEOUT_OPAQUE) => Some(Whiteout::Opaque),
+            Some(whiteout) => Some(Whiteout::Entry(OsStr::from_bytes(whiteout))),
+            None if is_overlay_whiteout(header) => Some(Whiteout::Entry(name)),
+            None => None,
+        }
+    }
+}
+
+/// Check if the entry is a character device with number `0/0`.
+fn is_overlay_whiteout(header: &tar::Header) -> bool {
+    header.entry_type() == tar::EntryType::Char
+        && matches!(header.device_major(), Ok(Some(0)))
+        && matches!(header.device_minor(), Ok(Some(0)))
+}
+
+/// Check if any component of the path is AUFS metadata. The opaque
+/// marker (`.wh..wh..opq`) is not metadata.
+fn is_aufs_metadata(parent_path: &Path, file_name: &Path) -> bool {
+    let is_metadata = |name: &OsStr| {
+        let name = name.as_bytes();
+        name.starts_with(AUFS_METADATA_PREFIX)
+            && name.strip_prefix(WHITEOUT_PREFIX) != Some(WHITEOUT_OPAQUE)
+    };
+
+    parent_path.iter().any(is_metadata) || is_metadata(file_name.as_os_str())
+}
+
+/// Xattrs to mark opaque directories in overlayfs.
+const OVERLAY_OPAQUE_TRUSTED: &str = "trusted.overlay.opaque";
+const OVERLAY_OPAQUE_USER: &str = "user.overlay.opaque";
+
+/// Prefix of the PAX records for extended attributes.
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Records in the PAX extended header of an entry.
+#[derive(Default)]
+struct PaxRecords {
+    xattrs: Vec<(String, Vec<u8>)>,
+    times: PaxTimes,
+    sparse: PaxSparse,
+}
+
+/// High-resolution timestamps from the `mtime` and `atime` PAX records.
+#[derive(Default)]
+struct PaxTimes {
+    mtime: Option<Timespec>,
+    atime: Option<Timespec>,
+}
+
+impl PaxTimes {
+    /// Return the timestamps for an entry.
+    ///
+    /// Without PAX records, the `mtime` of the header is used, and
+    /// `atime` is the same as `mtime`.
+    fn timestamps(&self, header: &tar::Header) -> io::Result<Timestamps> {
+        let mtime = match self.mtime {
+            Some(mtime) => mtime,
+            None => Timespec {
+                tv_sec: i64::try_from(header.mtime()?).unwrap_or_default(),
+                tv_nsec: 0,
+            },
+        };
+
+        Ok(Timestamps {
+            last_access: self.atime.unwrap_or(mtime),
+            last_modification: mtime,
+        })
+    }
+}
+
+/// Parse a timestamp from a PAX record, like `1700000000.123456789`.
+///
+/// Digits beyond nanoseconds are ignored.
+fn parse_pax_time(value: &str) -> Option<Timespec> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    let mut tv_sec: i64 = secs.parse().ok()?;
+
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut tv_nsec = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(9)
+        .fold(0, |n, b| n * 10 + i64::from(b - b'0'));
+
+    // The fraction of negative timestamps is also negative, but
+    // `tv_nsec` must be positive.
+    if secs.starts_with('-') && tv_nsec > 0 {
+        tv_sec = tv_sec.checked_sub(1)?;
+        tv_nsec = 1_000_000_000 - tv_nsec;
+    }
+
+    Some(Timespec {
+        tv_sec,
+        tv_nsec: tv_nsec as _,
+    })
+}
+
+/// Data of a layer to unpack.
+pub(crate) enum LayerSource<'a> {
+    /// The blob was downloaded to a temporary file.
+    File(File),
+
+    /// The blob is read as it is received from the registry.
+    Stream(Box<dyn Read + 'a>),
+}
+
 pub(crate) fn unpack_layer<E: EventHandler>(
-    blob_id: &str,
     event_handler: &E,
     target: &Directory,
     blob: &Blob,
-    mut tarball: File,
-    dirs_metadata: &mut DirectoryMetadata,
+    diff_id: Option<&Digest>,
+    tarball: LayerSource,
+    state: &mut RootfsState,
+    options: &Options,
 ) -> Result<(), UnpackError> {
-    let archive_len = try_io!(blob_id, {
-        let len = tarball.seek(io::SeekFrom::End(0))?;
-        tarball.rewind()?;
-        len
-    });
+    let blob_id = blob.digest.source();
+
+    let (archive_len, tarball): (_, Box<dyn Read>) = match tarball {
+        LayerSource::File(mut file) => {
+            let len = try_io!(blob_id, {
+                let len = file.seek(io::SeekFrom::End(0))?;
+                file.rewind()?;
+                len
+            });
+
+            (len, Box::new(file))
+        }
+
+        LayerSource::Stream(reader) => (blob.expected_size().unwrap_or(0) as u64, reader),
+    };
 
     // Track position (in bytes) to send progress notifications.
     let tarball_position = Cell::new(0);
@@ -50,40 +205,110 @@ pub(crate) fn unpack_layer<E: EventHandler>(
         reader: tarball,
     };
 
-    // Uncompress and extract files from the archive.
-    let reader: Box<dyn Read> = match blob.media_type {
-        MediaType::DockerImageV1 | MediaType::OciConfig => {
-            // Configuration files are just written to disk.
-            return Ok(());
-        }
+    let media_type = blob.media_type;
+    #[cfg_attr(feature = "encryption", expect(unused_mut))]
+    let mut source: Box<dyn Read> = Box::new(tarball);
 
-        MediaType::DockerFsTarGzip | MediaType::OciFsTarGzip => {
-            Box::new(flate2::read::GzDecoder::new(tarball))
-        }
+    #[cfg(feature = "encryption")]
+    let (media_type, mut source) = match crate::encryption::decrypted_media_type(media_type) {
+        Some(decrypted) => (
+            decrypted,
+            crate::encryption::decrypt_layer(blob, &options.decryption_keys, source)?,
+        ),
 
-        #[cfg(feature = "zstd")]
-        MediaType::OciFsTarZstd => {
-            let reader = zstd::stream::read::Decoder::new(tarball)
-                .map_err(|e| UnpackError::Io(e, format!("blob:{blob_id}").into()))?;
+        None => (media_type, source),
+    };
 
-            Box::new(reader)
-        }
+    // Use the compression detected from the first bytes of the layer,
+    // since some tools use the wrong media type for it.
+    let declared = Compression::from_media_type(media_type)
+        .ok_or(UnpackError::InvalidContentType(media_type))?;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    try_io!(
+        blob_id,
+        (&mut source)
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut header)
+    );
+
+    let compression = match Compression::detect(&header) {
+        Some(detected) if detected != declared => {
+            if options.strict_compression {
+                return Err(UnpackError::CompressionMismatch(
+                    blob.digest.clone(),
+                    detected,
+                ));
+            }
 
-        MediaType::OciFsTar => Box::new(BufReader::new(tarball)),
+            event_handler.layer_compression_mismatch(blob, detected);
+            detected
+        }
 
-        unknown => return Err(UnpackError::InvalidContentType(unknown)),
+        _ => declared,
     };
 
+    // Uncompress and extract files from the archive.
+    let reader = compression.decoder(blob_id, io::Cursor::new(header).chain(&mut source))?;
+
+    // Compute the digest of the uncompressed data, to compare it with
+    // the `diff_id` in the image configuration.
+    let reader = HashingReader::new(diff_id.map(|d| d.algorithm()), reader);
+
     event_handler.layer_start(archive_len);
 
     let mut archive = tar::Archive::new(reader);
-    let mut ctx = Context::new(event_handler, blob_id, target, dirs_metadata);
+    let mut ctx = Context::new(event_handler, blob_id, target, state, options);
+
+    let threads = options.extract_threads;
+    let workers = (threads > 1).then(|| FileWorkers::new(threads));
+
+    thread::scope(|scope| {
+        if let Some(workers) = &workers {
+            for _ in 0..threads {
+                let write = Context::file_worker(event_handler, target, workers, ctx.file_writer());
+                scope.spawn(|| workers.run(write));
+            }
+
+            ctx.workers = Some(workers);
+        }
+
+        let mut extract = || {
+            for entry in try_io!(blob_id, archive.entries()) {
+                event_handler.layer_progress(tarball_position.get());
+                ctx.unpack(entry)?;
+            }
+
+            match &workers {
+                Some(workers) => workers.wait(None, ctx.mtree.as_deref_mut()),
+                None => Ok(()),
+            }
+        };
+
+        let result = extract();
+
+        if let Some(workers) = &workers {
+            workers.close(result.is_err());
+        }
+
+        result
+    })?;
+
+    // Consume any data after the end of the archive, so the digest
+    // of the uncompressed data is complete, and the checks done at
+    // the end of the stream (like the HMAC of encrypted layers) are
+    // always executed.
+    let mut reader = archive.into_inner();
+    try_io!(blob_id, io::copy(&mut reader, &mut io::sink()));
 
-    for entry in try_io!(blob_id, archive.entries()) {
-        event_handler.layer_progress(tarball_position.get());
-        ctx.unpack(entry)?;
+    if let (Some(expected), Some(computed)) = (diff_id, reader.digest()) {
+        if !computed.same_hash(expected) {
+            return Err(UnpackError::InvalidDiffId(expected.clone(), computed));
+        }
     }
 
+    try_io!(blob_id, io::copy(&mut source, &mut io::sink()));
+
     event_handler.layer_progress(tarball_position.get());
 
     Ok(())
@@ -117,7 +342,46 @@ struct Context<'a, E> {
     target: &'a Directory,
     dirs_cache: DirFdCache<'a>,
     dirs_metadata: &'a mut DirectoryMetadata,
+    mtree: Option<&'a mut Mtree>,
+    content_store: Option<&'a ContentStore>,
+    options: &'a Options,
     cached_link_dirfd: Option<(PathBuf, OwnedFd)>,
+
+    /// Pool to write regular files, if [`Unpacker::extract_threads`](super::Unpacker::extract_threads)
+    /// is greater than `1`.
+    workers: Option<&'a FileWorkers>,
+
+    /// Value of [`FileWorkers::generation`] when `dirs_cache` was
+    /// last cleared.
+    dirs_generation: usize,
+
+    /// Paths of the entries in the current layer. Whiteouts only apply
+    /// to the lower layers, so these entries are never removed by them.
+    added: BTreeSet<PathBuf>,
+}
+
+/// Settings to write regular files, shared by the workers.
+#[derive(Clone, Copy)]
+struct FileWriter<'a> {
+    options: &'a Options,
+    content_store: Option<&'a ContentStore>,
+
+    /// Return the entries for the mtree manifest.
+    mtree: bool,
+}
+
+/// Regular file from an archive entry.
+pub(super) struct RegularFile<R> {
+    header: tar::Header,
+    times: Timestamps,
+
+    /// The file can be a hard link to the content store.
+    can_link: bool,
+
+    /// Map of the holes, if it is a sparse file.
+    sparse: Option<SparseMap>,
+
+    data: R,
 }
 
 impl<'a, E: EventHandler> Context<'a, E> {
@@ -125,15 +389,22 @@ impl<'a, E: EventHandler> Context<'a, E> {
         event_handler: &'a E,
         blob_id: &'a str,
         target: &'a Directory,
-        dirs_metadata: &'a mut DirectoryMetadata,
+        state: &'a mut RootfsState,
+        options: &'a Options,
     ) -> Self {
         Self {
             event_handler,
             blob_id,
             target,
             dirs_cache: DirFdCache::new(target),
-            dirs_metadata,
+            dirs_metadata: &mut state.dirs_metadata,
+            mtree: state.mtree.as_mut(),
+            content_store: state.content_store.as_ref(),
+            options,
             cached_link_dirfd: None,
+            workers: None,
+            dirs_generation: 0,
+            added: BTreeSet::new(),
         }
     }
 
@@ -142,37 +413,133 @@ impl<'a, E: EventHandler> Context<'a, E> {
     }
 
     fn unpack(&mut self, entry: io::Result<tar::Entry<impl Read>>) -> Result<(), UnpackError> {
-        let entry = try_io!(self.blob_id, entry);
+        let mut entry = try_io!(self.blob_id, entry);
+
+        let PaxRecords {
+            xattrs,
+            times,
+            mut sparse,
+        } = try_io!(self.blob_id, self.read_pax_records(&mut entry));
+
+        // Sparse files in the PAX 1.0 format have their real name in a
+        // PAX record.
+        let entry_path = match sparse.name.take() {
+            Some(name) => Cow::Owned(name),
+            None => try_io!(self.blob_id, entry.path()),
+        };
 
-        let entry_path = try_io!(self.blob_id, entry.path());
         let entry_path = entry_path.as_ref();
 
         let (parent_path, file_name) = try_io!(entry_path, normalize_path(entry_path));
 
-        // Handle whiteout entries.
-        //
-        // The cache for directory file descriptors is reset after removing
-        // any entry.
-        if let Some(whiteout) = file_name
-            .as_os_str()
-            .as_bytes()
-            .strip_prefix(WHITEOUT_PREFIX)
-        {
-            let parent_fd = try_io!(parent_path, self.path_fd(&parent_path));
-            try_io!(entry_path, Self::process_whiteout(parent_fd, whiteout));
-            self.dirs_cache.clear();
+        // AUFS metadata is not part of the filesystem.
+        if is_aufs_metadata(&parent_path, &file_name) {
             return Ok(());
         }
 
+        let whiteout = Whiteout::from_entry(&file_name, entry.header());
+
+        // Wait until the workers are done with the files that may be
+        // affected by this entry. Whiteouts and hard links may depend
+        // on any previous entry.
+        if let Some(workers) = self.workers {
+            let path = match whiteout.is_some() || entry.header().entry_type().is_hard_link() {
+                true => None,
+                false => Some(parent_path.join(&file_name)),
+            };
+
+            workers.wait(path.as_deref(), self.mtree.as_deref_mut())?;
+
+            // A worker may have replaced a directory with a file.
+            if workers.generation() != self.dirs_generation {
+                self.dirs_cache.clear();
+                self.cached_link_dirfd = None;
+                self.dirs_generation = workers.generation();
+            }
+        }
+
+        if let Some(whiteout) = whiteout {
+            return self.unpack_whiteout(entry_path, &parent_path, whiteout);
+        }
+
+        self.added.insert(parent_path.join(&file_name));
+
+        // The entry is consumed when it is unpacked.
+        let entry_path = match xattrs.is_empty() {
+            true => None,
+            false => Some(entry_path.to_path_buf()),
+        };
+
+        // Sparse files are regular files with a map of their holes.
+        let (entry_type, sparse) = match entry.header().entry_type() {
+            tar::EntryType::GNUSparse => (
+                tar::EntryType::Regular,
+                Some(try_io!(&file_name, SparseMap::from_gnu(entry.header()))),
+            ),
+
+            tar::EntryType::Regular => {
+                let size = entry.size();
+                let map = try_io!(&file_name, sparse.into_map(&mut entry, size));
+                (tar::EntryType::Regular, map)
+            }
+
+            other => (other, None),
+        };
+
+        // A hard link to the content store would share the xattrs.
+        let can_link = xattrs.is_empty();
+
+        // Small regular files are written by the workers.
+        if let Some(workers) = self
+            .workers
+            .filter(|_| entry_type == tar::EntryType::Regular && entry.size() <= MAX_BUFFERED_FILE)
+        {
+            // Workers don't create directories, so the parent must exist
+            // before the file is queued.
+            try_io!(&parent_path, self.path_fd(&parent_path));
+            self.forget_metadata(&parent_path.join(&file_name));
+
+            let file = try_io!(
+                &file_name,
+                Self::buffer_file(entry, &times, can_link, sparse)
+            );
+
+            return workers.submit(FileJob {
+                entry_path,
+                parent_path,
+                file_name,
+                file,
+                xattrs,
+                generation: workers.generation(),
+            });
+        }
+
         // Unpack the entry.
         try_io!(file_name, {
-            match entry.header().entry_type() {
-                tar::EntryType::Directory => self.unpack_dir(parent_path, &file_name, entry)?,
+            match entry_type {
+                tar::EntryType::Directory => {
+                    self.unpack_dir(&parent_path, &file_name, entry, &times)?
+                }
 
-                tar::EntryType::Regular => self.unpack_regular(parent_path, &file_name, entry)?,
+                tar::EntryType::Regular => {
+                    let header = entry.header().clone();
+                    let file = RegularFile {
+                        times: times.timestamps(&header)?,
+                        header,
+                        can_link,
+                        sparse,
+                        data: entry,
+                    };
+
+                    self.unpack_regular(&parent_path, &file_name, file)?
+                }
 
                 tar::EntryType::Symlink | tar::EntryType::Link => {
-                    self.unpack_link(self.target.as_fd(), parent_path, &file_name, entry)?
+                    self.unpack_link(self.target.as_fd(), &parent_path, &file_name, entry, &times)?
+                }
+
+                tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                    self.unpack_node(&parent_path, &file_name, entry, &times)?
                 }
 
                 other => {
@@ -182,19 +549,85 @@ impl<'a, E: EventHandler> Context<'a, E> {
             }
         });
 
+        // Hard links share the attributes of their targets.
+        if let Some(entry_path) = entry_path {
+            if matches!(
+                entry_type,
+                tar::EntryType::Directory | tar::EntryType::Regular | tar::EntryType::Symlink
+            ) {
+                let event_handler = self.event_handler;
+                let parent_fd = try_io!(&parent_path, self.path_fd(&parent_path));
+                Self::set_xattrs(event_handler, parent_fd, &file_name, &entry_path, xattrs);
+            }
+        }
+
         Ok(())
     }
 
+    /// Read the PAX records of an entry.
+    ///
+    /// Extended attributes are read from the `SCHILY.xattr.*` records.
+    /// Attributes in the namespaces of [`Unpacker::skip_xattrs`](super::Unpacker::skip_xattrs)
+    /// are ignored.
+    fn read_pax_records(&self, entry: &mut tar::Entry<impl Read>) -> io::Result<PaxRecords> {
+        let mut records = PaxRecords::default();
+
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(records);
+        };
+
+        for extension in extensions {
+            let extension = extension?;
+
+            let Ok(key) = extension.key() else {
+                continue;
+            };
+
+            match key {
+                "mtime" => records.times.mtime = extension.value().ok().and_then(parse_pax_time),
+                "atime" => records.times.atime = extension.value().ok().and_then(parse_pax_time),
+                _ => (),
+            }
+
+            if records.sparse.record(key, extension.value_bytes())? {
+                continue;
+            }
+
+            let Some(name) = key.strip_prefix(XATTR_PREFIX) else {
+                continue;
+            };
+
+            if !self.options.xattrs
+                || self
+                    .options
+                    .skip_xattrs
+                    .iter()
+                    .any(|p| name.starts_with(p.as_str()))
+            {
+                continue;
+            }
+
+            records
+                .xattrs
+                .push((name.to_owned(), extension.value_bytes().to_owned()));
+        }
+
+        Ok(records)
+    }
+
     fn unpack_dir(
         &mut self,
         parent_path: impl AsRef<Path>,
         file_name: &Path,
         entry: tar::Entry<impl Read>,
+        times: &PaxTimes,
     ) -> io::Result<()> {
         use rustix::fs;
 
         let header = entry.header();
+        let options = self.options;
 
+        let parent_path = parent_path.as_ref();
         let parent_fd = self.path_fd(parent_path)?;
         let result = fs::mkdirat(parent_fd, file_name, Mode::from_raw_mode(0o700));
 
@@ -205,17 +638,24 @@ impl<'a, E: EventHandler> Context<'a, E> {
             }
         }
 
-        let (uid, gid) = Self::get_entry_owner(header)?;
+        // The xattr does not modify the mtime, so it can be written now.
+        // Otherwise, the owner is changed with the other metadata.
+        let (uid, gid) = if options.rootless_xattr {
+            Self::set_owner(options, parent_fd, file_name, header)?;
+            (None, None)
+        } else {
+            Self::get_entry_owner(options, header)?
+        };
 
         // Store mtime/mode metadata to be applied later.
-        if let Ok(mtime) = header.mtime() {
+        if let Ok(times) = times.timestamps(header) {
             let (mut path, b) = normalize_path(entry.path()?)?;
             path.push(b);
 
             let key = super::DirectoryMetadataEntry::key(path);
             let entry = super::DirectoryMetadataEntry {
                 mode: Mode::from_bits_retain(header.mode()?),
-                mtime,
+                times,
                 uid,
                 gid,
             };
@@ -223,6 +663,11 @@ impl<'a, E: EventHandler> Context<'a, E> {
             self.dirs_metadata.insert(key, entry);
         }
 
+        if let Some(mtree) = &mut self.mtree {
+            let entry = Self::mtree_entry(header, times.mtime, mtree::EntryType::Dir)?;
+            mtree.insert(parent_path.join(file_name), entry);
+        }
+
         Ok(())
     }
 
@@ -230,20 +675,59 @@ impl<'a, E: EventHandler> Context<'a, E> {
         &mut self,
         parent_path: impl AsRef<Path>,
         file_name: &Path,
-        mut entry: tar::Entry<impl Read>,
+        file: RegularFile<impl Read>,
     ) -> io::Result<()> {
-        use rustix::fs;
+        let parent_path = parent_path.as_ref();
+        let path = parent_path.join(file_name);
 
-        let mode = Mode::from_bits_retain(entry.header().mode()? & 0o7777);
+        self.forget_metadata(&path);
 
-        let parent_path = parent_path.as_ref();
+        let writer = self.file_writer();
         let parent_fd = self.dirs_cache.get(parent_path, true)?;
+        let (mtree_entry, removed) = Self::write_regular(writer, parent_fd, file_name, file)?;
 
+        if removed == RemovedEntry::Directory {
+            self.invalidate_dirs();
+        }
+
+        if let (Some(mtree), Some(entry)) = (&mut self.mtree, mtree_entry) {
+            mtree.insert(path, entry);
+        }
+
+        Ok(())
+    }
+
+    /// Write a regular file, and return its entry for the mtree manifest,
+    /// and the kind of entry replaced by it.
+    ///
+    /// This function is also used by the workers of [`FileWorkers`], so
+    /// it can't access the state of the context. The deferred metadata
+    /// of any entry replaced by the file must be discarded by the caller,
+    /// and the directory caches must be invalidated if the replaced entry
+    /// is a directory.
+    fn write_regular(
+        writer: FileWriter,
+        parent_fd: BorrowedFd,
+        file_name: &Path,
+        mut file: RegularFile<impl Read>,
+    ) -> io::Result<(Option<mtree::Entry>, RemovedEntry)> {
+        use rustix::fs;
+
+        let header = &file.header;
+        let mode = Mode::from_bits_retain(header.mode()? & 0o7777);
+
+        // Files are cloned to the content store, so they must be readable.
+        let access = match writer.content_store {
+            Some(_) => fs::OFlags::RDWR,
+            None => fs::OFlags::WRONLY,
+        };
+
+        let mut removed = RemovedEntry::Nothing;
         let mut output = loop {
             let result = fs::openat2(
                 parent_fd,
                 file_name,
-                fs::OFlags::CREATE | fs::OFlags::EXCL | fs::OFlags::WRONLY,
+                fs::OFlags::CREATE | fs::OFlags::EXCL | access,
                 mode,
                 fs::ResolveFlags::BENEATH,
             );
@@ -252,31 +736,205 @@ impl<'a, E: EventHandler> Context<'a, E> {
                 Ok(f) => break File::from(f),
 
                 Err(e) if e.kind() == AlreadyExists => {
-                    let removed = crate::fs::remove_entry(parent_fd, file_name)?;
-
-                    // Remove the entry from dirs_metadata if the entry
-                    // was a directory.
-                    if removed == crate::fs::RemovedEntry::Directory {
-                        let path = parent_path.join(file_name);
-                        let key = super::DirectoryMetadataEntry::key(path);
-                        self.dirs_metadata.remove(&key);
-                    }
+                    removed = crate::fs::remove_entry(parent_fd, file_name)?;
                 }
 
                 Err(e) => return Err(e.into()),
             }
         };
 
-        io::copy(&mut entry, &mut output)?;
+        let hash = writer.mtree || writer.content_store.is_some();
 
-        drop(output);
+        let (output, size, sha256) = match &file.sparse {
+            Some(map) => {
+                let (size, sha256) = sparse::write(&mut file.data, &output, map, hash)?;
+                (output, size, sha256)
+            }
 
-        Self::set_owner(parent_fd, file_name, entry.header())?;
+            None if hash => {
+                let mut hashing_writer = HashingWriter::new(output);
+                let size = io::copy(&mut file.data, &mut hashing_writer)?;
+                let (output, sha256) = hashing_writer.into_inner();
+                (output, size, Some(sha256))
+            }
 
-        let mtime = Self::make_timestamps(entry.header().mtime()?);
-        fs::utimensat(parent_fd, file_name, &mtime, fs::AtFlags::SYMLINK_NOFOLLOW)?;
+            None => {
+                let size = io::copy(&mut file.data, &mut output)?;
+                (output, size, None)
+            }
+        };
 
-        Ok(())
+        let mtree_entry = match (writer.mtree, sha256) {
+            (true, Some(sha256)) => {
+                let mut mtree_entry = Self::mtree_entry(
+                    header,
+                    Some(file.times.last_modification),
+                    mtree::EntryType::File,
+                )?;
+                mtree_entry.size = size;
+                mtree_entry.sha256 = Some(sha256);
+                Some(mtree_entry)
+            }
+
+            _ => None,
+        };
+
+        let times = file.times;
+
+        // Empty files have no contents to share.
+        let content_store = writer.content_store.filter(|_| size > 0);
+
+        // In hardlink mode, the files in the store share their inode with
+        // the unpacked files, so files with metadata that is not part of
+        // the link (like extended attributes) are not linked to the store,
+        // nor added to it.
+        let can_link = file.can_link && !writer.options.rootless_xattr;
+
+        let mut stored = false;
+        if let (Some(store), Some(sha256)) = (content_store, &sha256) {
+            if store.hardlinks() && can_link {
+                let (uid, gid) = Self::get_entry_owner(writer.options, header)?;
+                let metadata = LinkMetadata {
+                    mode: mode.bits(),
+                    uid: uid.unwrap_or_else(|| rustix::process::geteuid().as_raw()),
+                    gid: gid.unwrap_or_else(|| rustix::process::getegid().as_raw()),
+                    mtime: times.last_modification,
+                };
+
+                if store.link_to(sha256, &metadata, parent_fd, file_name)? {
+                    return Ok((mtree_entry, removed));
+                }
+            }
+
+            stored = store.clone_to(sha256, &output)?;
+        }
+
+        Self::set_owner(writer.options, parent_fd, file_name, header)?;
+        fs::utimensat(parent_fd, file_name, &times, fs::AtFlags::SYMLINK_NOFOLLOW)?;
+
+        // Add the file to the store after its metadata is set, so it can be
+        // hard-linked by other files with the same metadata.
+        if let (Some(store), Some(sha256), false) = (content_store, &sha256, stored) {
+            if can_link || !store.hardlinks() {
+                store.insert(sha256, &output, parent_fd, file_name)?;
+            }
+        }
+
+        Ok((mtree_entry, removed))
+    }
+
+    /// Settings to write regular files, without the state of the context.
+    fn file_writer(&self) -> FileWriter<'a> {
+        FileWriter {
+            options: self.options,
+            content_store: self.content_store,
+            mtree: self.mtree.is_some(),
+        }
+    }
+
+    /// Read the data of a regular file, so it can be written by a worker.
+    fn buffer_file(
+        mut entry: tar::Entry<impl Read>,
+        times: &PaxTimes,
+        can_link: bool,
+        sparse: Option<SparseMap>,
+    ) -> io::Result<RegularFile<io::Cursor<Vec<u8>>>> {
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+
+        let header = entry.header().clone();
+
+        Ok(RegularFile {
+            times: times.timestamps(&header)?,
+            header,
+            can_link,
+            sparse,
+            data: io::Cursor::new(data),
+        })
+    }
+
+    /// Return a function to write the files queued in [`FileWorkers`].
+    ///
+    /// Each worker has its own cache of directories, which is cleared
+    /// when any directory is removed.
+    fn file_worker(
+        event_handler: &'a E,
+        target: &'a Directory,
+        workers: &'a FileWorkers,
+        writer: FileWriter<'a>,
+    ) -> impl FnMut(FileJob) -> Result<Option<mtree::Entry>, UnpackError> + 'a {
+        let mut dirs_cache = DirFdCache::new(target);
+        let mut generation = 0;
+
+        move |job| {
+            if job.generation != generation {
+                dirs_cache.clear();
+                generation = job.generation;
+            }
+
+            let FileJob {
+                entry_path,
+                parent_path,
+                file_name,
+                file,
+                xattrs,
+                ..
+            } = job;
+
+            let (mtree_entry, removed) = try_io!(&file_name, {
+                let parent_fd = dirs_cache.get(&parent_path, false)?;
+                let result = Self::write_regular(writer, parent_fd, &file_name, file)?;
+
+                if let Some(entry_path) = entry_path {
+                    Self::set_xattrs(event_handler, parent_fd, &file_name, &entry_path, xattrs);
+                }
+
+                result
+            });
+
+            if removed == RemovedEntry::Directory {
+                workers.invalidate_dirs();
+                dirs_cache.clear();
+                generation = workers.generation();
+            }
+
+            Ok(mtree_entry)
+        }
+    }
+
+    /// Set the extended attributes of an entry.
+    ///
+    /// Attributes that can't be set are reported to the event handler.
+    fn set_xattrs(
+        event_handler: &E,
+        parent_fd: BorrowedFd,
+        file_name: &Path,
+        entry_path: &Path,
+        xattrs: Vec<(String, Vec<u8>)>,
+    ) {
+        for (name, value) in xattrs {
+            if let Err(e) = crate::fs::set_xattr(parent_fd, file_name, &name, &value) {
+                event_handler.xattr_skipped(entry_path, &name, &e);
+            }
+        }
+    }
+
+    /// Discard the cached file descriptors of directories, in this context
+    /// and in the workers, after a directory is removed.
+    fn invalidate_dirs(&mut self) {
+        self.dirs_cache.clear();
+        self.cached_link_dirfd = None;
+
+        if let Some(workers) = self.workers {
+            workers.invalidate_dirs();
+            self.dirs_generation = workers.generation();
+        }
+    }
+
+    /// Discard the deferred metadata of an entry that is replaced.
+    fn forget_metadata(&mut self, path: &Path) {
+        let key = super::DirectoryMetadataEntry::key(path.to_path_buf());
+        self.dirs_metadata.remove(&key);
     }
 
     /// Unpack hard and symbolic links.
@@ -286,6 +944,7 @@ impl<'a, E: EventHandler> Context<'a, E> {
         parent_path: impl AsRef<Path>,
         file_name: &Path,
         entry: tar::Entry<impl Read>,
+        times: &PaxTimes,
     ) -> io::Result<()> {
         use rustix::fs;
 
@@ -294,7 +953,8 @@ impl<'a, E: EventHandler> Context<'a, E> {
             None => return Err(io::Error::new(NotFound, "Missing link")),
         };
 
-        let parent_fd = self.dirs_cache.get(parent_path, true)?;
+        let parent_path = parent_path.as_ref();
+        let mut parent_fd = self.dirs_cache.get(parent_path, true)?;
 
         let is_symlink = entry.header().entry_type().is_symlink();
 
@@ -337,83 +997,469 @@ impl<'a, E: EventHandler> Context<'a, E> {
                 Ok(_) => break,
 
                 Err(e) if e.kind() == AlreadyExists => {
-                    fs::unlinkat(parent_fd, file_name, fs::AtFlags::empty())?;
+                    self.replace_entry(parent_path, file_name)?;
+                    parent_fd = self.dirs_cache.get(parent_path, true)?;
                 }
 
                 Err(e) => return Err(e.into()),
             }
         }
 
+        if let Some(mtree) = &mut self.mtree {
+            let path = parent_path.join(file_name);
+
+            if is_symlink {
+                let mut mtree_entry =
+                    Self::mtree_entry(entry.header(), times.mtime, mtree::EntryType::Link)?;
+                mtree_entry.link = Some(dest.to_path_buf());
+                mtree.insert(path, mtree_entry);
+            } else {
+                let (old_parent, old_name) = normalize_path(&dest)?;
+                mtree.insert_hardlink(path, &old_parent.join(old_name));
+            }
+        }
+
         if is_symlink {
             let header = entry.header();
 
-            let mtime = Self::make_timestamps(header.mtime()?);
-            fs::utimensat(parent_fd, file_name, &mtime, fs::AtFlags::SYMLINK_NOFOLLOW)?;
+            let times = times.timestamps(header)?;
+            fs::utimensat(parent_fd, file_name, &times, fs::AtFlags::SYMLINK_NOFOLLOW)?;
+
+            Self::set_owner(self.options, parent_fd, file_name, header)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unpack character devices, block devices, and FIFOs.
+    ///
+    /// Device nodes that can't be created are handled according to
+    /// [`Unpacker::device_nodes`](super::Unpacker::device_nodes).
+    fn unpack_node(
+        &mut self,
+        parent_path: impl AsRef<Path>,
+        file_name: &Path,
+        entry: tar::Entry<impl Read>,
+        times: &PaxTimes,
+    ) -> io::Result<()> {
+        use rustix::fs::{self, FileType};
+
+        let header = entry.header();
+        let options = self.options;
+
+        let (file_type, kind) = match header.entry_type() {
+            tar::EntryType::Char => (FileType::CharacterDevice, mtree::EntryType::Char),
+            tar::EntryType::Block => (FileType::BlockDevice, mtree::EntryType::Block),
+            _ => (FileType::Fifo, mtree::EntryType::Fifo),
+        };
+
+        let device = match kind {
+            mtree::EntryType::Fifo => None,
+            _ => Some((
+                header.device_major()?.unwrap_or_default(),
+                header.device_minor()?.unwrap_or_default(),
+            )),
+        };
+
+        let parent_path = parent_path.as_ref();
+        let mut parent_fd = self.dirs_cache.get(parent_path, true)?;
+
+        let (major, minor) = device.unwrap_or_default();
+
+        let created = loop {
+            let result = fs::mknodat(
+                parent_fd,
+                file_name,
+                file_type,
+                Mode::from_raw_mode(0o600),
+                fs::makedev(major, minor),
+            );
+
+            match result {
+                Ok(_) => break true,
+
+                Err(e) if e.kind() == AlreadyExists => {
+                    self.replace_entry(parent_path, file_name)?;
+                    parent_fd = self.dirs_cache.get(parent_path, true)?;
+                }
+
+                Err(e) if e == rustix::io::Errno::PERM => match options.device_nodes {
+                    DeviceNodePolicy::Record if self.mtree.is_some() => break false,
+
+                    DeviceNodePolicy::Skip | DeviceNodePolicy::Record => {
+                        self.event_handler
+                            .layer_entry_skipped(entry.path()?.as_ref(), &e);
+                        return Ok(());
+                    }
+
+                    DeviceNodePolicy::Placeholder => {
+                        fs::openat2(
+                            parent_fd,
+                            file_name,
+                            fs::OFlags::CREATE | fs::OFlags::EXCL | fs::OFlags::WRONLY,
+                            Mode::from_raw_mode(0o600),
+                            fs::ResolveFlags::BENEATH,
+                        )?;
+
+                        break false;
+                    }
+                },
+
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let path = parent_path.join(file_name);
 
-            Self::set_owner(parent_fd, file_name, header)?;
+        if let Some(mtree) = &mut self.mtree {
+            let mut mtree_entry = Self::mtree_entry(header, times.mtime, kind)?;
+            mtree_entry.device = device;
+            mtree_entry.optional = !created;
+            mtree.insert(path.clone(), mtree_entry);
         }
 
+        if !created && options.device_nodes == DeviceNodePolicy::Record {
+            return Ok(());
+        }
+
+        // `user.*` xattrs are not allowed in device nodes, so the owner
+        // is not stored with rootless_xattr.
+        let (uid, gid) = match options.rootless_xattr {
+            true => (None, None),
+            false => Self::get_entry_owner(options, header)?,
+        };
+
+        let key = super::DirectoryMetadataEntry::key(path);
+        let entry = super::DirectoryMetadataEntry {
+            mode: Mode::from_bits_retain(header.mode()? & 0o7777),
+            times: times.timestamps(header)?,
+            uid,
+            gid,
+        };
+
+        self.dirs_metadata.insert(key, entry);
+
         Ok(())
     }
 
+    /// Remove an existing entry, so it can be replaced by a new one.
+    ///
+    /// Deferred metadata for the removed entry, if any, is discarded. If
+    /// the entry is a directory, the cached file descriptors are discarded
+    /// too.
+    fn replace_entry(&mut self, parent_path: &Path, file_name: &Path) -> io::Result<()> {
+        let parent_fd = self.dirs_cache.get(parent_path, true)?;
+        let removed = crate::fs::remove_entry(parent_fd, file_name)?;
+
+        self.forget_metadata(&parent_path.join(file_name));
+
+        if removed == RemovedEntry::Directory {
+            self.invalidate_dirs();
+        }
+
+        Ok(())
+    }
+
+    /// Build an entry for the mtree manifest from the header of an archive entry.
+    ///
+    /// `mtime` is the timestamp from the PAX records, if any. Without it,
+    /// the `mtime` of the header is used.
+    ///
+    /// Like in [`get_entry_owner`](Self::get_entry_owner), invalid numbers
+    /// are ignored.
+    fn mtree_entry(
+        header: &tar::Header,
+        mtime: Option<Timespec>,
+        kind: mtree::EntryType,
+    ) -> io::Result<mtree::Entry> {
+        let mtime = match mtime {
+            Some(t) => (
+                t.tv_sec.try_into().unwrap_or_default(),
+                t.tv_nsec.try_into().unwrap_or_default(),
+            ),
+            None => (header.mtime().unwrap_or_default(), 0),
+        };
+
+        Ok(mtree::Entry {
+            kind,
+            mode: header.mode()? & 0o7777,
+            uid: header.uid().unwrap_or_default(),
+            gid: header.gid().unwrap_or_default(),
+            size: 0,
+            mtime,
+            link: None,
+            sha256: None,
+            device: None,
+            optional: false,
+        })
+    }
+
     fn is_directory(parent: BorrowedFd, file_name: &Path) -> io::Result<bool> {
         let stat = rustix::fs::statat(parent, file_name, rustix::fs::AtFlags::empty())?;
         Ok(stat.st_mode & libc::S_IFDIR != 0)
     }
 
-    fn make_timestamps(mtime: u64) -> rustix::fs::Timestamps {
-        let mtime = rustix::fs::Timespec {
-            tv_sec: i64::try_from(mtime).unwrap_or_default(),
-            tv_nsec: 0,
+    /// Return the `uid, gid` of the entry in the host, translated with
+    /// the ID mappings.
+    ///
+    /// Invalid numbers are ignored. The user ID is also ignored if it is
+    /// the effective UID of the process, since new files are already owned
+    /// by it. The group ID is always kept, because new files can inherit
+    /// the group of a setgid directory.
+    fn get_entry_owner(
+        options: &Options,
+        header: &tar::Header,
+    ) -> io::Result<(Option<u32>, Option<u32>)> {
+        let map = |mappings: &[IdMapping], id: io::Result<u64>| {
+            let Ok(id) = id else {
+                return Ok(None);
+            };
+
+            match idmap::map_id(mappings, id) {
+                Some(id) => Ok(Some(id)),
+                None if mappings.is_empty() => Ok(None),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("ID {id} is not in the ID mapping"),
+                )),
+            }
         };
 
-        rustix::fs::Timestamps {
-            last_access: mtime,
-            last_modification: mtime,
-        }
-    }
+        let current_uid = rustix::process::geteuid().as_raw();
 
-    /// Return the `uid, gid` of the entry.
-    ///
-    /// The owner is ignored if it is `0` (`root`).
-    fn get_entry_owner(header: &tar::Header) -> io::Result<(Option<u32>, Option<u32>)> {
         Ok((
-            match header.uid().map(|id| id.try_into()) {
-                Ok(Ok(id)) if id > 0 => Some(id),
-                _ => None,
-            },
-            match header.gid().map(|id| id.try_into()) {
-                Ok(Ok(id)) if id > 0 => Some(id),
-                _ => None,
-            },
+            map(&options.uid_map, header.uid())?.filter(|&id| id != current_uid),
+            map(&options.gid_map, header.gid())?,
         ))
     }
 
     /// Set user/group of an entry.
     ///
-    /// Errors from `fchownat` are ignored.
-    fn set_owner(parent_fd: BorrowedFd, file_name: &Path, header: &tar::Header) -> io::Result<()> {
-        let (uid, gid) = Self::get_entry_owner(header)?;
-        crate::fs::change_owner(parent_fd, file_name, uid, gid, true)?;
+    /// Errors from `fchownat` are ignored, unless
+    /// [`Unpacker::require_chown`](super::Unpacker::require_chown) is set.
+    ///
+    /// With [`Unpacker::rootless_xattr`](super::Unpacker::rootless_xattr),
+    /// the owner is stored in a xattr instead.
+    fn set_owner(
+        options: &Options,
+        parent_fd: BorrowedFd,
+        file_name: &Path,
+        header: &tar::Header,
+    ) -> io::Result<()> {
+        if options.rootless_xattr {
+            // Linux does not allow `user.*` xattrs in symbolic links.
+            if header.entry_type().is_symlink() {
+                return Ok(());
+            }
+
+            let id = |id: io::Result<u64>| id.ok().and_then(|id| id.try_into().ok());
+            let (uid, gid) = (id(header.uid()), id(header.gid()));
+
+            return crate::fs::set_rootless_owner(
+                parent_fd,
+                file_name,
+                uid.unwrap_or_default(),
+                gid.unwrap_or_default(),
+            );
+        }
+
+        let (uid, gid) = Self::get_entry_owner(options, header)?;
+        crate::fs::change_owner(parent_fd, file_name, uid, gid, true, options.require_chown)?;
+        Ok(())
+    }
+
+    /// Apply a whiteout entry in `parent_path`.
+    ///
+    /// The cache for directory file descriptors is reset after removing
+    /// any entry.
+    fn unpack_whiteout(
+        &mut self,
+        entry_path: &Path,
+        parent_path: &Path,
+        whiteout: Whiteout,
+    ) -> Result<(), UnpackError> {
+        let options = self.options;
+        let parent_fd = try_io!(parent_path, self.dirs_cache.get(parent_path, true));
+
+        if options.layer_store.is_some() {
+            try_io!(
+                entry_path,
+                Self::convert_whiteout(options, &self.added, parent_fd, parent_path, whiteout)
+            );
+            return Ok(());
+        }
+
+        try_io!(
+            entry_path,
+            Self::process_whiteout(&self.added, parent_fd, parent_path, whiteout)
+        );
+
+        self.invalidate_dirs();
+
+        let scope = match whiteout {
+            Whiteout::Opaque => parent_path.to_path_buf(),
+            Whiteout::Entry(name) => parent_path.join(name),
+        };
+
+        let is_removed = |path: &Path| {
+            path != parent_path && path.starts_with(&scope) && !has_added_entries(&self.added, path)
+        };
+
+        // Discard the deferred metadata of the removed entries, so it is
+        // not applied to new entries in the same paths.
+        self.dirs_metadata.retain(|(_, path), _| !is_removed(path));
+
+        if let Some(mtree) = &mut self.mtree {
+            mtree.retain(|path| !is_removed(path));
+        }
+
+        Ok(())
+    }
+
+    /// Convert whiteout entries to the format used by overlayfs, for
+    /// [`Unpacker::overlay_layers`](super::Unpacker::overlay_layers).
+    ///
+    /// A whiteout is a character device with number `0/0`, and an opaque
+    /// directory has the `overlay.opaque` xattr.
+    ///
+    /// If the whiteout is for an entry added by the same layer, the entry
+    /// is kept. If it is a directory, it is marked as opaque, so the
+    /// contents of the lower layers are still hidden.
+    fn convert_whiteout(
+        options: &Options,
+        added: &BTreeSet<PathBuf>,
+        dir: BorrowedFd,
+        dir_path: &Path,
+        whiteout: Whiteout,
+    ) -> io::Result<()> {
+        use rustix::fs::{self, AtFlags, FileType};
+
+        let opaque_xattr = match options.overlay_userxattr {
+            true => OVERLAY_OPAQUE_USER,
+            false => OVERLAY_OPAQUE_TRUSTED,
+        };
+
+        let name = match whiteout {
+            Whiteout::Opaque => {
+                return crate::fs::set_xattr(dir, Path::new("."), opaque_xattr, b"y");
+            }
+
+            Whiteout::Entry(name) => Path::new(name),
+        };
+
+        if has_added_entries(added, &dir_path.join(name)) {
+            return match fs::statat(dir, name, AtFlags::SYMLINK_NOFOLLOW) {
+                Ok(stat) if FileType::from_raw_mode(stat.st_mode) == FileType::Directory => {
+                    crate::fs::set_xattr(dir, name, opaque_xattr, b"y")
+                }
+
+                Ok(_) | Err(rustix::io::Errno::NOENT) => Ok(()),
+
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        crate::fs::remove_entry(dir, name)?;
+        fs::mknodat(dir, name, FileType::CharacterDevice, Mode::empty(), 0)?;
+
         Ok(())
     }
 
     /// Process whiteout entries, by removing files in the directory.
     ///
     /// The [1]specification indicates that whiteout entries _should_
-    /// appear before regular files. This implementation assumes that
-    /// the layer was built in such way.
+    /// appear before regular files, but layers built in a different
+    /// order are also accepted: the entries in `added` are never
+    /// removed, since whiteouts only apply to the lower layers.
     ///
     /// [1]: https://github.com/opencontainers/image-spec/blob/v1.0/layer.md#whiteouts
-    fn process_whiteout(dir: BorrowedFd, whiteout: &[u8]) -> io::Result<()> {
-        let path = Path::new(OsStr::from_bytes(whiteout));
-
-        if whiteout == WHITEOUT_OPAQUE {
-            crate::fs::remove_subtree(dir, Path::new("."))?;
-        } else {
-            crate::fs::remove_entry(dir, path)?;
+    fn process_whiteout(
+        added: &BTreeSet<PathBuf>,
+        dir: BorrowedFd,
+        dir_path: &Path,
+        whiteout: Whiteout,
+    ) -> io::Result<()> {
+        match whiteout {
+            Whiteout::Opaque => remove_lower_children(added, dir, Path::new("."), dir_path),
+            Whiteout::Entry(name) => remove_lower_entry(added, dir, dir_path, name),
         }
+    }
+}
 
-        Ok(())
+/// Check if `path`, or any of its descendants, is in `added`.
+fn has_added_entries(added: &BTreeSet<PathBuf>, path: &Path) -> bool {
+    // Paths are sorted by their components, so the descendants of
+    // `path` are just after it.
+    added
+        .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+        .next()
+        .is_some_and(|p| p.starts_with(path))
+}
+
+/// Remove the entry `name` in `dir`, unless it was added by the current
+/// layer. If it is a directory, the entries from the lower layers in it
+/// are removed.
+fn remove_lower_entry(
+    added: &BTreeSet<PathBuf>,
+    dir: BorrowedFd,
+    dir_path: &Path,
+    name: &OsStr,
+) -> io::Result<()> {
+    let path = dir_path.join(name);
+
+    if has_added_entries(added, &path) {
+        return remove_lower_children(added, dir, Path::new(name), &path);
+    }
+
+    crate::fs::remove_entry(dir, Path::new(name))?;
+    Ok(())
+}
+
+/// Remove the entries of the directory `subdir`, beneath `parent`, that
+/// were not added by the current layer.
+///
+/// Nothing is done if `subdir` is not a directory.
+fn remove_lower_children(
+    added: &BTreeSet<PathBuf>,
+    parent: BorrowedFd,
+    subdir: &Path,
+    subdir_path: &Path,
+) -> io::Result<()> {
+    use rustix::{fs, io::Errno};
+
+    let subdir = match fs::openat2(
+        parent,
+        subdir,
+        fs::OFlags::RDONLY | fs::OFlags::DIRECTORY | fs::OFlags::NOFOLLOW,
+        Mode::empty(),
+        fs::ResolveFlags::BENEATH,
+    ) {
+        Ok(fd) => fd,
+        Err(Errno::NOTDIR | Errno::NOENT | Errno::LOOP) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = fs::Dir::read_from(&subdir)?;
+    while let Some(entry) = entries.read() {
+        let entry = entry?;
+        let name = entry.file_name().to_bytes();
+        if name != b"." && name != b".." {
+            let name = OsStr::from_bytes(name);
+            remove_lower_entry(added, subdir.as_fd(), subdir_path, name)?;
+        }
     }
+
+    Ok(())
+}
+
+#[test]
+fn parse_pax_times() {
+    let time = |value| parse_pax_time(value).map(|t| (t.tv_sec, t.tv_nsec));
+
+    assert_eq!(time("1700000000"), Some((1700000000, 0)));
+    assert_eq!(time("1700000000.5"), Some((1700000000, 500_000_000)));
+    assert_eq!(time("1.1234567891"), Some((1, 123_456_789)));
+    assert_eq!(time("-1.25"), Some((-2, 750_000_000)));
+    assert_eq!(time("1.x"), None);
+    assert_eq!(time(""), None);
 }
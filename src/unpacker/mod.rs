@@ -1,16 +1,31 @@
+mod bundle;
+mod compression;
+mod content_store;
 mod event_handler;
 mod images;
 mod layers;
+mod sparse;
+mod workers;
 
 use std::collections::BTreeMap;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-use crate::{digest::DigestError, reference::Reference, MediaType};
+use crate::{
+    config::ImageConfig,
+    digest::{Digest, DigestError},
+    idmap::IdMapping,
+    manifests::{Index, Manifest},
+    reference::Reference,
+    MediaType, Platform,
+};
 
+pub use compression::Compression;
 pub use event_handler::{EventHandler, NoEventHandler};
 
+pub(crate) use images::{blob_path, CONFIG_PATH, IMAGE_CONFIG_PATH, MANIFEST_PATH, ROOTFS_PATH};
+
 /// Errors from [`Unpacker::unpack`].
 #[derive(thiserror::Error, Debug)]
 pub enum UnpackError {
@@ -39,8 +54,52 @@ pub enum UnpackError {
     #[error("Invalid Content-Type: {0}")]
     InvalidContentType(MediaType),
 
-    #[error("No image for the architecture.")]
+    #[error("No image for the requested platforms.")]
     MissingArchitecture,
+
+    #[error("The reference is not a manifest index.")]
+    MissingIndex,
+
+    #[error("Too many nested manifest indexes.")]
+    IndexTooDeep,
+
+    #[error("Cycle in manifest indexes: {}", .0.source())]
+    IndexCycle(Digest),
+
+    #[error("Size of the blob does not match its descriptor: {}", .0.source())]
+    InvalidBlobSize(Digest),
+
+    #[error("Document is too large: {0}")]
+    DocumentTooLarge(String),
+
+    #[error("Uncompressed layer does not match its diff_id. Expected {}, got {}.", .0.source(), .1.source())]
+    InvalidDiffId(Digest, Digest),
+
+    #[error("The image has {0} layers, but its configuration has {1} diff_ids.")]
+    LayerCountMismatch(usize, usize),
+
+    #[error("Can't resolve the user of the image: {0}")]
+    InvalidUser(String),
+
+    #[error("Foreign layers are not allowed: {}", .0.source())]
+    ForeignLayer(Digest),
+
+    #[error("Layer {} is compressed with {1}, but its media type does not match.", .0.source())]
+    CompressionMismatch(Digest, Compression),
+
+    #[error("Unsupported compression: {0}")]
+    UnsupportedCompression(Compression),
+
+    #[error("Invalid options: {0}")]
+    InvalidOptions(&'static str),
+
+    #[cfg(feature = "encryption")]
+    #[error("Failed to decrypt layer: {0}")]
+    Decryption(#[from] crate::encryption::DecryptionError),
+
+    #[cfg(feature = "schema1")]
+    #[error("Invalid schema 1 manifest: {0}")]
+    InvalidSchema1(&'static str),
 }
 
 /// Wrap a [std::io::Error] with the path related to the I/O operation.
@@ -48,19 +107,19 @@ pub enum UnpackError {
 /// The second argument can be either a single expression, or a block.
 macro_rules! try_io {
     ($path:expr, $b:block) => {
-        match (|| -> Result<_, io::Error> { Ok($b) })() {
+        match (|| -> Result<_, std::io::Error> { Ok($b) })() {
             Ok(ok) => ok,
-            Err(err) => return Err(UnpackError::Io(io::Error::from(err), $path.into())),
+            Err(err) => return Err(UnpackError::Io(std::io::Error::from(err), $path.into())),
         }
     };
 
     ($path:expr, $e:expr $(,)?) => {
-        $e.map_err(|e| UnpackError::Io(io::Error::from(e), $path.into()))?
+        $e.map_err(|e| UnpackError::Io(std::io::Error::from(e), $path.into()))?
     };
 }
 
 // Make visible to mods.
-use try_io;
+pub(crate) use try_io;
 
 /// Track directory metadata, to be applied when all files are written.
 ///
@@ -70,14 +129,47 @@ use try_io;
 /// `mtime` could be set, but it is replaced by the kernel when new files
 /// are unpacked.
 ///
+/// The metadata of device nodes and FIFOs is also stored here, so it is
+/// applied in the same step as the metadata of their parents.
+///
 /// The `usize` field in the key is the length, in bytes, of the path. It
 /// is needed to guarantee that child directories are updated before their
 /// parents.
 type DirectoryMetadata = BTreeMap<(usize, PathBuf), DirectoryMetadataEntry>;
 
+/// State shared by all layers unpacked in the same root filesystem.
+#[derive(Default)]
+struct RootfsState {
+    dirs_metadata: DirectoryMetadata,
+
+    /// Manifest of the unpacked files, if [`Unpacker::mtree`] is enabled.
+    mtree: Option<crate::mtree::Mtree>,
+
+    /// Store to deduplicate files, if [`Unpacker::content_store`] is enabled.
+    content_store: Option<content_store::ContentStore>,
+}
+
+impl RootfsState {
+    fn new(options: &Options) -> Result<Self, UnpackError> {
+        let content_store = match &options.content_store {
+            Some(path) => Some(try_io!(
+                path,
+                content_store::ContentStore::open(path, options.content_store_hardlinks)
+            )),
+            None => None,
+        };
+
+        Ok(RootfsState {
+            dirs_metadata: DirectoryMetadata::default(),
+            mtree: options.mtree.then(crate::mtree::Mtree::default),
+            content_store,
+        })
+    }
+}
+
 struct DirectoryMetadataEntry {
     mode: rustix::fs::Mode,
-    mtime: u64,
+    times: rustix::fs::Timestamps,
     uid: Option<u32>,
     gid: Option<u32>,
 }
@@ -90,13 +182,238 @@ impl DirectoryMetadataEntry {
     }
 }
 
+/// Policy for [foreign layers](MediaType::is_foreign_layer).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ForeignLayerPolicy {
+    /// Fail with [`UnpackError::ForeignLayer`] if the image contains
+    /// any foreign layer.
+    #[default]
+    Reject,
+
+    /// Ignore the foreign layers. The image is unpacked without them.
+    Skip,
+
+    /// Download the foreign layers from the URLs in their descriptors.
+    /// If none of them works, the layer is requested to the registry.
+    ///
+    /// The hosts in the URLs can be restricted with
+    /// [`Unpacker::foreign_layer_hosts`].
+    Download,
+}
+
+/// What to do with device nodes that can't be created, because the
+/// process does not have the `CAP_MKNOD` capability.
+///
+/// FIFOs don't require any privilege, so they are always created.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceNodePolicy {
+    /// Ignore the device node. It is reported with
+    /// [`EventHandler::layer_entry_skipped`].
+    #[default]
+    Skip,
+
+    /// Create an empty regular file in place of the device node. A
+    /// container runtime can bind-mount the device of the host on it.
+    Placeholder,
+
+    /// Don't create anything, but add the device node to the mtree
+    /// manifest (see [`Unpacker::mtree`]), so it can be created later.
+    Record,
+}
+
+/// Default value for [`Unpacker::max_document_size`].
+const DEFAULT_MAX_DOCUMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Options to unpack an image.
+pub(crate) struct Options {
+    foreign_layers: ForeignLayerPolicy,
+    foreign_layer_hosts: Vec<String>,
+    max_document_size: usize,
+    strict_compression: bool,
+    runtime_bundle: bool,
+    mtree: bool,
+    uid_map: Vec<IdMapping>,
+    gid_map: Vec<IdMapping>,
+    require_chown: bool,
+    rootless_xattr: bool,
+    xattrs: bool,
+    skip_xattrs: Vec<String>,
+    device_nodes: DeviceNodePolicy,
+    layer_store: Option<PathBuf>,
+    overlay_userxattr: bool,
+    content_store: Option<PathBuf>,
+    content_store_hardlinks: bool,
+    stream_layers: bool,
+    extract_threads: usize,
+
+    #[cfg(feature = "encryption")]
+    decryption_keys: Vec<crate::DecryptionKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            foreign_layers: ForeignLayerPolicy::default(),
+            foreign_layer_hosts: Vec::new(),
+            max_document_size: DEFAULT_MAX_DOCUMENT_SIZE,
+            strict_compression: false,
+            runtime_bundle: false,
+            mtree: false,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            require_chown: false,
+            rootless_xattr: false,
+            xattrs: true,
+            skip_xattrs: Vec::new(),
+            device_nodes: DeviceNodePolicy::default(),
+            layer_store: None,
+            overlay_userxattr: false,
+            content_store: None,
+            content_store_hardlinks: false,
+            stream_layers: false,
+            extract_threads: 1,
+
+            #[cfg(feature = "encryption")]
+            decryption_keys: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Check that the options can be used together.
+    fn check(&self) -> Result<(), UnpackError> {
+        if self.layer_store.is_some() {
+            if self.mtree {
+                return Err(UnpackError::InvalidOptions(
+                    "mtree manifests require a rootfs directory",
+                ));
+            }
+
+            if self.runtime_bundle {
+                return Err(UnpackError::InvalidOptions(
+                    "runtime bundles require a rootfs directory",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if the layers in `manifest` are allowed.
+    fn check_manifest(&self, manifest: &Manifest) -> Result<(), UnpackError> {
+        if self.foreign_layers == ForeignLayerPolicy::Reject {
+            if let Some(layer) = manifest
+                .layers
+                .iter()
+                .find(|l| l.media_type.is_foreign_layer())
+            {
+                return Err(UnpackError::ForeignLayer(layer.digest.clone()));
+            }
+        }
+
+        // Fail early if there are encrypted layers, but no keys.
+        #[cfg(feature = "encryption")]
+        if self.decryption_keys.is_empty()
+            && manifest
+                .layers
+                .iter()
+                .any(|l| crate::encryption::decrypted_media_type(l.media_type).is_some())
+        {
+            return Err(crate::encryption::DecryptionError::NoMatchingKey.into());
+        }
+
+        Ok(())
+    }
+
+    /// Return `true` if `layer` has to be downloaded.
+    fn want_layer(&self, layer: &crate::Blob) -> bool {
+        self.foreign_layers != ForeignLayerPolicy::Skip || !layer.media_type.is_foreign_layer()
+    }
+}
+
+/// Return the `diff_ids` of the image configuration, if any.
+///
+/// Fails if the number of `diff_ids` does not match the number of
+/// layers in the manifest.
+fn check_diff_ids<'a>(
+    manifest: &Manifest,
+    config: &'a ImageConfig,
+) -> Result<Option<&'a [Digest]>, UnpackError> {
+    let Some(rootfs) = &config.rootfs else {
+        return Ok(None);
+    };
+
+    if rootfs.diff_ids.len() != manifest.layers.len() {
+        return Err(UnpackError::LayerCountMismatch(
+            manifest.layers.len(),
+            rootfs.diff_ids.len(),
+        ));
+    }
+
+    Ok(Some(&rootfs.diff_ids))
+}
+
+/// Image unpacked by [`Unpacker::unpack`] or [`Unpacker::unpack_platforms`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct UnpackedImage {
+    /// Platform of the image, as it appears in the manifest index.
+    ///
+    /// If the reference points directly to a manifest, the platform
+    /// is taken from the image configuration.
+    pub platform: Platform,
+
+    /// Directory where the image was unpacked.
+    pub path: PathBuf,
+
+    /// Digest of the manifest index used to find the image, if any.
+    pub index_digest: Option<Digest>,
+
+    /// Digest of the manifest of the image.
+    pub manifest_digest: Digest,
+
+    /// Digest of the image configuration.
+    pub config_digest: Digest,
+
+    /// Directories of the layers, if they are unpacked with
+    /// [`Unpacker::overlay_layers`].
+    ///
+    /// The uppermost layer is the first one, so the paths can be joined
+    /// with `:` for the `lowerdir` option of overlayfs.
+    pub lower_dirs: Vec<PathBuf>,
+}
+
+/// Metadata of an image, returned by [`Unpacker::inspect`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ImageInfo {
+    /// Manifest index used to select the image, if the reference
+    /// points to an index.
+    pub index: Option<Index>,
+
+    /// Digest of the manifest index.
+    pub index_digest: Option<Digest>,
+
+    /// Platform of the selected entry in the index.
+    pub platform: Option<Platform>,
+
+    /// Manifest of the image.
+    pub manifest: Manifest,
+
+    /// Digest of the manifest.
+    pub manifest_digest: Digest,
+
+    /// Configuration of the image.
+    pub config: ImageConfig,
+}
+
 /// Download an image and unpack its contents to a new directory.
 pub struct Unpacker<'a, E> {
     reference: Reference<'a>,
-    architecture: Option<&'a str>,
-    os: Option<&'a str>,
+    platforms: Vec<Platform>,
     event_handler: E,
     require_sandbox: bool,
+    options: Options,
 }
 
 impl<'a> Unpacker<'a, NoEventHandler> {
@@ -106,10 +423,10 @@ impl<'a> Unpacker<'a, NoEventHandler> {
     pub fn new(reference: Reference<'a>) -> Self {
         Self {
             reference,
-            architecture: None,
-            os: None,
+            platforms: Vec::new(),
             event_handler: NoEventHandler,
             require_sandbox: true,
+            options: Options::default(),
         }
     }
 
@@ -118,9 +435,9 @@ impl<'a> Unpacker<'a, NoEventHandler> {
         Unpacker {
             event_handler,
             reference: self.reference,
-            architecture: self.architecture,
-            os: self.os,
+            platforms: self.platforms,
             require_sandbox: self.require_sandbox,
+            options: self.options,
         }
     }
 }
@@ -135,22 +452,382 @@ impl<'a, E: EventHandler> Unpacker<'a, E> {
         self
     }
 
-    /// Set the expected CPU architecture of the image.
+    /// Add a platform to select the image from a manifest index.
+    ///
+    /// This method can be called multiple times to build a list of
+    /// fallbacks. The platforms are tried in the same order they were
+    /// added, so the first one found in the index is used.
+    ///
+    /// If omitted, it uses the platform currently in use.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use oci_unpack::*;
+    /// # fn f(reference: Reference) {
+    /// Unpacker::new(reference)
+    ///     .platform("linux/amd64".parse().unwrap())
+    ///     .platform("linux/386".parse().unwrap())
+    ///     .unpack("/tmp/image")
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platforms.push(platform);
+        self
+    }
+
+    /// Set the policy for [foreign layers](MediaType::is_foreign_layer).
+    ///
+    /// By default, images with foreign layers are rejected.
+    pub fn foreign_layers(mut self, policy: ForeignLayerPolicy) -> Self {
+        self.options.foreign_layers = policy;
+        self
+    }
+
+    /// Hosts allowed in the URLs of foreign layers, when they are
+    /// downloaded with [`ForeignLayerPolicy::Download`].
+    ///
+    /// A host can include a port, like `example.com:8080`. URLs to other
+    /// hosts are ignored. By default, any host is allowed.
+    ///
+    /// Only `http` and `https` URLs are used, regardless of this option.
+    pub fn foreign_layer_hosts<S: Into<String>>(
+        mut self,
+        hosts: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.options.foreign_layer_hosts = hosts.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Add a private key to decrypt encrypted layers.
+    ///
+    /// This method can be called multiple times. Each layer is
+    /// decrypted with the first key that is valid for it.
+    #[cfg(feature = "encryption")]
+    pub fn decryption_key(mut self, key: crate::DecryptionKey) -> Self {
+        self.options.decryption_keys.push(key);
+        self
+    }
+
+    /// Set the maximum size, in bytes, of the manifests, the indexes, and
+    /// the image configurations.
+    ///
+    /// The default value is 4 MiB.
+    pub fn max_document_size(mut self, size: usize) -> Self {
+        self.options.max_document_size = size;
+        self
+    }
+
+    /// Reject layers if their compression does not match their media type.
+    ///
+    /// By default, the compression of every layer is detected from its
+    /// first bytes. If it does not match the media type, the detected
+    /// compression is used, and the mismatch is reported with
+    /// [`EventHandler::layer_compression_mismatch`].
+    ///
+    /// If `strict` is `true`, the unpacker fails with
+    /// [`UnpackError::CompressionMismatch`].
+    pub fn strict_compression(mut self, strict: bool) -> Self {
+        self.options.strict_compression = strict;
+        self
+    }
+
+    /// Generate an [OCI runtime bundle][bundle].
+    ///
+    /// If `runtime_bundle` is `true`, the `config.json` file in the target
+    /// directory is a [runtime configuration][runtime-config], so the
+    /// image can be executed with tools like `runc run`. The process
+    /// arguments, environment, working directory, and user are taken from
+    /// the image configuration, which is stored in `image-config.json`.
+    ///
+    /// Names in the `User` field are resolved with the `/etc/passwd` and
+    /// `/etc/group` files in the image.
+    ///
+    /// By default, `config.json` contains the image configuration.
+    ///
+    /// [bundle]: https://github.com/opencontainers/runtime-spec/blob/main/bundle.md
+    /// [runtime-config]: https://github.com/opencontainers/runtime-spec/blob/main/config.md
+    pub fn runtime_bundle(mut self, runtime_bundle: bool) -> Self {
+        self.options.runtime_bundle = runtime_bundle;
+        self
+    }
+
+    /// Write a manifest of the unpacked files, in the [mtree] format.
+    ///
+    /// If `mtree` is `true`, the file `<algorithm>_<digest>.mtree` is
+    /// written to the target directory, where `<digest>` is the digest of
+    /// the image manifest (like `umoci unpack`). It contains the type,
+    /// mode, owner, size, mtime, link target, and SHA256 digest of every
+    /// file in `rootfs`, so it can be used to detect changes in the
+    /// filesystem.
+    ///
+    /// The digests are computed while the files are written, so no extra
+    /// pass over the unpacked tree is needed.
+    ///
+    /// The image manifest is also written to `manifest.json`, and the
+    /// blobs of the layers are kept in the `blobs` directory, so the
+    /// changes in `rootfs` can be converted to a new layer with
+    /// [`Repacker`](crate::Repacker).
+    ///
+    /// [mtree]: https://man.freebsd.org/cgi/man.cgi?mtree(5)
+    pub fn mtree(mut self, mtree: bool) -> Self {
+        self.options.mtree = mtree;
+        self
+    }
+
+    /// Set the mappings to translate the user IDs in the layers to
+    /// user IDs in the host.
+    ///
+    /// The mappings have the same meaning as in `/proc/<pid>/uid_map`.
+    /// If there are mappings, IDs out of their ranges are rejected.
+    /// By default, IDs are not changed.
+    ///
+    /// # Examples
+    ///
+    /// Map the owners to the ranges in `/etc/subuid` and `/etc/subgid`,
+    /// like in a rootless container:
+    ///
+    /// ```no_run
+    /// # use oci_unpack::*;
+    /// # fn f(reference: Reference) -> std::io::Result<()> {
+    /// let uid_map = IdMapping::from_subid_file("/etc/subuid", "app", 1000)?;
+    /// let gid_map = IdMapping::from_subid_file("/etc/subgid", "app", 1000)?;
+    ///
+    /// Unpacker::new(reference)
+    ///     .uid_map(uid_map)
+    ///     .gid_map(gid_map)
+    ///     .unpack("/tmp/image")
+    ///     .unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn uid_map(mut self, mappings: impl IntoIterator<Item = IdMapping>) -> Self {
+        self.options.uid_map = mappings.into_iter().collect();
+        self
+    }
+
+    /// Set the mappings to translate the group IDs in the layers to
+    /// group IDs in the host.
+    ///
+    /// See [`uid_map`](Self::uid_map) for more details.
+    pub fn gid_map(mut self, mappings: impl IntoIterator<Item = IdMapping>) -> Self {
+        self.options.gid_map = mappings.into_iter().collect();
+        self
+    }
+
+    /// Set whether errors from `chown` are fatal.
+    ///
+    /// By default, the owners of the files are changed only if the process
+    /// has the privileges to do it, and the errors are ignored. If
+    /// `require_chown` is `true`, the unpacker fails if the owner of a
+    /// file can't be changed.
+    pub fn require_chown(mut self, require_chown: bool) -> Self {
+        self.options.require_chown = require_chown;
+        self
+    }
+
+    /// Store the owners of the files in the `user.rootlesscontainers`
+    /// xattr, instead of changing them with `chown`.
+    ///
+    /// This is useful when the image is unpacked by a user without
+    /// privileges, since the owners in the image would be lost. The
+    /// xattr follows the [rootless containers][proto] specification
+    /// (like `umoci unpack --rootless`), so other tools can recover the
+    /// owners when the files are used in a user namespace.
+    ///
+    /// The files are kept owned by the current user, and the ID mappings
+    /// are not used. The xattr is not added to files owned by `root`, nor
+    /// to symbolic links.
+    ///
+    /// [proto]: https://github.com/rootless-containers/proto
+    pub fn rootless_xattr(mut self, rootless_xattr: bool) -> Self {
+        self.options.rootless_xattr = rootless_xattr;
+        self
+    }
+
+    /// Set whether extended attributes are extracted.
+    ///
+    /// The extended attributes of the files are stored in the archives as
+    /// `SCHILY.xattr.*` PAX records. They include file capabilities
+    /// (`security.capability`) and POSIX ACLs (`system.posix_acl_access` and
+    /// `system.posix_acl_default`).
+    ///
+    /// Attributes that can't be written (for example, because the process
+    /// does not have the required privileges) are reported with
+    /// [`EventHandler::xattr_skipped`].
+    ///
+    /// Extended attributes are extracted by default.
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.options.xattrs = xattrs;
+        self
+    }
+
+    /// Ignore the extended attributes whose name starts with `prefix`, like
+    /// `trusted.` or `security.selinux`.
+    ///
+    /// It can be called multiple times to ignore multiple namespaces.
+    pub fn skip_xattrs(mut self, prefix: impl Into<String>) -> Self {
+        self.options.skip_xattrs.push(prefix.into());
+        self
+    }
+
+    /// Set the policy for device nodes that can't be created.
+    ///
+    /// Character and block devices in the layers are created with
+    /// `mknodat` when the process has the `CAP_MKNOD` capability. If it
+    /// fails with `EPERM`, the device node is handled as described in
+    /// [`DeviceNodePolicy`].
+    ///
+    /// By default, those device nodes are skipped.
+    pub fn device_nodes(mut self, policy: DeviceNodePolicy) -> Self {
+        self.options.device_nodes = policy;
+        self
+    }
+
+    /// Unpack each layer in its own directory, to be mounted with overlayfs,
+    /// instead of merging them in `rootfs`.
+    ///
+    /// The layers are written to subdirectories of `store`, named as
+    /// `<algorithm>_<digest>`, where `<digest>` is the `diff_id` of the
+    /// layer (or the digest of the blob, if the image configuration has
+    /// no `diff_ids`). Layers that already exist in `store` are not
+    /// downloaded again, so the directory can be shared by multiple
+    /// images.
+    ///
+    /// Whiteout files are converted to the format used by overlayfs:
+    /// `.wh.<name>` entries are created as `0/0` character devices, and
+    /// opaque directories (`.wh..wh..opq`) get the `trusted.overlay.opaque`
+    /// xattr (see [`overlay_userxattr`](Self::overlay_userxattr)).
+    ///
+    /// The directories are returned in [`UnpackedImage::lower_dirs`].
+    /// The target directory only contains the image configuration.
+    ///
+    /// This mode can't be used with [`mtree`](Self::mtree) or
+    /// [`runtime_bundle`](Self::runtime_bundle).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use oci_unpack::*;
+    /// # fn f(reference: Reference) {
+    /// let image = Unpacker::new(reference)
+    ///     .overlay_layers("/var/lib/layers")
+    ///     .unpack("/tmp/image")
+    ///     .unwrap();
+    ///
+    /// let lower_dirs: Vec<_> = image.lower_dirs.iter().map(|p| p.display().to_string()).collect();
+    /// println!("lowerdir={}", lower_dirs.join(":"));
+    /// # }
+    /// ```
+    pub fn overlay_layers(mut self, store: impl Into<PathBuf>) -> Self {
+        self.options.layer_store = Some(store.into());
+        self
+    }
+
+    /// Use the `user.overlay.*` xattrs for opaque directories, instead of
+    /// `trusted.overlay.*`.
     ///
-    /// If omitted, it uses the architecture currently in use.
-    pub fn architecture(mut self, architecture: &'a str) -> Self {
-        self.architecture = Some(architecture);
+    /// This is required if the layers are mounted without privileges,
+    /// with the `userxattr` option of overlayfs.
+    pub fn overlay_userxattr(mut self, userxattr: bool) -> Self {
+        self.options.overlay_userxattr = userxattr;
         self
     }
 
-    /// Set the expected operating system  the image.
+    /// Deduplicate the contents of regular files with the files in `store`.
     ///
-    /// If omitted, it uses the operating system currently in use.
-    pub fn os(mut self, os: &'a str) -> Self {
-        self.os = Some(os);
+    /// When a regular file is unpacked, its SHA256 digest is computed while
+    /// it is written. If `store` has a file with the same digest, the new
+    /// file is cloned from it with `FICLONE`, so both share the same
+    /// extents in the disk. Otherwise, a clone of the new file is added to
+    /// `store`.
+    ///
+    /// `FICLONE` is supported by filesystems like Btrfs and XFS. In other
+    /// filesystems, files are not deduplicated, unless hard links are
+    /// enabled with [`content_store_hardlinks`](Self::content_store_hardlinks).
+    ///
+    /// `store` must be in the same filesystem as the target directory.
+    pub fn content_store(mut self, store: impl Into<PathBuf>) -> Self {
+        self.options.content_store = Some(store.into());
+        self
+    }
+
+    /// Replace files with hard links to the files in the content store,
+    /// when they have the same contents, mode, owner, and mtime.
+    ///
+    /// Files with extended attributes are never hard-linked, and neither
+    /// are files when the owners are stored with
+    /// [`rootless_xattr`](Self::rootless_xattr).
+    ///
+    /// Files in different images share the same inode, so a file modified
+    /// in place in one image is also modified in the store and in every
+    /// other image. Use this option only if the unpacked images are never
+    /// modified.
+    pub fn content_store_hardlinks(mut self, hardlinks: bool) -> Self {
+        self.options.content_store_hardlinks = hardlinks;
+        self
+    }
+
+    /// Extract the first layer while it is downloaded, instead of waiting
+    /// until the whole blob is written to a temporary file.
+    ///
+    /// Only the first layer is streamed. Later layers are still downloaded
+    /// to temporary files in parallel, while the previous ones are
+    /// extracted.
+    ///
+    /// The digest of a streamed layer can only be verified after it is
+    /// extracted. If it is wrong, or the layer can't be extracted, the
+    /// `rootfs` directory (or the directory of the layer, with
+    /// [`overlay_layers`](Self::overlay_layers)) is removed.
+    ///
+    /// Layers are never streamed with [`mtree`](Self::mtree), because
+    /// their blobs are kept in the target directory.
+    pub fn stream_layers(mut self, stream: bool) -> Self {
+        self.options.stream_layers = stream;
         self
     }
 
+    /// Number of threads to write the files of a layer.
+    ///
+    /// The archive is always read by a single thread, but the regular
+    /// files are created, written, and their metadata updated, in a pool
+    /// of `threads` workers. This is useful for layers with many small
+    /// files, especially on network filesystems.
+    ///
+    /// Entries that depend on previous ones, like whiteouts, hard links,
+    /// or entries that replace a file still being written, wait until
+    /// the previous entries are complete.
+    ///
+    /// The default value is `1`, so every file is written by the thread
+    /// that reads the archive.
+    pub fn extract_threads(mut self, threads: usize) -> Self {
+        self.options.extract_threads = threads.max(1);
+        self
+    }
+
+    /// Download the manifests and the configuration of the image, without
+    /// downloading any layer.
+    ///
+    /// The image is selected with the same rules used by [`unpack`](Self::unpack).
+    pub fn inspect(&self) -> Result<ImageInfo, UnpackError> {
+        let mut client = self.http_client();
+
+        let resolved = crate::manifests::get(&self.reference, &self.platforms, &mut client)?;
+        let (config, _) = crate::manifests::get_config(&resolved.manifest, &client)?;
+
+        let (index, index_digest) = resolved.index.unzip();
+
+        Ok(ImageInfo {
+            index,
+            index_digest,
+            platform: resolved.platform,
+            manifest: resolved.manifest,
+            manifest_digest: resolved.digest,
+            config,
+        })
+    }
+
     /// Download the image of `reference`, and unpack its contents to the
     /// directory `target`.
     ///
@@ -159,27 +836,212 @@ impl<'a, E: EventHandler> Unpacker<'a, E> {
     /// Before unpacking the layers, it tries to create a sandbox to restrict
     /// the write access to the `target` directory. If the sandbox can't be
     /// created, and `require_sandbox` is `true`, the process is interrupted.
-    pub fn unpack(self, target: impl AsRef<Path>) -> Result<(), UnpackError> {
+    ///
+    /// Returns the digests of the objects used to unpack the image, so
+    /// the caller can record exactly what was written to `target`.
+    pub fn unpack(self, target: impl AsRef<Path>) -> Result<UnpackedImage, UnpackError> {
         let target = target.as_ref();
 
+        self.options.check()?;
+
         Self::check_empty_dir(target).map_err(|e| UnpackError::Io(e, target.to_owned()))?;
+        self.create_stores()?;
+
+        let mut client = self.http_client();
 
-        let mut client = crate::http::Client::new(&self.reference, &self.event_handler);
+        let resolved = crate::manifests::get(&self.reference, &self.platforms, &mut client)?;
+        let manifest = resolved.manifest;
 
-        let manifest =
-            crate::manifests::get(&self.reference, self.architecture, self.os, &mut client)?;
+        self.options.check_manifest(&manifest)?;
+
+        let (image_config, config) = crate::manifests::get_config(&manifest, &client)?;
+        check_diff_ids(&manifest, &image_config)?;
 
         // Create sandbox after downloading the manifest, but before writing any
         // file. Thus, we don't need to gran read-access to the files needed to
         // make HTTPS requests (like `/etc/resolv.conf` or `/etc/ssl`).
+        self.try_sandbox(target)?;
+
+        let image = images::ImageData {
+            manifest: &manifest,
+            digest: &resolved.digest,
+            config: &image_config,
+            raw_config: &config,
+        };
+
+        let lower_dirs = images::get(
+            &client,
+            &image,
+            target,
+            &self.event_handler,
+            &self.options,
+            None,
+        )?;
+
+        let platform = resolved.platform.unwrap_or_else(|| Platform {
+            os: image_config.os,
+            architecture: image_config.architecture,
+            variant: image_config.variant,
+            ..Platform::default()
+        });
+
+        Ok(UnpackedImage {
+            platform,
+            path: target.to_owned(),
+            index_digest: resolved.index.map(|(_, d)| d),
+            manifest_digest: resolved.digest,
+            config_digest: manifest.config.digest,
+            lower_dirs,
+        })
+    }
+
+    /// Download every image in the manifest index of `reference`, and
+    /// unpack each one to a subdirectory of `target`.
+    ///
+    /// The name of each subdirectory is `<os>-<arch>[-<variant>]`. If the
+    /// platform has an OS version (like in Windows images), it is added
+    /// at the end of the name. If multiple entries in the index have the
+    /// same platform, only the first one is unpacked.
+    ///
+    /// If some platforms were added with [`platform`](Self::platform),
+    /// only the images that match any of them are unpacked.
+    ///
+    /// Layers that are shared by multiple images are downloaded only once.
+    ///
+    /// If `target` exists, it must be empty.
+    pub fn unpack_platforms(
+        self,
+        target: impl AsRef<Path>,
+    ) -> Result<Vec<UnpackedImage>, UnpackError> {
+        let target = target.as_ref();
+
+        self.options.check()?;
+
+        Self::check_empty_dir(target).map_err(|e| UnpackError::Io(e, target.to_owned()))?;
+        self.create_stores()?;
+
+        let mut client = self.http_client();
+
+        let (index_digest, images) =
+            crate::manifests::get_all(&self.reference, &self.platforms, &mut client)?;
+
+        if images.is_empty() {
+            return Err(UnpackError::MissingArchitecture);
+        }
+
+        for image in &images {
+            self.options.check_manifest(&image.manifest)?;
+        }
+
+        let configs = images
+            .iter()
+            .map(|i| crate::manifests::get_config(&i.manifest, &client))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (image, (image_config, _)) in images.iter().zip(&configs) {
+            check_diff_ids(&image.manifest, image_config)?;
+        }
+
+        self.try_sandbox(target)?;
+
+        let blob_cache = images::BlobCache::new(images.iter().map(|i| &i.manifest));
+
+        let mut unpacked = Vec::with_capacity(images.len());
+
+        for (image, (image_config, config)) in images.into_iter().zip(configs) {
+            let path = target.join(Self::platform_dir_name(&image.platform));
+
+            try_io!(&path, std::fs::create_dir(&path));
+
+            let data = images::ImageData {
+                manifest: &image.manifest,
+                digest: &image.digest,
+                config: &image_config,
+                raw_config: &config,
+            };
+
+            let lower_dirs = images::get(
+                &client,
+                &data,
+                &path,
+                &self.event_handler,
+                &self.options,
+                Some(&blob_cache),
+            )?;
+
+            blob_cache.release(&image.manifest);
+
+            unpacked.push(UnpackedImage {
+                platform: image.platform,
+                path,
+                index_digest: Some(index_digest.clone()),
+                manifest_digest: image.digest,
+                config_digest: image.manifest.config.digest,
+                lower_dirs,
+            });
+        }
+
+        Ok(unpacked)
+    }
+
+    /// Create a HTTP client for the registry of the image.
+    fn http_client(&self) -> crate::http::Client<'_, E> {
+        crate::http::Client::new(
+            &self.reference,
+            &self.event_handler,
+            self.options.max_document_size,
+        )
+        .external_hosts(&self.options.foreign_layer_hosts)
+    }
+
+    /// Return the name of the directory to unpack an image of `platform`.
+    fn platform_dir_name(platform: &Platform) -> String {
+        let platform = platform.clone().normalize();
+        let mut name = format!("{}-{}", platform.os, platform.architecture);
+
+        for component in [&platform.variant, &platform.os_version]
+            .into_iter()
+            .flatten()
+        {
+            name.push('-');
+            name.push_str(component);
+        }
+
+        // Don't allow path separators in the name.
+        name.replace(['/', '\\'], "_")
+    }
+
+    /// Create the sandbox, if it is enabled.
+    #[cfg_attr(not(feature = "sandbox"), expect(unused_variables))]
+    fn try_sandbox(&self, target: &Path) -> Result<(), UnpackError> {
         #[cfg(feature = "sandbox")]
-        if let Err(err) = Self::sandbox(target, &self.event_handler) {
+        if let Err(err) = Self::sandbox(
+            [
+                Some(target),
+                self.options.layer_store.as_deref(),
+                self.options.content_store.as_deref(),
+            ],
+            &self.event_handler,
+        ) {
             if self.require_sandbox {
                 return Err(UnpackError::Sandbox(err));
             }
         }
 
-        images::get(client, manifest, target, &self.event_handler)
+        Ok(())
+    }
+
+    /// Create the directories for [`overlay_layers`](Self::overlay_layers)
+    /// and [`content_store`](Self::content_store), if they don't exist.
+    fn create_stores(&self) -> Result<(), UnpackError> {
+        for store in [&self.options.layer_store, &self.options.content_store]
+            .into_iter()
+            .flatten()
+        {
+            try_io!(store, std::fs::create_dir_all(store));
+        }
+
+        Ok(())
     }
 
     /// Check if the `target` directory is empty.
@@ -199,14 +1061,15 @@ impl<'a, E: EventHandler> Unpacker<'a, E> {
         Ok(())
     }
 
-    /// Restrict filesystem access to the `target` directory.
+    /// Restrict filesystem access to the `target` directory, and to the
+    /// stores, if any.
     ///
     /// The sandbox must be created after initializing the HTTP client,
     /// since the rules don't allow access to other files in the system,
     /// like `/etc/resolv.conf` or `/etc/ssl`.
     #[cfg(feature = "sandbox")]
     fn sandbox(
-        target: &Path,
+        paths: [Option<&Path>; 3],
         event_handler: &impl EventHandler,
     ) -> Result<(), landlock::RulesetError> {
         use landlock::*;
@@ -217,7 +1080,10 @@ impl<'a, E: EventHandler> Unpacker<'a, E> {
             .set_compatibility(CompatLevel::HardRequirement)
             .handle_access(AccessFs::from_all(abi))?
             .create()?
-            .add_rules(path_beneath_rules(&[target], AccessFs::from_all(abi)))?
+            .add_rules(path_beneath_rules(
+                paths.iter().flatten(),
+                AccessFs::from_all(abi),
+            ))?
             .restrict_self()?;
 
         event_handler.sandbox_status(status);
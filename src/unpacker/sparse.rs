@@ -0,0 +1,448 @@
+//! Sparse files in GNU and PAX archives.
+//!
+//! GNU archives use the `S` entry type, with the map of the file in the
+//! header. PAX archives use regular entries, with the map in the
+//! `GNU.sparse.*` records (formats 0.0 and 0.1) or at the beginning of
+//! the data of the entry (format 1.0).
+//!
+//! See the [GNU tar manual][manual] for more details.
+//!
+//! [manual]: https://www.gnu.org/software/tar/manual/html_node/Sparse-Formats.html
+
+use std::{fs::File, io, io::Read, os::unix::fs::FileExt, path::PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks in an archive.
+const BLOCK_SIZE: u64 = 512;
+
+/// Size of the buffer to copy data to the file.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Maximum length of a number in the map of the 1.0 format.
+const MAX_NUMBER_LEN: usize = 20;
+
+/// Region of a sparse file with data. Everything else is a hole.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(super) struct Region {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Map of a sparse file.
+#[derive(Debug, PartialEq)]
+pub(super) enum SparseMap {
+    /// The entry contains only the data of the regions, one after
+    /// another. This is used by the PAX formats.
+    Packed {
+        regions: Vec<Region>,
+        real_size: u64,
+    },
+
+    /// The entry contains the whole file, with the holes filled with
+    /// zeros. GNU entries are expanded by the `tar` crate.
+    ///
+    /// If `regions` is `None`, the map is unknown (because it is in
+    /// extended headers, which are not accessible), so the holes are
+    /// the blocks with only zeros.
+    Expanded { regions: Option<Vec<Region>> },
+}
+
+impl SparseMap {
+    /// Read the map from the header of a GNU sparse entry.
+    pub fn from_gnu(header: &tar::Header) -> io::Result<SparseMap> {
+        let Some(gnu) = header.as_gnu() else {
+            return Err(invalid_data("Sparse entry without a GNU header"));
+        };
+
+        if gnu.is_extended() {
+            return Ok(SparseMap::Expanded { regions: None });
+        }
+
+        let mut regions = Vec::new();
+        for block in gnu.sparse.iter().filter(|b| !b.is_empty()) {
+            regions.push(Region {
+                offset: block.offset()?,
+                len: block.length()?,
+            });
+        }
+
+        Ok(SparseMap::Expanded {
+            regions: Some(regions),
+        })
+    }
+}
+
+/// `GNU.sparse.*` records in the PAX extended header of an entry.
+#[derive(Default)]
+pub(super) struct PaxSparse {
+    major: Option<u64>,
+    minor: Option<u64>,
+    real_size: Option<u64>,
+    map: Option<String>,
+    offsets: Vec<u64>,
+    lengths: Vec<u64>,
+
+    /// Real name of the file, in the 1.0 format.
+    pub name: Option<PathBuf>,
+}
+
+impl PaxSparse {
+    /// Process a PAX record. Returns `false` if it is not a `GNU.sparse.*`
+    /// record.
+    pub fn record(&mut self, key: &str, value: &[u8]) -> io::Result<bool> {
+        let Some(key) = key.strip_prefix("GNU.sparse.") else {
+            return Ok(false);
+        };
+
+        let number = || {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| invalid_data(format!("Invalid value for GNU.sparse.{key}")))
+        };
+
+        match key {
+            "major" => self.major = Some(number()?),
+            "minor" => self.minor = Some(number()?),
+            "size" | "realsize" => self.real_size = Some(number()?),
+            "offset" => self.offsets.push(number()?),
+            "numbytes" => self.lengths.push(number()?),
+            "map" => self.map = Some(String::from_utf8_lossy(value).into_owned()),
+            "name" => {
+                use std::os::unix::ffi::OsStrExt;
+                self.name = Some(std::ffi::OsStr::from_bytes(value).into());
+            }
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    /// Return the map of the sparse file, or `None` if the entry is not
+    /// sparse.
+    ///
+    /// In the 1.0 format, the map is read from `data`.
+    pub fn into_map(self, data: &mut impl Read, entry_size: u64) -> io::Result<Option<SparseMap>> {
+        let (numbers, data_size) = match (self.major, self.minor) {
+            (Some(1), Some(0)) => {
+                let (numbers, map_size) = read_map(data)?;
+                let data_size = entry_size
+                    .checked_sub(map_size)
+                    .ok_or_else(|| invalid_data("Sparse map is larger than the entry"))?;
+
+                (numbers, data_size)
+            }
+
+            (Some(_), _) => return Err(invalid_data("Unsupported sparse format")),
+
+            _ => match &self.map {
+                Some(map) => {
+                    let numbers = map
+                        .split(',')
+                        .filter(|n| !n.is_empty())
+                        .map(|n| n.parse().map_err(|_| invalid_data("Invalid sparse map")))
+                        .collect::<io::Result<Vec<u64>>>()?;
+
+                    (numbers, entry_size)
+                }
+
+                None if self.offsets.is_empty() => return Ok(None),
+
+                None => {
+                    if self.offsets.len() != self.lengths.len() {
+                        return Err(invalid_data("Invalid sparse map"));
+                    }
+
+                    let numbers = self
+                        .offsets
+                        .iter()
+                        .zip(&self.lengths)
+                        .flat_map(|(&o, &l)| [o, l])
+                        .collect();
+
+                    (numbers, entry_size)
+                }
+            },
+        };
+
+        let real_size = self
+            .real_size
+            .ok_or_else(|| invalid_data("Missing size of sparse file"))?;
+
+        if numbers.len() % 2 != 0 {
+            return Err(invalid_data("Invalid sparse map"));
+        }
+
+        let regions: Vec<_> = numbers
+            .chunks(2)
+            .map(|pair| Region {
+                offset: pair[0],
+                len: pair[1],
+            })
+            .collect();
+
+        // Regions must be sorted, and inside the file.
+        let mut position = 0;
+        let mut total = 0u64;
+        for region in &regions {
+            let end = region.offset.checked_add(region.len);
+            if region.offset < position || end.is_none_or(|e| e > real_size) {
+                return Err(invalid_data("Invalid sparse map"));
+            }
+
+            position = region.offset + region.len;
+            total += region.len;
+        }
+
+        if total != data_size {
+            return Err(invalid_data("Sparse map does not match the entry size"));
+        }
+
+        Ok(Some(SparseMap::Packed { regions, real_size }))
+    }
+}
+
+/// Read the map at the beginning of the data of an entry in the 1.0
+/// format.
+///
+/// The map is a list of decimal numbers, each one followed by a newline.
+/// The first one is the number of regions, and then the offset and the
+/// length of each region. It is padded to a multiple of the block size.
+///
+/// Returns the numbers of the regions, and the size of the map.
+fn read_map(data: &mut impl Read) -> io::Result<(Vec<u64>, u64)> {
+    let mut consumed: u64 = 0;
+
+    let mut read_number = || -> io::Result<u64> {
+        let mut digits = Vec::new();
+        loop {
+            let mut byte = [0];
+            data.read_exact(&mut byte)?;
+            consumed += 1;
+
+            match byte[0] {
+                b'\n' => break,
+                b if b.is_ascii_digit() && digits.len() < MAX_NUMBER_LEN => digits.push(b),
+                _ => return Err(invalid_data("Invalid sparse map")),
+            }
+        }
+
+        std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|d| d.parse().ok())
+            .ok_or_else(|| invalid_data("Invalid sparse map"))
+    };
+
+    let count = read_number()?;
+
+    let mut numbers = Vec::new();
+    for _ in 0..count
+        .checked_mul(2)
+        .ok_or_else(|| invalid_data("Invalid sparse map"))?
+    {
+        numbers.push(read_number()?);
+    }
+
+    let padding = consumed.next_multiple_of(BLOCK_SIZE) - consumed;
+    io::copy(&mut data.take(padding), &mut io::sink())?;
+
+    Ok((numbers, consumed + padding))
+}
+
+/// Write a sparse file to `output`, which must be empty. The holes are
+/// never written, so they don't use space in the filesystem.
+///
+/// If `hash` is `true`, it returns the SHA256 digest of the contents
+/// of the file, including the holes.
+pub(super) fn write(
+    data: &mut impl Read,
+    output: &File,
+    map: &SparseMap,
+    hash: bool,
+) -> io::Result<(u64, Option<[u8; 32]>)> {
+    let mut writer = SparseWriter {
+        output,
+        position: 0,
+        hasher: hash.then(Sha256::new),
+    };
+
+    match map {
+        SparseMap::Packed { regions, real_size } => {
+            for region in regions {
+                writer.hole(region.offset - writer.position);
+                writer.copy(data, region.len, false)?;
+            }
+
+            writer.hole(real_size - writer.position);
+        }
+
+        SparseMap::Expanded {
+            regions: Some(regions),
+        } => {
+            for region in regions {
+                let hole = region
+                    .offset
+                    .checked_sub(writer.position)
+                    .ok_or_else(|| invalid_data("Invalid sparse map"))?;
+
+                io::copy(&mut data.take(hole), &mut io::sink())?;
+                writer.hole(hole);
+
+                writer.copy(data, region.len, false)?;
+            }
+
+            let trailing = io::copy(data, &mut io::sink())?;
+            writer.hole(trailing);
+        }
+
+        SparseMap::Expanded { regions: None } => {
+            writer.copy(data, u64::MAX, true)?;
+        }
+    }
+
+    output.set_len(writer.position)?;
+
+    let digest = writer.hasher.map(|h| h.finalize().into());
+    Ok((writer.position, digest))
+}
+
+struct SparseWriter<'a> {
+    output: &'a File,
+    position: u64,
+    hasher: Option<Sha256>,
+}
+
+impl SparseWriter<'_> {
+    /// Skip `len` bytes in the output.
+    fn hole(&mut self, len: u64) {
+        if let Some(hasher) = &mut self.hasher {
+            let zeros = [0; BUFFER_SIZE];
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = remaining.min(BUFFER_SIZE as u64) as usize;
+                hasher.update(&zeros[..n]);
+                remaining -= n as u64;
+            }
+        }
+
+        self.position += len;
+    }
+
+    /// Copy up to `len` bytes from `data` to the output.
+    ///
+    /// If `skip_zeros` is `true`, blocks with only zeros are not written.
+    fn copy(&mut self, data: &mut impl Read, len: u64, skip_zeros: bool) -> io::Result<()> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut data = data.take(len);
+
+        loop {
+            let n = read_full(&mut data, &mut buffer)?;
+            if n == 0 {
+                break;
+            }
+
+            let chunk = &buffer[..n];
+
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(chunk);
+            }
+
+            if skip_zeros {
+                for (index, block) in chunk.chunks(BLOCK_SIZE as usize).enumerate() {
+                    if block.iter().any(|&b| b != 0) {
+                        let offset = self.position + (index as u64) * BLOCK_SIZE;
+                        self.output.write_all_at(block, offset)?;
+                    }
+                }
+            } else {
+                self.output.write_all_at(chunk, self.position)?;
+            }
+
+            self.position += n as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// Fill `buffer` from `data`, unless EOF is found.
+fn read_full(data: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match data.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[test]
+fn parse_pax_maps() {
+    let map = |records: &[(&str, &str)], data: &[u8], entry_size| {
+        let mut sparse = PaxSparse::default();
+        for (key, value) in records {
+            assert!(sparse.record(key, value.as_bytes()).unwrap());
+        }
+
+        sparse.into_map(&mut &data[..], entry_size).unwrap()
+    };
+
+    let expected = Some(SparseMap::Packed {
+        regions: vec![
+            Region { offset: 0, len: 10 },
+            Region {
+                offset: 100,
+                len: 20,
+            },
+        ],
+        real_size: 200,
+    });
+
+    // 0.0
+    let records = [
+        ("GNU.sparse.size", "200"),
+        ("GNU.sparse.numblocks", "2"),
+        ("GNU.sparse.offset", "0"),
+        ("GNU.sparse.numbytes", "10"),
+        ("GNU.sparse.offset", "100"),
+        ("GNU.sparse.numbytes", "20"),
+    ];
+    assert_eq!(map(&records, b"", 30), expected);
+
+    // 0.1
+    let records = [
+        ("GNU.sparse.size", "200"),
+        ("GNU.sparse.numblocks", "2"),
+        ("GNU.sparse.map", "0,10,100,20"),
+    ];
+    assert_eq!(map(&records, b"", 30), expected);
+
+    // 1.0
+    let records = [
+        ("GNU.sparse.major", "1"),
+        ("GNU.sparse.minor", "0"),
+        ("GNU.sparse.name", "file"),
+        ("GNU.sparse.realsize", "200"),
+    ];
+    let mut data = b"2\n0\n10\n100\n20\n".to_vec();
+    data.resize(512, 0);
+    assert_eq!(map(&records, &data, 542), expected);
+
+    // Regions out of the file.
+    let mut sparse = PaxSparse::default();
+    sparse.record("GNU.sparse.size", b"10").unwrap();
+    sparse.record("GNU.sparse.map", b"5,10").unwrap();
+    assert!(sparse.into_map(&mut &b""[..], 10).is_err());
+
+    // Not a sparse file.
+    assert_eq!(map(&[], b"", 0), None);
+}
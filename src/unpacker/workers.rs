@@ -0,0 +1,251 @@
+//! Pool of threads to write regular files while a layer is extracted.
+//!
+//! See [`Unpacker::extract_threads`](super::Unpacker::extract_threads).
+
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Condvar, Mutex,
+    },
+};
+
+use crate::mtree::{self, Mtree};
+
+use super::{layers::RegularFile, UnpackError};
+
+/// Maximum size of a file written by the pool. Larger files are written
+/// by the thread that reads the archive, so their data is not buffered.
+pub(super) const MAX_BUFFERED_FILE: u64 = 1024 * 1024;
+
+/// Number of jobs that can be queued for every worker.
+const JOBS_PER_WORKER: usize = 16;
+
+/// Regular file to be written by a worker.
+pub(super) struct FileJob {
+    /// Path of the entry in the archive, if it has extended attributes.
+    pub entry_path: Option<PathBuf>,
+
+    pub parent_path: PathBuf,
+    pub file_name: PathBuf,
+    pub file: RegularFile<io::Cursor<Vec<u8>>>,
+    pub xattrs: Vec<(String, Vec<u8>)>,
+
+    /// Value of [`FileWorkers::generation`] when the job was queued.
+    pub generation: usize,
+}
+
+impl FileJob {
+    fn path(&self) -> PathBuf {
+        self.parent_path.join(&self.file_name)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Paths of the files that are being written.
+    in_flight: BTreeSet<PathBuf>,
+
+    /// Entries for the mtree manifest of the files already written.
+    finished: Vec<(PathBuf, mtree::Entry)>,
+
+    /// First error found by a worker.
+    error: Option<UnpackError>,
+}
+
+pub(super) struct FileWorkers {
+    sender: Mutex<Option<mpsc::SyncSender<FileJob>>>,
+    receiver: Mutex<mpsc::Receiver<FileJob>>,
+    state: Mutex<State>,
+    notifier: Condvar,
+
+    /// Incremented when directories are removed, so the workers discard
+    /// their cached file descriptors.
+    generation: AtomicUsize,
+}
+
+impl FileWorkers {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(threads * JOBS_PER_WORKER);
+
+        FileWorkers {
+            sender: Mutex::new(Some(sender)),
+            receiver: Mutex::new(receiver),
+            state: Mutex::default(),
+            notifier: Condvar::new(),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Notify the workers that some directories were removed.
+    pub fn invalidate_dirs(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Queue a file to be written by the pool.
+    ///
+    /// The caller must [`wait`](Self::wait) for conflicting files before.
+    pub fn submit(&self, job: FileJob) -> Result<(), UnpackError> {
+        self.state.lock().unwrap().in_flight.insert(job.path());
+
+        let sender = self.sender.lock().unwrap();
+        if let Some(sender) = &*sender {
+            if sender.send(job).is_ok() {
+                return Ok(());
+            }
+        }
+
+        Err(UnpackError::Interrupted)
+    }
+
+    /// Wait until the files in the pool don't conflict with `path`, or
+    /// until all files are written if `path` is `None`.
+    ///
+    /// A file conflicts with `path` if it is the same path, one of its
+    /// ancestors, or one of its descendants.
+    ///
+    /// The entries of the written files are added to `mtree`, and the
+    /// first error from the workers, if any, is returned.
+    pub fn wait(&self, path: Option<&Path>, mtree: Option<&mut Mtree>) -> Result<(), UnpackError> {
+        let mut state = self.state.lock().unwrap();
+
+        while state.error.is_none() && Self::conflicts(&state.in_flight, path) {
+            state = self.notifier.wait(state).unwrap();
+        }
+
+        if let Some(error) = state.error.take() {
+            return Err(error);
+        }
+
+        let finished = std::mem::take(&mut state.finished);
+        drop(state);
+
+        if let Some(mtree) = mtree {
+            for (path, entry) in finished {
+                mtree.insert(path, entry);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn conflicts(in_flight: &BTreeSet<PathBuf>, path: Option<&Path>) -> bool {
+        let Some(path) = path else {
+            return !in_flight.is_empty();
+        };
+
+        // Paths are sorted by their components, so the descendants of
+        // `path` are just after it.
+        if let Some(next) = in_flight.range(path.to_path_buf()..).next() {
+            if next.starts_with(path) {
+                return true;
+            }
+        }
+
+        path.ancestors().skip(1).any(|p| in_flight.contains(p))
+    }
+
+    /// Stop the workers after the queued jobs are done.
+    ///
+    /// If `discard` is `true`, the jobs in the queue are discarded.
+    pub fn close(&self, discard: bool) {
+        self.sender.lock().unwrap().take();
+
+        if discard {
+            let mut state = self.state.lock().unwrap();
+            state.error.get_or_insert(UnpackError::Interrupted);
+        }
+    }
+
+    /// Run jobs until the pool is closed.
+    ///
+    /// After an error, the remaining jobs are discarded.
+    pub fn run<F>(&self, mut write: F)
+    where
+        F: FnMut(FileJob) -> Result<Option<mtree::Entry>, UnpackError>,
+    {
+        loop {
+            let Ok(job) = self.receiver.lock().unwrap().recv() else {
+                return;
+            };
+
+            let path = job.path();
+            let mut done = JobGuard {
+                workers: self,
+                path: Some(path.clone()),
+            };
+
+            let result = match self.state.lock().unwrap().error {
+                Some(_) => Ok(None),
+                None => write(job),
+            };
+
+            let mut state = self.state.lock().unwrap();
+            match result {
+                Ok(Some(entry)) => state.finished.push((path, entry)),
+                Ok(None) => (),
+                Err(e) => {
+                    state.error.get_or_insert(e);
+                }
+            }
+
+            drop(state);
+            done.finish(None);
+        }
+    }
+}
+
+/// Remove a job from the files in flight when it is done.
+///
+/// If the worker panics, the error is reported to the thread that
+/// reads the archive, so it does not wait forever.
+struct JobGuard<'a> {
+    workers: &'a FileWorkers,
+    path: Option<PathBuf>,
+}
+
+impl JobGuard<'_> {
+    fn finish(&mut self, error: Option<UnpackError>) {
+        let Some(path) = self.path.take() else {
+            return;
+        };
+
+        let mut state = self.workers.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight.remove(&path);
+
+        if let Some(error) = error {
+            state.error.get_or_insert(error);
+        }
+
+        self.workers.notifier.notify_all();
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.finish(Some(UnpackError::Interrupted));
+    }
+}
+
+#[test]
+fn conflicting_paths() {
+    let in_flight: BTreeSet<_> = ["/a/b", "/c"].into_iter().map(PathBuf::from).collect();
+
+    let conflicts = |path: &str| FileWorkers::conflicts(&in_flight, Some(Path::new(path)));
+
+    assert!(conflicts("/a/b"));
+    assert!(conflicts("/a"));
+    assert!(conflicts("/c/d"));
+    assert!(!conflicts("/a/bc"));
+    assert!(!conflicts("/a b"));
+    assert!(!conflicts("/b"));
+
+    assert!(FileWorkers::conflicts(&in_flight, None));
+    assert!(!FileWorkers::conflicts(&BTreeSet::new(), None));
+}
@@ -2,7 +2,7 @@ use std::fs;
 
 use oci_unpack::{MediaType, Reference, Unpacker};
 
-mod common;
+pub mod common;
 
 use common::{
     blobs::Blob,
@@ -62,8 +62,7 @@ fn multiple_layers_test() {
     let reference = Reference::try_from(reference.as_str());
 
     Unpacker::new(reference.unwrap())
-        .architecture(registry::ARCH)
-        .os(registry::OS)
+        .platform(registry::platform())
         .unpack(target.path())
         .expect("Run unpacker");
 
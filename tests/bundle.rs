@@ -0,0 +1,131 @@
+use std::fs;
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+#[test]
+fn generate_runtime_bundle() {
+    let target = tempfile::tempdir().unwrap();
+
+    let config = serde_json::json!({
+        "architecture": registry::ARCH,
+        "os": registry::OS,
+        "config": {
+            "User": "app",
+            "Env": ["HOME=/home/app"],
+            "Entrypoint": ["/bin/app"],
+            "Cmd": ["--verbose"],
+            "WorkingDir": "/srv",
+            "Labels": { "com.example": "test" },
+            "StopSignal": "SIGINT",
+        },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    let layers = vec![Blob::archive(MediaType::OciFsTarGzip)
+        .directory("etc")
+        .regular(
+            "etc/passwd",
+            "root:x:0:0::/root:/bin/sh\napp:x:1000:100::/home/app:/bin/sh\n",
+        )
+        .regular(
+            "etc/group",
+            "root:x:0:\nusers:x:100:\nwheel:x:10:root,app\n",
+        )
+        .build()];
+
+    let config_data = config.data.clone();
+
+    let port = start_registry("foo/bundle", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/bundle:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .runtime_bundle(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    // The image configuration is moved to another file.
+    assert_eq!(
+        fs::read(target.path().join("image-config.json")).unwrap(),
+        &*config_data
+    );
+
+    let spec: serde_json::Value =
+        serde_json::from_slice(&fs::read(target.path().join("config.json")).unwrap()).unwrap();
+
+    let process = &spec["process"];
+    assert_eq!(
+        process["args"],
+        serde_json::json!(["/bin/app", "--verbose"])
+    );
+    assert_eq!(process["cwd"], "/srv");
+    assert_eq!(
+        process["user"],
+        serde_json::json!({ "uid": 1000, "gid": 100, "additionalGids": [10] })
+    );
+
+    let env = process["env"].as_array().unwrap();
+    assert!(env.iter().any(|e| e == "HOME=/home/app"));
+    assert!(env.iter().any(|e| e.as_str().unwrap().starts_with("PATH=")));
+
+    assert_eq!(spec["root"]["path"], "rootfs");
+    assert_eq!(spec["annotations"]["com.example"], "test");
+    assert_eq!(
+        spec["annotations"]["org.opencontainers.image.stopSignal"],
+        "SIGINT"
+    );
+
+    assert!(spec["mounts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|m| m["destination"] == "/proc"));
+
+    assert!(spec["linux"]["namespaces"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|ns| ns["type"] == "mount"));
+}
+
+#[test]
+fn ignore_special_user_databases() {
+    let target = tempfile::tempdir().unwrap();
+
+    let config = serde_json::json!({
+        "architecture": registry::ARCH,
+        "os": registry::OS,
+        "config": { "User": "1000" },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    // Reading a FIFO would block the unpacker.
+    let layers = vec![Blob::archive(MediaType::OciFsTarGzip)
+        .directory("etc")
+        .node("etc/passwd", tar::EntryType::Fifo, (0, 0))
+        .node("etc/group", tar::EntryType::Fifo, (0, 0))
+        .build()];
+
+    let port = start_registry("foo/bundle", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/bundle:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .runtime_bundle(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let spec: serde_json::Value =
+        serde_json::from_slice(&fs::read(target.path().join("config.json")).unwrap()).unwrap();
+
+    assert_eq!(spec["process"]["user"]["uid"], 1000);
+}
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, fmt, io::Write, path::Path, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, fmt, io::Write, path::Path, rc::Rc};
 
 use flate2::write::GzEncoder;
 use oci_unpack::MediaType;
@@ -10,6 +10,11 @@ pub struct Blob {
     pub media_type: MediaType,
     pub digest: String,
     pub data: Box<[u8]>,
+    pub urls: Vec<String>,
+    pub annotations: HashMap<String, String>,
+
+    /// Size in the descriptor. It can be modified to test invalid sizes.
+    pub size: usize,
 }
 
 impl serde::Serialize for Blob {
@@ -17,10 +22,23 @@ impl serde::Serialize for Blob {
     where
         S: serde::Serializer,
     {
-        let mut s = serializer.serialize_struct("Blob", 3)?;
+        let mut s = serializer.serialize_struct("Blob", 5)?;
         s.serialize_field("mediaType", self.media_type.as_str())?;
         s.serialize_field("digest", &format!("sha256:{}", self.digest))?;
-        s.serialize_field("size", &self.data.len())?;
+        s.serialize_field("size", &self.size)?;
+
+        if self.urls.is_empty() {
+            s.skip_field("urls")?;
+        } else {
+            s.serialize_field("urls", &self.urls)?;
+        }
+
+        if self.annotations.is_empty() {
+            s.skip_field("annotations")?;
+        } else {
+            s.serialize_field("annotations", &self.annotations)?;
+        }
+
         s.end()
     }
 }
@@ -36,7 +54,10 @@ impl Blob {
         Blob {
             media_type,
             digest,
+            size: data.len(),
             data,
+            urls: Vec::new(),
+            annotations: HashMap::new(),
         }
     }
 
@@ -45,7 +66,9 @@ impl Blob {
         let buffer = SharedBuffer(Rc::new(Vec::with_capacity(4096).into()));
 
         let stream: Box<dyn Write> = match media_type {
-            MediaType::OciFsTarGzip => Box::new(GzEncoder::new(buffer.clone(), Default::default())),
+            MediaType::OciFsTarGzip | MediaType::DockerForeignFsTarGzip => {
+                Box::new(GzEncoder::new(buffer.clone(), Default::default()))
+            }
 
             #[cfg(feature = "zstd")]
             MediaType::OciFsTarZstd => Box::new(
@@ -61,6 +84,7 @@ impl Blob {
             media_type,
             buffer,
             archive: tar::Builder::new(stream),
+            owner: (0, 0),
         }
     }
 }
@@ -69,6 +93,7 @@ pub struct BlobArchive {
     media_type: MediaType,
     buffer: SharedBuffer,
     archive: tar::Builder<Box<dyn Write>>,
+    owner: (u64, u64),
 }
 
 #[derive(Clone)]
@@ -93,8 +118,41 @@ impl BlobArchive {
         Blob::new(self.media_type, self.buffer.0.take())
     }
 
-    pub fn directory(mut self, path: impl AsRef<Path>) -> Self {
+    /// Set the `uid, gid` for the next entries.
+    pub fn owner(mut self, uid: u64, gid: u64) -> Self {
+        self.owner = (uid, gid);
+        self
+    }
+
+    /// Create a header with the current owner.
+    fn header(&self) -> tar::Header {
         let mut header = tar::Header::new_gnu();
+        header.set_uid(self.owner.0);
+        header.set_gid(self.owner.1);
+        header
+    }
+
+    /// Add PAX records to the next entry.
+    pub fn pax_records(mut self, records: &[(&str, &str)]) -> Self {
+        self.archive
+            .append_pax_extensions(records.iter().map(|(k, v)| (*k, v.as_bytes())))
+            .unwrap();
+        self
+    }
+
+    /// Add extended attributes to the next entry.
+    pub fn xattrs(self, xattrs: &[(&str, &str)]) -> Self {
+        let records: Vec<_> = xattrs
+            .iter()
+            .map(|(name, value)| (format!("SCHILY.xattr.{name}"), *value))
+            .collect();
+
+        let records: Vec<_> = records.iter().map(|(k, v)| (k.as_str(), *v)).collect();
+        self.pax_records(&records)
+    }
+
+    pub fn directory(mut self, path: impl AsRef<Path>) -> Self {
+        let mut header = self.header();
         header.set_path(path).unwrap();
         header.set_mode(0o755);
         header.set_entry_type(tar::EntryType::dir());
@@ -106,7 +164,7 @@ impl BlobArchive {
 
     pub fn regular(mut self, path: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Self {
         let data = data.as_ref();
-        let mut header = tar::Header::new_gnu();
+        let mut header = self.header();
         header.set_path(path).unwrap();
         header.set_mode(0o644);
         header.set_entry_type(tar::EntryType::file());
@@ -117,13 +175,178 @@ impl BlobArchive {
     }
 
     pub fn symlink(mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> Self {
-        let mut header = tar::Header::new_gnu();
+        let mut header = self.header();
         header.set_mode(0o755);
         header.set_entry_type(tar::EntryType::symlink());
         header.set_size(0);
         self.archive.append_link(&mut header, path, target).unwrap();
         self
     }
+
+    /// Add a device node or a FIFO.
+    pub fn node(
+        mut self,
+        path: impl AsRef<Path>,
+        entry_type: tar::EntryType,
+        device: (u32, u32),
+    ) -> Self {
+        let mut header = self.header();
+        header.set_path(path).unwrap();
+        header.set_mode(0o666);
+        header.set_entry_type(entry_type);
+        header.set_device_major(device.0).unwrap();
+        header.set_device_minor(device.1).unwrap();
+        header.set_size(0);
+        header.set_cksum();
+        self.archive.append(&header, &b""[..]).unwrap();
+        self
+    }
+
+    pub fn hardlink(mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> Self {
+        let mut header = self.header();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::hard_link());
+        header.set_size(0);
+        self.archive.append_link(&mut header, path, target).unwrap();
+        self
+    }
+
+    /// Add a GNU sparse entry. `regions` are the offsets and the data of
+    /// the regions of the file; everything else is a hole.
+    ///
+    /// If there are more than 4 regions, the map is written in extended
+    /// sparse headers.
+    ///
+    /// Like GNU tar, the regions are padded with zeros to a multiple of
+    /// the block size, and the map ends with an empty region at the end
+    /// of the file.
+    pub fn gnu_sparse(
+        mut self,
+        path: impl AsRef<Path>,
+        regions: &[(u64, &[u8])],
+        real_size: u64,
+    ) -> Self {
+        let regions: Vec<(u64, Vec<u8>)> = regions
+            .iter()
+            .map(|(offset, data)| {
+                let mut data = data.to_vec();
+                data.resize(data.len().next_multiple_of(512), 0);
+                (*offset, data)
+            })
+            .chain([(real_size, Vec::new())])
+            .collect();
+
+        let data: Vec<u8> = regions.iter().flat_map(|r| &r.1).copied().collect();
+
+        let mut header = self.header();
+        header.set_path(path).unwrap();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::GNUSparse);
+        header.set_size(data.len() as u64);
+
+        let (in_header, extended) = regions.split_at(regions.len().min(4));
+
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.set_real_size(real_size);
+        gnu.set_is_extended(!extended.is_empty());
+        for (sparse, (offset, data)) in gnu.sparse.iter_mut().zip(in_header) {
+            sparse.set_offset(*offset);
+            sparse.set_length(data.len() as u64);
+        }
+
+        header.set_cksum();
+
+        let mut blocks = header.as_bytes().to_vec();
+
+        let chunks: Vec<_> = extended.chunks(21).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let mut ext = tar::GnuExtSparseHeader::new();
+            ext.set_is_extended(index + 1 < chunks.len());
+            for (sparse, (offset, data)) in ext.sparse_mut().iter_mut().zip(*chunk) {
+                sparse.set_offset(*offset);
+                sparse.set_length(data.len() as u64);
+            }
+
+            blocks.extend_from_slice(ext.as_bytes());
+        }
+
+        blocks.extend_from_slice(&data);
+        blocks.resize(blocks.len().next_multiple_of(512), 0);
+
+        self.archive.get_mut().write_all(&blocks).unwrap();
+        self
+    }
+
+    /// Add a sparse file using the PAX format `version` (`"0.0"`, `"0.1"`,
+    /// or `"1.0"`).
+    pub fn pax_sparse(
+        self,
+        path: impl AsRef<Path>,
+        version: &str,
+        regions: &[(u64, &[u8])],
+        real_size: u64,
+    ) -> Self {
+        let path = path.as_ref();
+        let real_size = real_size.to_string();
+        let count = regions.len().to_string();
+
+        let numbers: Vec<_> = regions
+            .iter()
+            .flat_map(|(offset, data)| [offset.to_string(), data.len().to_string()])
+            .collect();
+
+        let mut data: Vec<u8> = regions.iter().flat_map(|r| r.1).copied().collect();
+
+        let mut records = Vec::new();
+        let entry_path = match version {
+            "0.0" => {
+                records.push(("GNU.sparse.size", real_size.as_str()));
+                records.push(("GNU.sparse.numblocks", count.as_str()));
+                for pair in numbers.chunks(2) {
+                    records.push(("GNU.sparse.offset", pair[0].as_str()));
+                    records.push(("GNU.sparse.numbytes", pair[1].as_str()));
+                }
+
+                path.to_owned()
+            }
+
+            "0.1" => {
+                records.push(("GNU.sparse.size", real_size.as_str()));
+                records.push(("GNU.sparse.numblocks", count.as_str()));
+                records.push(("GNU.sparse.map", ""));
+                path.to_owned()
+            }
+
+            "1.0" => {
+                records.push(("GNU.sparse.major", "1"));
+                records.push(("GNU.sparse.minor", "0"));
+                records.push(("GNU.sparse.name", path.to_str().unwrap()));
+                records.push(("GNU.sparse.realsize", real_size.as_str()));
+
+                let mut map = format!("{count}\n");
+                for number in &numbers {
+                    map.push_str(number);
+                    map.push('\n');
+                }
+
+                let mut map = map.into_bytes();
+                map.resize(map.len().next_multiple_of(512), 0);
+                map.extend_from_slice(&data);
+                data = map;
+
+                Path::new("GNUSparseFile.0").join(path)
+            }
+
+            _ => panic!("Unknown sparse format {version}"),
+        };
+
+        let map = numbers.join(",");
+        if let Some(record) = records.iter_mut().find(|r| r.0 == "GNU.sparse.map") {
+            record.1 = map.as_str();
+        }
+
+        self.pax_records(&records).regular(entry_path, data)
+    }
 }
 
 /// Encode a byte buffer as hex string.
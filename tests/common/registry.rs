@@ -1,6 +1,11 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use oci_unpack::MediaType;
+use oci_unpack::{MediaType, Platform};
 use tiny_http::{Header, Request, Response, Server};
 
 use super::blobs::Blob;
@@ -9,6 +14,33 @@ pub const ARCH: &str = "ARCH";
 
 pub const OS: &str = "OS";
 
+/// Platform to select images in the test registry.
+pub fn platform() -> Platform {
+    Platform::new(OS, ARCH)
+}
+
+/// Image served by the registry, for a specific platform.
+pub struct Image {
+    pub platform: &'static str,
+    pub config: Blob,
+    pub layers: Vec<Blob>,
+}
+
+/// Entry in a manifest index.
+pub enum Entry {
+    Image(Image),
+
+    /// Nested index. The platform, if any, is added to the entry in
+    /// the parent index.
+    Index(Option<&'static str>, Vec<Entry>),
+
+    /// Entry with an unknown media type, and an optional platform.
+    Artifact(Option<&'static str>),
+}
+
+/// Number of requests received for each blob, indexed by its digest.
+pub type BlobRequests = Arc<Mutex<HashMap<String, usize>>>;
+
 /// Start a registry server in a random port.
 ///
 /// Returns the port number of the server.
@@ -18,41 +50,228 @@ pub fn start_registry(
     config: Blob,
     layers: Vec<Blob>,
 ) -> u16 {
+    start_registry_with_content_digest(repository, tag, config, layers, None)
+}
+
+/// Start a registry server in a random port.
+///
+/// If `content_digest` is not `None`, it is sent in the
+/// `Docker-Content-Digest` header, instead of the real digest
+/// of the manifest.
+///
+/// Returns the port number of the server.
+pub fn start_registry_with_content_digest(
+    repository: &'static str,
+    tag: &'static str,
+    config: Blob,
+    layers: Vec<Blob>,
+    content_digest: Option<&'static str>,
+) -> u16 {
+    let server = Server::http("127.1:0").expect("start registry server");
+    let port = server.server_addr().to_ip().unwrap().port();
+
+    let mut registry = Registry::new(server, repository);
+    registry.content_digest = content_digest;
+    let manifest = registry.add_image(config, layers);
+    registry.add_manifest(tag, manifest);
+
+    std::thread::spawn(move || registry.run());
+
+    port
+}
+
+/// Start a registry server in a random port, with an index for
+/// multiple images.
+///
+/// Returns the port number of the server, and a map to track the
+/// requests for the blobs.
+pub fn start_registry_with_index(
+    repository: &'static str,
+    tag: &'static str,
+    images: Vec<Image>,
+) -> (u16, BlobRequests) {
+    let entries = images.into_iter().map(Entry::Image).collect();
+    start_registry_with_entries(repository, tag, entries)
+}
+
+/// Start a registry server in a random port. The manifest for `tag`
+/// is an index with the items in `entries`.
+pub fn start_registry_with_entries(
+    repository: &'static str,
+    tag: &'static str,
+    entries: Vec<Entry>,
+) -> (u16, BlobRequests) {
     let server = Server::http("127.1:0").expect("start registry server");
     let port = server.server_addr().to_ip().unwrap().port();
 
-    let registry = Registry::new(server, repository, tag, config, layers);
+    let mut registry = Registry::new(server, repository);
+
+    let index = registry.add_index(entries);
+    registry.add_manifest(tag, index);
+
+    let requests = registry.blob_requests.clone();
 
     std::thread::spawn(move || registry.run());
 
+    (port, requests)
+}
+
+/// Start a HTTP server in a random port, to serve files outside
+/// the registry.
+///
+/// `files` is a list of paths and their contents. Returns the port
+/// number of the server.
+pub fn start_file_server(files: Vec<(String, Box<[u8]>)>) -> u16 {
+    let server = Server::http("127.1:0").expect("start file server");
+    let port = server.server_addr().to_ip().unwrap().port();
+
+    let files: HashMap<_, _> = files.into_iter().collect();
+
+    std::thread::spawn(move || {
+        let timeout = Duration::from_secs(30);
+
+        while let Ok(Some(request)) = server.recv_timeout(timeout) {
+            let response = match files.get(request.url()) {
+                Some(data) => Response::from_data(data.clone()),
+                None => Response::from_data(vec![]).with_status_code(404),
+            };
+
+            request.respond(response).expect("Send response");
+        }
+    });
+
     port
 }
 
+/// Build the JSON object for the `platform` field of an index entry.
+fn platform_json(platform: &str) -> serde_json::Value {
+    let mut parts = platform.split('/');
+    let mut platform = serde_json::json!({
+        "os": parts.next().unwrap(),
+        "architecture": parts.next().unwrap(),
+    });
+
+    if let Some(variant) = parts.next() {
+        platform["variant"] = variant.into();
+    }
+
+    platform
+}
+
 struct Registry {
     server: Server,
-    manifest_path: String,
+    manifests_prefix: String,
     blobs_prefix: String,
-    config: Blob,
-    layers: Vec<Blob>,
+    manifests: HashMap<String, Blob>,
+    blobs: HashMap<String, Blob>,
+    blob_requests: BlobRequests,
+    content_digest: Option<&'static str>,
 }
 
 impl Registry {
-    fn new(
-        server: Server,
-        repository: &'static str,
-        tag: &'static str,
-        config: Blob,
-        layers: Vec<Blob>,
-    ) -> Registry {
+    fn new(server: Server, repository: &'static str) -> Registry {
         Registry {
             server,
-            manifest_path: format!("/v2/{repository}/manifests/{tag}"),
+            manifests_prefix: format!("/v2/{repository}/manifests/"),
             blobs_prefix: format!("/v2/{repository}/blobs/sha256:"),
-            config,
-            layers,
+            manifests: HashMap::new(),
+            blobs: HashMap::new(),
+            blob_requests: Default::default(),
+            content_digest: None,
         }
     }
 
+    /// Add the blobs of an image, and return its manifest.
+    fn add_image(&mut self, config: Blob, layers: Vec<Blob>) -> Blob {
+        #[derive(serde::Serialize, Debug)]
+        struct Image<'a> {
+            config: &'a Blob,
+            layers: &'a [Blob],
+        }
+
+        let manifest = Image {
+            config: &config,
+            layers: &layers,
+        };
+
+        let manifest = Blob::new(
+            MediaType::OciManifestV1,
+            serde_json::to_vec(&manifest).expect("Serialize JSON"),
+        );
+
+        // Blobs with URLs are foreign layers, which are not
+        // stored in the registry.
+        for blob in [config].into_iter().chain(layers) {
+            if blob.urls.is_empty() {
+                self.blobs.insert(blob.digest.clone(), blob);
+            }
+        }
+
+        manifest
+    }
+
+    /// Add the manifests of the items in `entries`, and return the
+    /// index to reference them.
+    fn add_index(&mut self, entries: Vec<Entry>) -> Blob {
+        let mut manifests = Vec::new();
+
+        for entry in entries {
+            let (manifest, platform) = match entry {
+                Entry::Image(image) => (
+                    self.add_image(image.config, image.layers),
+                    Some(image.platform),
+                ),
+
+                Entry::Index(platform, entries) => (self.add_index(entries), platform),
+
+                Entry::Artifact(platform) => {
+                    let mut item = serde_json::json!({
+                        "mediaType": "application/vnd.example.artifact",
+                        "digest": format!("sha256:{:064}", 0),
+                        "size": 0,
+                    });
+
+                    if let Some(platform) = platform {
+                        item["platform"] = platform_json(platform);
+                    }
+
+                    manifests.push(item);
+
+                    continue;
+                }
+            };
+
+            let mut item = serde_json::json!({
+                "mediaType": manifest.media_type.as_str(),
+                "digest": format!("sha256:{}", manifest.digest),
+                "size": manifest.data.len(),
+            });
+
+            if let Some(platform) = platform {
+                item["platform"] = platform_json(platform);
+            }
+
+            manifests.push(item);
+
+            self.add_manifest(&format!("sha256:{}", manifest.digest), manifest);
+        }
+
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MediaType::OciImageIndex.as_str(),
+            "manifests": manifests,
+        });
+
+        Blob::new(
+            MediaType::OciImageIndex,
+            serde_json::to_vec(&index).unwrap(),
+        )
+    }
+
+    fn add_manifest(&mut self, reference: &str, manifest: Blob) {
+        self.manifests.insert(reference.to_owned(), manifest);
+    }
+
     fn run(mut self) {
         let timeout = Duration::from_secs(30);
 
@@ -68,55 +287,42 @@ impl Registry {
 
         let url = request.url();
 
-        // Manifest
-        if url == self.manifest_path {
-            self.manifest(request);
-            return;
-        }
+        // Manifests
+        if let Some(reference) = url.strip_prefix(&self.manifests_prefix) {
+            if let Some(manifest) = self.manifests.get(reference) {
+                let digest = match self.content_digest {
+                    Some(digest) => digest.to_owned(),
+                    None => format!("sha256:{}", manifest.digest),
+                };
 
-        // Blobs
-        if let Some(digest) = url.strip_prefix(&self.blobs_prefix) {
-            if let Some(blob) = self.find_blob(digest) {
-                Self::send_body(request, blob.media_type, blob.data.clone());
+                let response = Self::response(manifest.media_type, manifest.data.clone())
+                    .with_header(Header::from_bytes("Docker-Content-Digest", digest).unwrap());
+
+                request.respond(response).expect("Send response");
             }
-        }
-    }
 
-    fn manifest(&self, request: Request) {
-        #[derive(serde::Serialize, Debug)]
-        struct Image<'a> {
-            config: &'a Blob,
-            layers: &'a [Blob],
+            return;
         }
 
-        Self::send_json(
-            request,
-            MediaType::OciManifestV1,
-            Image {
-                config: &self.config,
-                layers: &self.layers,
-            },
-        );
-    }
+        // Blobs
+        if let Some(digest) = url.strip_prefix(&self.blobs_prefix) {
+            if let Some(blob) = self.blobs.get(digest) {
+                *self
+                    .blob_requests
+                    .lock()
+                    .unwrap()
+                    .entry(blob.digest.clone())
+                    .or_default() += 1;
 
-    fn find_blob(&self, digest: &str) -> Option<&Blob> {
-        if self.config.digest == digest {
-            return Some(&self.config);
+                let response = Self::response(blob.media_type, blob.data.clone());
+                request.respond(response).expect("Send response");
+            }
         }
-
-        self.layers.iter().find(|l| l.digest == digest)
     }
 
-    fn send_json(request: Request, media_type: MediaType, body: impl serde::Serialize) {
-        let body = serde_json::to_vec(&body).expect("Serialize JSON");
-        Self::send_body(request, media_type, body);
-    }
-
-    fn send_body(request: Request, media_type: MediaType, body: impl Into<Vec<u8>>) {
-        let response = Response::from_data(body)
+    fn response(media_type: MediaType, body: impl Into<Vec<u8>>) -> Response<Cursor<Vec<u8>>> {
+        Response::from_data(body)
             .with_status_code(200)
-            .with_header(Header::from_bytes("Content-Type", media_type.as_str()).unwrap());
-
-        request.respond(response).expect("Send response");
+            .with_header(Header::from_bytes("Content-Type", media_type.as_str()).unwrap())
     }
 }
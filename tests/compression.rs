@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use oci_unpack::{
+    errors::UnpackError, Blob as BlobDescriptor, Compression, EventHandler, MediaType, Reference,
+    Unpacker,
+};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Collect the mismatches reported by the unpacker.
+#[derive(Clone, Default)]
+struct Mismatches(Arc<Mutex<Vec<Compression>>>);
+
+impl EventHandler for Mismatches {
+    fn layer_compression_mismatch(&self, _: &BlobDescriptor, detected: Compression) {
+        self.0.lock().unwrap().push(detected);
+    }
+}
+
+/// Build a layer with `actual` compression, and label it as `declared`.
+fn mislabeled(actual: MediaType, declared: MediaType, path: &str) -> Blob {
+    let mut blob = Blob::archive(actual).regular(path, path).build();
+    blob.media_type = declared;
+    blob
+}
+
+fn unpack(
+    repository: &'static str,
+    layers: Vec<Blob>,
+    strict: bool,
+) -> Result<(tempfile::TempDir, Vec<Compression>), UnpackError> {
+    let target = tempfile::tempdir().unwrap();
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+    let port = start_registry(repository, "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    let mismatches = Mismatches::default();
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .event_handler(mismatches.clone())
+        .strict_compression(strict)
+        .unpack(target.path())?;
+
+    let mismatches = mismatches.0.lock().unwrap().clone();
+    Ok((target, mismatches))
+}
+
+#[test]
+fn detect_mislabeled_layers() {
+    #[cfg_attr(not(feature = "zstd"), expect(unused_mut))]
+    let mut layers = vec![
+        mislabeled(MediaType::OciFsTarGzip, MediaType::OciFsTar, "gzip-as-tar"),
+        mislabeled(MediaType::OciFsTar, MediaType::OciFsTarGzip, "tar-as-gzip"),
+        Blob::archive(MediaType::OciFsTarGzip)
+            .regular("gzip", "gzip")
+            .build(),
+    ];
+
+    #[cfg(feature = "zstd")]
+    layers.push(mislabeled(
+        MediaType::OciFsTarZstd,
+        MediaType::OciFsTarGzip,
+        "zstd-as-gzip",
+    ));
+
+    let (target, mismatches) = unpack("foo/mislabeled", layers, false).expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    for name in ["gzip-as-tar", "tar-as-gzip", "gzip"] {
+        assert_eq!(fs::read(rootfs.join(name)).unwrap(), name.as_bytes());
+    }
+
+    #[cfg_attr(not(feature = "zstd"), expect(unused_mut))]
+    let mut expected = vec![Compression::Gzip, Compression::None];
+
+    #[cfg(feature = "zstd")]
+    {
+        assert_eq!(
+            fs::read(rootfs.join("zstd-as-gzip")).unwrap(),
+            b"zstd-as-gzip"
+        );
+        expected.push(Compression::Zstd);
+    }
+
+    assert_eq!(mismatches, expected);
+}
+
+#[test]
+fn reject_mislabeled_layers_in_strict_mode() {
+    let layers = vec![mislabeled(
+        MediaType::OciFsTarGzip,
+        MediaType::OciFsTar,
+        "gzip-as-tar",
+    )];
+
+    let result = unpack("foo/strict", layers, true);
+
+    assert!(matches!(
+        result,
+        Err(UnpackError::CompressionMismatch(_, Compression::Gzip))
+    ));
+}
+
+#[cfg(not(feature = "xz"))]
+#[test]
+fn reject_unsupported_compression() {
+    let layers = vec![Blob::new(MediaType::OciFsTarGzip, &b"\xFD7zXZ\0\0\0"[..])];
+
+    let result = unpack("foo/xz", layers, false);
+
+    assert!(matches!(
+        result,
+        Err(UnpackError::UnsupportedCompression(Compression::Xz))
+    ));
+}
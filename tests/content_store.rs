@@ -0,0 +1,165 @@
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+use sha2::{Digest, Sha256};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+fn unpack(repository: &'static str, layer: Blob, target: &Path, store: &Path, hardlinks: bool) {
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, vec![layer]);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .content_store(store)
+        .content_store_hardlinks(hardlinks)
+        .unpack(target)
+        .expect("Run unpacker");
+}
+
+fn entry_name(data: &str) -> String {
+    format!("sha256_{:x}", Sha256::digest(data))
+}
+
+#[test]
+fn hardlinks() {
+    let store = tempfile::tempdir().unwrap();
+    let first = tempfile::tempdir().unwrap();
+    let second = tempfile::tempdir().unwrap();
+
+    let first_layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "shared")
+        .regular("b", "first")
+        .regular("empty", "")
+        .build();
+
+    let second_layer = Blob::archive(MediaType::OciFsTar)
+        .directory("etc")
+        .regular("etc/a", "shared")
+        .xattrs(&[("user.x", "1")])
+        .regular("etc/x", "shared")
+        .regular("empty", "")
+        .build();
+
+    // The sandbox of the unpacker is applied to the current thread, so
+    // each image is unpacked in its own thread.
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            unpack(
+                "foo/hardlinks-1",
+                first_layer,
+                first.path(),
+                store.path(),
+                true,
+            )
+        })
+        .join()
+        .unwrap()
+    });
+
+    unpack(
+        "foo/hardlinks-2",
+        second_layer,
+        second.path(),
+        store.path(),
+        true,
+    );
+
+    let inode = |path: &Path| fs::symlink_metadata(path).unwrap().ino();
+
+    let stored = inode(&store.path().join(entry_name("shared")));
+    assert_eq!(inode(&first.path().join("rootfs/a")), stored);
+    assert_eq!(inode(&second.path().join("rootfs/etc/a")), stored);
+
+    // Files with xattrs are never linked.
+    assert_ne!(inode(&second.path().join("rootfs/etc/x")), stored);
+    assert_eq!(
+        fs::read_to_string(second.path().join("rootfs/etc/x")).unwrap(),
+        "shared"
+    );
+
+    // Empty files are not stored.
+    assert!(!store.path().join(entry_name("")).exists());
+    assert_ne!(
+        inode(&first.path().join("rootfs/empty")),
+        inode(&second.path().join("rootfs/empty"))
+    );
+}
+
+#[test]
+fn hardlinks_skip_files_with_xattrs() {
+    let store = tempfile::tempdir().unwrap();
+    let first = tempfile::tempdir().unwrap();
+    let second = tempfile::tempdir().unwrap();
+
+    let first_layer = Blob::archive(MediaType::OciFsTar)
+        .xattrs(&[("user.x", "1")])
+        .regular("x", "shared")
+        .build();
+
+    let second_layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "shared")
+        .build();
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            unpack(
+                "foo/hardlinks-xattrs-1",
+                first_layer,
+                first.path(),
+                store.path(),
+                true,
+            )
+        })
+        .join()
+        .unwrap()
+    });
+
+    unpack(
+        "foo/hardlinks-xattrs-2",
+        second_layer,
+        second.path(),
+        store.path(),
+        true,
+    );
+
+    let inode = |path: &Path| fs::symlink_metadata(path).unwrap().ino();
+
+    // The file with xattrs is not added to the store, so the
+    // file in the second image does not get its xattrs.
+    let stored = inode(&store.path().join(entry_name("shared")));
+    assert_ne!(inode(&first.path().join("rootfs/x")), stored);
+    assert_eq!(inode(&second.path().join("rootfs/a")), stored);
+
+    let xattr = rustix::fs::lgetxattr(second.path().join("rootfs/a"), "user.x", &mut [0; 16]);
+    assert_eq!(xattr, Err(rustix::io::Errno::NODATA));
+}
+
+#[test]
+fn reflinks() {
+    let store = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+
+    let layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "shared")
+        .regular("b", "shared")
+        .build();
+
+    unpack("foo/reflinks", layer, target.path(), store.path(), false);
+
+    // Files are never hard-linked, even if the filesystem can't clone
+    // them to the store.
+    for name in ["a", "b"] {
+        let path = target.path().join("rootfs").join(name);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert_eq!(metadata.nlink(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "shared");
+    }
+}
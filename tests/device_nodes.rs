@@ -0,0 +1,186 @@
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use oci_unpack::{
+    DeviceNodePolicy, EventHandler, MediaType, Reference, Repacker, UnpackedImage, Unpacker,
+};
+use rustix::thread::{capabilities, set_capabilities, CapabilityFlags};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Collect the entries reported as skipped by the unpacker.
+#[derive(Clone, Default)]
+struct Skipped(Arc<Mutex<Vec<PathBuf>>>);
+
+impl EventHandler for Skipped {
+    fn layer_entry_skipped(&self, path: &Path, _: &dyn std::fmt::Display) {
+        self.0.lock().unwrap().push(path.to_owned());
+    }
+}
+
+fn unpack(
+    repository: &'static str,
+    target: &Path,
+    policy: DeviceNodePolicy,
+    skipped: Skipped,
+) -> UnpackedImage {
+    let layer = Blob::archive(MediaType::OciFsTar)
+        .directory("dev")
+        .node("dev/null", tar::EntryType::Char, (1, 3))
+        .node("dev/fifo", tar::EntryType::Fifo, (0, 0))
+        .build();
+
+    let config = serde_json::json!({
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [ format!("sha256:{}", layer.digest) ],
+        },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    let port = start_registry(repository, "0.1", config, vec![layer]);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .event_handler(skipped)
+        .device_nodes(policy)
+        .mtree(true)
+        .unpack(target)
+        .expect("Run unpacker")
+}
+
+/// Check if the current thread can create device nodes.
+fn has_cap_mknod() -> bool {
+    capabilities(None)
+        .unwrap()
+        .effective
+        .contains(CapabilityFlags::MKNOD)
+}
+
+/// Remove `CAP_MKNOD` from the current thread, to test the fallbacks.
+fn drop_cap_mknod() {
+    let mut caps = capabilities(None).unwrap();
+    caps.effective.remove(CapabilityFlags::MKNOD);
+    set_capabilities(None, caps).unwrap();
+}
+
+#[test]
+fn create_nodes() {
+    let target = tempfile::tempdir().unwrap();
+    let skipped = Skipped::default();
+
+    unpack(
+        "foo/nodes",
+        target.path(),
+        DeviceNodePolicy::Skip,
+        skipped.clone(),
+    );
+
+    let rootfs = target.path().join("rootfs");
+
+    let fifo = fs::symlink_metadata(rootfs.join("dev/fifo")).unwrap();
+    assert!(fifo.file_type().is_fifo());
+    assert_eq!(fifo.permissions().mode() & 0o7777, 0o666);
+
+    if has_cap_mknod() {
+        let null = fs::symlink_metadata(rootfs.join("dev/null")).unwrap();
+        assert!(null.file_type().is_char_device());
+        assert_eq!(null.rdev(), rustix::fs::makedev(1, 3));
+        assert_eq!(null.permissions().mode() & 0o7777, 0o666);
+        assert!(skipped.0.lock().unwrap().is_empty());
+    }
+}
+
+#[test]
+fn skip_nodes() {
+    drop_cap_mknod();
+
+    let target = tempfile::tempdir().unwrap();
+    let skipped = Skipped::default();
+
+    unpack(
+        "foo/skip-nodes",
+        target.path(),
+        DeviceNodePolicy::Skip,
+        skipped.clone(),
+    );
+
+    assert!(!target.path().join("rootfs/dev/null").exists());
+    assert_eq!(*skipped.0.lock().unwrap(), [PathBuf::from("dev/null")]);
+}
+
+#[test]
+fn placeholder_nodes() {
+    drop_cap_mknod();
+
+    let target = tempfile::tempdir().unwrap();
+
+    let unpacked = unpack(
+        "foo/placeholder-nodes",
+        target.path(),
+        DeviceNodePolicy::Placeholder,
+        Skipped::default(),
+    );
+
+    let null = fs::symlink_metadata(target.path().join("rootfs/dev/null")).unwrap();
+    assert!(null.is_file());
+    assert_eq!(null.len(), 0);
+
+    let mtree = target.path().join(format!(
+        "sha256_{}.mtree",
+        unpacked.manifest_digest.hash_value()
+    ));
+
+    let mtree = fs::read_to_string(mtree).unwrap();
+    assert!(
+        mtree.contains("./dev/null type=char mode=0666 uid=0 gid=0 time=0.000000000 device=native,1,3 optional\n"),
+        "{mtree}"
+    );
+
+    // The placeholder is not a change in the root filesystem.
+    let image = Repacker::new(target.path())
+        .compression(oci_unpack::Compression::None)
+        .repack(target.path().join("layout"))
+        .expect("Run repacker");
+
+    let layer = target
+        .path()
+        .join("layout/blobs/sha256")
+        .join(image.layer_digest.hash_value());
+
+    let entries = tar::Archive::new(fs::File::open(layer).unwrap())
+        .entries()
+        .unwrap()
+        .count();
+
+    assert_eq!(entries, 0);
+}
+
+#[test]
+fn record_nodes() {
+    drop_cap_mknod();
+
+    let target = tempfile::tempdir().unwrap();
+    let skipped = Skipped::default();
+
+    unpack(
+        "foo/record-nodes",
+        target.path(),
+        DeviceNodePolicy::Record,
+        skipped.clone(),
+    );
+
+    assert!(!target.path().join("rootfs/dev/null").exists());
+    assert!(skipped.0.lock().unwrap().is_empty());
+}
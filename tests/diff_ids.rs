@@ -0,0 +1,67 @@
+use std::fs;
+
+use oci_unpack::{errors::UnpackError, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::{Blob, BlobArchive},
+    registry::{self, start_registry},
+};
+
+fn archive(media_type: MediaType) -> BlobArchive {
+    Blob::archive(media_type)
+        .directory("etc")
+        .regular("etc/hostname", "test")
+}
+
+/// Run the unpacker with an image with a single gzip layer. The
+/// configuration has the digests in `diff_ids`.
+fn unpack(repository: &'static str, diff_ids: &[String]) -> Result<(), UnpackError> {
+    let target = tempfile::tempdir().unwrap();
+
+    let config = serde_json::json!({
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": diff_ids,
+        },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+    let layers = vec![archive(MediaType::OciFsTarGzip).build()];
+
+    let port = start_registry(repository, "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .unpack(target.path())?;
+
+    assert_eq!(
+        fs::read(target.path().join("rootfs/etc/hostname")).unwrap(),
+        b"test"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn valid_diff_ids() {
+    let uncompressed = archive(MediaType::OciFsTar).build();
+    unpack("foo/valid", &[format!("sha256:{}", uncompressed.digest)]).expect("Run unpacker");
+}
+
+#[test]
+fn invalid_diff_id() {
+    let result = unpack("foo/invalid", &[format!("sha256:{:064}", 0)]);
+    assert!(matches!(result, Err(UnpackError::InvalidDiffId(..))));
+}
+
+#[test]
+fn diff_ids_count_mismatch() {
+    let uncompressed = archive(MediaType::OciFsTar).build();
+    let diff_id = format!("sha256:{}", uncompressed.digest);
+
+    let result = unpack("foo/count", &[diff_id.clone(), diff_id]);
+    assert!(matches!(result, Err(UnpackError::LayerCountMismatch(1, 2))));
+}
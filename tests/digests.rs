@@ -0,0 +1,106 @@
+use std::io;
+
+use oci_unpack::{errors::UnpackError, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry_with_content_digest, start_registry_with_index, Image},
+};
+
+fn image() -> (Blob, Vec<Blob>) {
+    let config = Blob::new(
+        MediaType::OciConfig,
+        format!(
+            r#"{{"os": "{}", "architecture": "{}"}}"#,
+            registry::OS,
+            registry::ARCH
+        )
+        .into_bytes(),
+    );
+
+    let layers = vec![Blob::archive(MediaType::OciFsTar).regular("a", "0").build()];
+
+    (config, layers)
+}
+
+#[test]
+fn report_manifest_digests() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (config, layers) = image();
+    let config_digest = format!("sha256:{}", config.digest);
+
+    let port = start_registry_with_content_digest("foo/bar", "0.1", config, layers, None);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str()).unwrap();
+
+    let info = Unpacker::new(reference.clone()).inspect().unwrap();
+
+    let unpacked = Unpacker::new(reference)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    assert_eq!(unpacked.index_digest, None);
+    assert_eq!(unpacked.manifest_digest, info.manifest_digest);
+    assert_eq!(unpacked.config_digest.source(), config_digest);
+    assert!(registry::platform().matches(&unpacked.platform));
+    assert_eq!(unpacked.path, target.path());
+}
+
+#[test]
+fn report_index_digest() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (config, layers) = image();
+
+    let images = vec![Image {
+        platform: "linux/amd64",
+        config,
+        layers,
+    }];
+
+    let (port, _) = start_registry_with_index("foo/bar", "0.1", images);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str()).unwrap();
+
+    let info = Unpacker::new(reference.clone())
+        .platform("linux/amd64".parse().unwrap())
+        .inspect()
+        .unwrap();
+
+    let unpacked = Unpacker::new(reference)
+        .platform("linux/amd64".parse().unwrap())
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    assert!(info.index_digest.is_some());
+    assert_eq!(unpacked.index_digest, info.index_digest);
+    assert_eq!(unpacked.manifest_digest, info.manifest_digest);
+    assert_eq!(unpacked.platform.to_string(), "linux/amd64");
+}
+
+#[test]
+fn reject_invalid_content_digest() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (config, layers) = image();
+
+    let content_digest = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+    let port =
+        start_registry_with_content_digest("foo/bar", "0.1", config, layers, Some(content_digest));
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let result = Unpacker::new(reference.unwrap()).unpack(target.path());
+
+    assert!(matches!(
+        result,
+        Err(UnpackError::Io(e, _)) if e.kind() == io::ErrorKind::InvalidData
+    ));
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "encryption")]
+
+use std::fs;
+
+use oci_unpack::{
+    errors::{DecryptionError, UnpackError},
+    DecryptionKey, MediaType, Reference, Unpacker,
+};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Start a registry with an image with the encrypted layer in
+/// `tests/fixtures/encryption`.
+///
+/// Returns the reference to the image.
+fn encrypted_image(repository: &'static str, tamper: bool) -> String {
+    let mut data = include_bytes!("fixtures/encryption/layer.bin").to_vec();
+    if tamper {
+        *data.last_mut().unwrap() ^= 1;
+    }
+
+    let mut encrypted = Blob::new(MediaType::OciFsTarGzipEncrypted, data);
+    encrypted.annotations =
+        serde_json::from_str(include_str!("fixtures/encryption/annotations.json")).unwrap();
+
+    let layers = vec![
+        Blob::archive(MediaType::OciFsTar)
+            .regular("public", "1")
+            .build(),
+        encrypted,
+    ];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, layers);
+
+    format!("127.0.0.1:{port}/{repository}:0.1")
+}
+
+fn key(pem: &str) -> DecryptionKey {
+    DecryptionKey::from_pem(pem).expect("Parse key")
+}
+
+fn unpack(reference: &str, keys: Vec<DecryptionKey>) -> Result<tempfile::TempDir, UnpackError> {
+    let target = tempfile::tempdir().unwrap();
+
+    let mut unpacker =
+        Unpacker::new(Reference::try_from(reference).unwrap()).platform(registry::platform());
+
+    for key in keys {
+        unpacker = unpacker.decryption_key(key);
+    }
+
+    unpacker.unpack(target.path())?;
+
+    Ok(target)
+}
+
+#[test]
+fn decrypt_with_rsa_key() {
+    let reference = encrypted_image("foo/rsa", false);
+
+    let target = unpack(
+        &reference,
+        vec![key(include_str!("fixtures/encryption/rsa.pem"))],
+    )
+    .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    assert_eq!(fs::read(rootfs.join("public")).unwrap(), b"1");
+    assert_eq!(
+        fs::read(rootfs.join("secret/file.txt")).unwrap(),
+        b"encrypted content\n"
+    );
+}
+
+#[test]
+fn decrypt_with_ec_key() {
+    let reference = encrypted_image("foo/ec", false);
+
+    // The first key is not a recipient of the layer.
+    let target = unpack(
+        &reference,
+        vec![
+            key(include_str!("fixtures/encryption/p384.pem")),
+            key(include_str!("fixtures/encryption/p256.pem")),
+        ],
+    )
+    .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    assert_eq!(
+        fs::read(rootfs.join("secret/file.txt")).unwrap(),
+        b"encrypted content\n"
+    );
+}
+
+#[test]
+fn missing_key() {
+    let reference = encrypted_image("foo/missing", false);
+
+    let result = unpack(&reference, vec![]);
+    assert!(matches!(
+        result,
+        Err(UnpackError::Decryption(DecryptionError::NoMatchingKey))
+    ));
+}
+
+#[test]
+fn wrong_key() {
+    let reference = encrypted_image("foo/wrong", false);
+
+    let result = unpack(
+        &reference,
+        vec![key(include_str!("fixtures/encryption/p384.pem"))],
+    );
+
+    assert!(matches!(
+        result,
+        Err(UnpackError::Decryption(DecryptionError::NoMatchingKey))
+    ));
+}
+
+#[test]
+fn invalid_hmac() {
+    let reference = encrypted_image("foo/hmac", true);
+
+    let result = unpack(
+        &reference,
+        vec![key(include_str!("fixtures/encryption/rsa.pem"))],
+    );
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn invalid_key() {
+    assert!(DecryptionKey::from_pem("not a key").is_err());
+}
@@ -0,0 +1,112 @@
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+fn layers() -> Vec<Blob> {
+    let mut base = Blob::archive(MediaType::OciFsTar)
+        .directory("usr")
+        .directory("etc")
+        .regular("etc/removed", "1")
+        .directory("etc/dir")
+        .regular("etc/dir/file", "2")
+        .regular("usr/large", vec![b'x'; 3 * 1024 * 1024]);
+
+    for n in 0..500 {
+        base = base.regular(format!("usr/f{n}"), n.to_string());
+    }
+
+    let top = Blob::archive(MediaType::OciFsTarGzip)
+        .regular("usr/f1", "first")
+        .regular("usr/f1", "second")
+        .regular("usr/new/file", "3")
+        .hardlink("usr/link", "usr/new/file")
+        .regular("etc/.wh.removed", "")
+        .regular("etc/dir", "now a file")
+        .regular("usr/symlink", "")
+        .symlink("usr/symlink", "f2")
+        .regular("usr/new", "replaces a directory")
+        .regular("usr/large", "small")
+        .directory("usr/target")
+        .directory("usr/cached")
+        .regular("usr/cached/a", "4")
+        .regular("usr/cached", "replaces a cached directory")
+        .symlink("usr/cached", "target")
+        .symlink("usr/cached/b", "a")
+        .directory("usr/linked")
+        .regular("usr/linked/a", "6")
+        .symlink("usr/linked", "target")
+        .regular("usr/linked/c", "7")
+        .build();
+
+    vec![base.build(), top]
+}
+
+/// Unpack the image, and return its mtree manifest.
+fn unpack(repository: &'static str, target: &Path, threads: usize) -> String {
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, layers());
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    let image = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .extract_threads(threads)
+        .unpack(target)
+        .expect("Run unpacker");
+
+    let path = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    fs::read_to_string(target.join(path)).unwrap()
+}
+
+#[test]
+fn same_result_with_threads() {
+    let sequential = tempfile::tempdir().unwrap();
+    let parallel = tempfile::tempdir().unwrap();
+
+    // The sandbox of the unpacker is applied to the current thread, so
+    // each image is unpacked in its own thread.
+    let expected = std::thread::scope(|s| {
+        s.spawn(|| unpack("foo/sequential", sequential.path(), 1))
+            .join()
+            .unwrap()
+    });
+
+    let mtree = unpack("foo/parallel", parallel.path(), 4);
+
+    assert_eq!(mtree, expected);
+
+    let rootfs = parallel.path().join("rootfs");
+    let read = |path: &str| fs::read_to_string(rootfs.join(path)).unwrap();
+
+    assert_eq!(read("usr/f0"), "0");
+    assert_eq!(read("usr/f499"), "499");
+    assert_eq!(read("usr/f1"), "second");
+    assert_eq!(read("usr/large"), "small");
+    assert_eq!(read("usr/new"), "replaces a directory");
+    assert_eq!(read("etc/dir"), "now a file");
+    assert_eq!(
+        fs::read_link(rootfs.join("usr/symlink")).unwrap(),
+        Path::new("f2")
+    );
+    assert!(!rootfs.join("etc/removed").exists());
+
+    // Files written through symbolic links that replaced directories.
+    assert_eq!(
+        fs::read_link(rootfs.join("usr/target/b")).unwrap(),
+        Path::new("a")
+    );
+    assert_eq!(read("usr/target/c"), "7");
+    assert!(!rootfs.join("usr/target/a").exists());
+
+    // The target of the hard link was removed after the link was created.
+    assert_eq!(read("usr/link"), "3");
+    assert_eq!(fs::metadata(rootfs.join("usr/link")).unwrap().nlink(), 1);
+}
@@ -0,0 +1,78 @@
+#!/usr/bin/env python3
+#
+# Generate the fixtures for tests/encryption.rs: private keys, and a layer
+# encrypted like ocicrypt does, with its annotations.
+#
+# Requires the `cryptography` package. Run it from this directory.
+
+import base64, gzip, hashlib, hmac, io, json, os, tarfile
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec, padding, rsa
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.keywrap import aes_key_wrap
+
+b64 = lambda d: base64.b64encode(d).decode()
+b64u = lambda d: base64.urlsafe_b64encode(d).rstrip(b"=").decode()
+
+def pem(key, fmt):
+    return key.private_bytes(serialization.Encoding.PEM, fmt, serialization.NoEncryption())
+
+rsa_key = rsa.generate_private_key(65537, 2048)
+ec_key = ec.generate_private_key(ec.SECP256R1())
+other_key = ec.generate_private_key(ec.SECP384R1())
+open("rsa.pem", "wb").write(pem(rsa_key, serialization.PrivateFormat.TraditionalOpenSSL))
+open("p256.pem", "wb").write(pem(ec_key, serialization.PrivateFormat.PKCS8))
+open("p384.pem", "wb").write(pem(other_key, serialization.PrivateFormat.TraditionalOpenSSL))
+
+# Layer
+raw = io.BytesIO()
+with tarfile.open(fileobj=raw, mode="w", format=tarfile.GNU_FORMAT) as tar:
+    data = b"encrypted content\n"
+    info = tarfile.TarInfo("secret/file.txt"); info.size = len(data); info.mode = 0o644
+    d = tarfile.TarInfo("secret"); d.type = tarfile.DIRTYPE; d.mode = 0o755
+    tar.addfile(d); tar.addfile(info, io.BytesIO(data))
+layer = gzip.compress(raw.getvalue(), mtime=0)
+
+symkey, nonce = os.urandom(32), os.urandom(16)
+enc = Cipher(algorithms.AES(symkey), modes.CTR(nonce)).encryptor()
+ciphertext = enc.update(layer) + enc.finalize()
+mac = hmac.new(symkey, ciphertext, hashlib.sha256).digest()
+open("layer.bin", "wb").write(ciphertext)
+
+payload = json.dumps({
+    "symkey": b64(symkey),
+    "digest": "sha256:" + hashlib.sha256(layer).hexdigest(),
+    "cipheroptions": {"nonce": b64(nonce)},
+}).encode()
+
+cek = os.urandom(32)
+protected = b64u(json.dumps({"enc": "A256GCM"}).encode())
+iv = os.urandom(12)
+ct_tag = AESGCM(cek).encrypt(iv, payload, protected.encode())
+
+recipients = [{
+    "header": {"alg": "RSA-OAEP"},
+    "encrypted_key": b64u(rsa_key.public_key().encrypt(cek, padding.OAEP(padding.MGF1(hashes.SHA1()), hashes.SHA1(), None))),
+}]
+
+eph = ec.generate_private_key(ec.SECP256R1())
+shared = eph.exchange(ec.ECDH(), ec_key.public_key())
+alg = b"ECDH-ES+A256KW"
+otherinfo = len(alg).to_bytes(4, "big") + alg + (0).to_bytes(4, "big") + (0).to_bytes(4, "big") + (256).to_bytes(4, "big")
+kek = hashlib.sha256((1).to_bytes(4, "big") + shared + otherinfo).digest()
+nums = eph.public_key().public_numbers()
+recipients.append({
+    "header": {"alg": "ECDH-ES+A256KW", "epk": {"kty": "EC", "crv": "P-256",
+        "x": b64u(nums.x.to_bytes(32, "big")), "y": b64u(nums.y.to_bytes(32, "big"))}},
+    "encrypted_key": b64u(aes_key_wrap(kek, cek)),
+})
+
+jwe = {"protected": protected, "recipients": recipients, "iv": b64u(iv),
+       "ciphertext": b64u(ct_tag[:-16]), "tag": b64u(ct_tag[-16:])}
+
+annotations = {
+    "org.opencontainers.image.enc.keys.jwe": b64(json.dumps(jwe).encode()),
+    "org.opencontainers.image.enc.pubopts": b64(json.dumps({"cipher": "AES_256_CTR_HMAC_SHA256", "hmac": b64(mac), "cipheroptions": {}}).encode()),
+}
+json.dump(annotations, open("annotations.json", "w"), indent=2)
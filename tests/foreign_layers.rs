@@ -0,0 +1,155 @@
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+};
+
+use oci_unpack::{
+    errors::UnpackError, EventHandler, ForeignLayerPolicy, MediaType, Reference, Unpacker,
+};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_file_server, start_registry},
+};
+
+/// Start a registry with an image with a foreign layer, which is
+/// served from an external server.
+///
+/// Returns the reference to the image.
+fn foreign_image(repository: &'static str) -> String {
+    foreign_image_with_urls(repository, &[])
+}
+
+/// Like [`foreign_image`], with `extra_urls` before the URLs of the
+/// external server.
+fn foreign_image_with_urls(repository: &'static str, extra_urls: &[&str]) -> String {
+    let mut foreign = Blob::archive(MediaType::DockerForeignFsTarGzip)
+        .regular("foreign", "1")
+        .build();
+
+    let path = format!("/layers/{}", foreign.digest);
+    let files_port = start_file_server(vec![(path.clone(), foreign.data.clone())]);
+
+    foreign.urls = extra_urls.iter().map(|u| u.to_string()).collect();
+    foreign.urls.extend([
+        format!("http://127.0.0.1:{files_port}/missing"),
+        format!("http://127.0.0.1:{files_port}{path}"),
+    ]);
+
+    let layers = vec![
+        foreign,
+        Blob::archive(MediaType::OciFsTar)
+            .regular("local", "2")
+            .build(),
+    ];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, layers);
+
+    format!("127.0.0.1:{port}/{repository}:0.1")
+}
+
+#[test]
+fn download_foreign_layers() {
+    let target = tempfile::tempdir().unwrap();
+
+    let reference = foreign_image("foo/download");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .foreign_layers(ForeignLayerPolicy::Download)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    assert_eq!(fs::read(rootfs.join("foreign")).unwrap(), b"1");
+    assert_eq!(fs::read(rootfs.join("local")).unwrap(), b"2");
+}
+
+#[test]
+fn skip_foreign_layers() {
+    let target = tempfile::tempdir().unwrap();
+
+    let reference = foreign_image("foo/skip");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .foreign_layers(ForeignLayerPolicy::Skip)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    assert!(!rootfs.join("foreign").exists());
+    assert_eq!(fs::read(rootfs.join("local")).unwrap(), b"2");
+}
+
+#[test]
+fn reject_foreign_layers() {
+    let target = tempfile::tempdir().unwrap();
+
+    let reference = foreign_image("foo/reject");
+
+    let result = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .unpack(target.path());
+
+    assert!(matches!(result, Err(UnpackError::ForeignLayer(_))));
+    assert!(fs::read_dir(target.path()).unwrap().next().is_none());
+}
+
+/// Record the requests to external URLs.
+#[derive(Clone, Default)]
+struct ExternalRequests(Arc<Mutex<Vec<String>>>);
+
+impl EventHandler for ExternalRequests {
+    fn external_request(&self, url: &str) {
+        self.0.lock().unwrap().push(url.to_owned());
+    }
+}
+
+/// Unpack an image with a foreign layer, and return the external URLs
+/// requested by the unpacker.
+///
+/// The first URL of the layer uses the `file:` scheme. The layer is not
+/// in the registry, so the unpacker fails if no URL can be used.
+fn foreign_layer_requests(repository: &'static str, hosts: &[&str]) -> (bool, Vec<String>) {
+    let target = tempfile::tempdir().unwrap();
+
+    let reference = foreign_image_with_urls(repository, &["file:///etc/hostname"]);
+    let handler = ExternalRequests::default();
+
+    let result = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .foreign_layers(ForeignLayerPolicy::Download)
+        .foreign_layer_hosts(hosts.iter().copied())
+        .event_handler(handler.clone())
+        .unpack(target.path());
+
+    let requests = handler.0.lock().unwrap().clone();
+    (result.is_ok(), requests)
+}
+
+#[test]
+fn ignore_non_http_urls() {
+    let (unpacked, requests) = foreign_layer_requests("foo/schemes", &[]);
+    assert!(unpacked);
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|u| u.starts_with("http://127.0.0.1:")));
+}
+
+#[test]
+fn allowed_foreign_layer_hosts() {
+    let (unpacked, requests) = foreign_layer_requests("foo/allowed", &["127.0.0.1"]);
+    assert!(unpacked);
+    assert_eq!(requests.len(), 2);
+}
+
+#[test]
+fn denied_foreign_layer_hosts() {
+    let (unpacked, requests) = foreign_layer_requests("foo/denied", &["example.com"]);
+    assert!(!unpacked);
+    assert!(requests.is_empty(), "{requests:?}");
+}
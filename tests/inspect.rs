@@ -0,0 +1,69 @@
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{start_registry_with_index, Image},
+};
+
+#[test]
+fn inspect_without_layers() {
+    let config = r#"{
+        "architecture": "arm",
+        "variant": "v7",
+        "os": "linux",
+        "config": {
+            "Env": ["PATH=/bin"],
+            "Entrypoint": ["/bin/app"],
+            "Cmd": ["--help"],
+            "User": "app",
+            "Labels": { "version": "1.2.3" }
+        },
+        "rootfs": { "type": "layers", "diff_ids": [] }
+    }"#;
+
+    let images = ["linux/amd64", "linux/arm/v7"]
+        .into_iter()
+        .map(|platform| Image {
+            platform,
+            config: Blob::new(MediaType::OciConfig, config.as_bytes()),
+            layers: vec![Blob::archive(MediaType::OciFsTarGzip)
+                .regular(platform, "")
+                .build()],
+        })
+        .collect();
+
+    let (port, blob_requests) = start_registry_with_index("foo/bar", "0.1", images);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let info = Unpacker::new(reference.unwrap())
+        .platform("linux/arm/v7".parse().unwrap())
+        .inspect()
+        .expect("Inspect image");
+
+    let index = info.index.expect("Index");
+    assert_eq!(index.manifests.len(), 2);
+    assert_eq!(
+        index.manifests[1].platform.as_ref().unwrap().to_string(),
+        "linux/arm/v7"
+    );
+    assert_eq!(info.platform.unwrap().to_string(), "linux/arm/v7");
+
+    assert_eq!(info.manifest.layers.len(), 1);
+    assert_eq!(info.manifest.layers[0].media_type, MediaType::OciFsTarGzip);
+
+    assert_eq!(info.config.variant.as_deref(), Some("v7"));
+    assert_eq!(info.config.config.env, ["PATH=/bin"]);
+    assert_eq!(info.config.config.entrypoint.unwrap(), ["/bin/app"]);
+    assert_eq!(info.config.config.cmd.unwrap(), ["--help"]);
+    assert_eq!(info.config.config.user.as_deref(), Some("app"));
+    assert_eq!(info.config.config.labels["version"], "1.2.3");
+
+    // Only the configuration is downloaded.
+    let blob_requests = blob_requests.lock().unwrap();
+    assert_eq!(blob_requests.len(), 1);
+    assert!(blob_requests.contains_key(&info.manifest.config.digest.hash_value().to_string()));
+}
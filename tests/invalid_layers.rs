@@ -19,8 +19,7 @@ fn run_test(layers: Vec<Blob>) {
     let reference = Reference::try_from(reference.as_str());
 
     let result = Unpacker::new(reference.unwrap())
-        .architecture(registry::ARCH)
-        .os(registry::OS)
+        .platform(registry::platform())
         .unpack(target.path());
 
     assert!(result.is_err())
@@ -53,7 +52,10 @@ fn whiteout_current_dir() {
 #[cfg(not(feature = "zstd"))]
 #[test]
 fn reject_zstd_layers() {
-    run_test(vec![Blob::archive(MediaType::OciFsTarZstd)
-        .regular("a", "b")
-        .build()]);
+    // Without the feature, the archive builder can't compress the
+    // layer, so it only contains the zstd magic number.
+    run_test(vec![Blob::new(
+        MediaType::OciFsTarZstd,
+        &b"\x28\xB5\x2F\xFD\0\0\0\0"[..],
+    )]);
 }
@@ -0,0 +1,56 @@
+use oci_unpack::{errors::UnpackError, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+fn run_test(layer_size_delta: isize, max_document_size: Option<usize>) -> Result<(), UnpackError> {
+    let target = tempfile::tempdir().unwrap();
+
+    let mut layer = Blob::archive(MediaType::OciFsTarGzip)
+        .regular("a", "0123456789")
+        .build();
+
+    layer.size = layer.size.checked_add_signed(layer_size_delta).unwrap();
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/bar", "0.1", config, vec![layer]);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let mut unpacker = Unpacker::new(reference.unwrap()).platform(registry::platform());
+
+    if let Some(size) = max_document_size {
+        unpacker = unpacker.max_document_size(size);
+    }
+
+    unpacker.unpack(target.path()).map(|_| ())
+}
+
+#[test]
+fn valid_sizes() {
+    run_test(0, None).expect("Run unpacker");
+}
+
+#[test]
+fn layer_larger_than_descriptor() {
+    let result = run_test(-1, None);
+    assert!(matches!(result, Err(UnpackError::InvalidBlobSize(_))));
+}
+
+#[test]
+fn layer_shorter_than_descriptor() {
+    let result = run_test(1, None);
+    assert!(matches!(result, Err(UnpackError::InvalidBlobSize(_))));
+}
+
+#[test]
+fn manifest_too_large() {
+    let result = run_test(0, Some(100));
+    assert!(matches!(result, Err(UnpackError::DocumentTooLarge(_))));
+}
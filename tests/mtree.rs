@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+};
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+#[test]
+fn write_mtree_manifest() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layers = vec![
+        Blob::archive(MediaType::OciFsTar)
+            .directory("etc")
+            .regular("etc/hostname", "abc")
+            .regular("etc/removed", "1")
+            .directory("old")
+            .regular("old/file", "2")
+            .symlink("etc/link", "hostname")
+            .regular("implicit/dir/file", "")
+            .build(),
+        Blob::archive(MediaType::OciFsTarGzip)
+            .regular("etc/.wh.removed", "")
+            .regular("old", "now a file")
+            .hardlink("etc/hardlink", "etc/hostname")
+            .build(),
+    ];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/mtree", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/mtree:0.1");
+
+    let image = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let path = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    let mtree = fs::read_to_string(target.path().join(path)).unwrap();
+
+    let lines: Vec<_> = mtree.lines().collect();
+
+    // SHA256 of `abc`.
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    let file = |path: &str| {
+        lines
+            .iter()
+            .find(|l| l.split(' ').next() == Some(path))
+            .unwrap_or_else(|| panic!("Missing {path} in {mtree}"))
+    };
+
+    assert_eq!(lines[0], "#mtree");
+
+    assert_eq!(
+        *file("./etc/hostname"),
+        format!("./etc/hostname type=file mode=0644 uid=0 gid=0 size=3 time=0.000000000 sha256digest={ABC}")
+    );
+
+    assert_eq!(
+        *file("./etc/hardlink"),
+        format!("./etc/hardlink type=file mode=0644 uid=0 gid=0 size=3 time=0.000000000 sha256digest={ABC}")
+    );
+
+    assert_eq!(
+        *file("./etc/link"),
+        "./etc/link type=link mode=0755 uid=0 gid=0 time=0.000000000 link=hostname"
+    );
+
+    assert!(file("./etc").contains(" type=dir mode=0755 "));
+    assert!(file("./implicit").contains(" type=dir "));
+    assert!(file("./implicit/dir").contains(" type=dir "));
+    assert!(file("./old").contains(" type=file "));
+
+    assert!(!mtree.contains("./etc/removed"));
+    assert!(!mtree.contains("./old/file"));
+
+    assert_eq!(lines.len(), 9);
+}
+
+#[test]
+fn owner_of_implicit_directories() {
+    let target = tempfile::tempdir().unwrap();
+
+    // Directories created in a setgid directory inherit its group, so
+    // the owner in the host is not the owner in the image. Without
+    // privileges, that group is the one of the current user.
+    if rustix::process::geteuid().is_root() {
+        std::os::unix::fs::chown(target.path(), None, Some(1234)).unwrap();
+    }
+    fs::set_permissions(target.path(), fs::Permissions::from_mode(0o2755)).unwrap();
+
+    let layers = vec![Blob::archive(MediaType::OciFsTar)
+        .regular("implicit/file", "")
+        .build()];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/mtree-implicit", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/mtree-implicit:0.1");
+
+    let image = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let implicit = target.path().join("rootfs/implicit");
+    assert_ne!(fs::metadata(implicit).unwrap().gid(), 0);
+
+    let path = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    let mtree = fs::read_to_string(target.path().join(path)).unwrap();
+
+    let line = mtree
+        .lines()
+        .find(|l| l.starts_with("./implicit "))
+        .unwrap_or_else(|| panic!("Missing ./implicit in {mtree}"));
+
+    assert!(line.contains(" uid=0 gid=0 "), "{line}");
+}
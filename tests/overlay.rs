@@ -0,0 +1,198 @@
+use std::{
+    fs,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use oci_unpack::{errors::UnpackError, EventHandler, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Collect the number of layers to download.
+#[derive(Clone, Default)]
+struct Downloads(Arc<Mutex<Vec<usize>>>);
+
+impl EventHandler for Downloads {
+    fn download_start(&self, layers: usize, _: usize) {
+        self.0.lock().unwrap().push(layers);
+    }
+}
+
+fn base_layer() -> Blob {
+    Blob::archive(MediaType::OciFsTar)
+        .directory("etc")
+        .regular("etc/hostname", "abc")
+        .regular("etc/removed", "1")
+        .directory("cache")
+        .regular("cache/a", "")
+        .build()
+}
+
+fn config(layers: &[&Blob]) -> Blob {
+    let diff_ids: Vec<_> = layers
+        .iter()
+        .map(|l| format!("sha256:{}", l.digest))
+        .collect();
+
+    let config = serde_json::json!({
+        "rootfs": { "type": "layers", "diff_ids": diff_ids },
+    });
+
+    Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap())
+}
+
+fn unpack(
+    repository: &'static str,
+    layers: Vec<Blob>,
+    target: &Path,
+    store: &Path,
+    downloads: Downloads,
+) -> Vec<std::path::PathBuf> {
+    let config = config(&layers.iter().collect::<Vec<_>>());
+
+    let port = start_registry(repository, "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .event_handler(downloads)
+        .overlay_layers(store)
+        .overlay_userxattr(true)
+        .unpack(target)
+        .expect("Run unpacker")
+        .lower_dirs
+}
+
+#[test]
+fn overlay_layers() {
+    let store = tempfile::tempdir().unwrap();
+    let downloads = Downloads::default();
+
+    let base = base_layer();
+    let top = Blob::archive(MediaType::OciFsTar)
+        .regular("etc/.wh.removed", "")
+        .regular("cache/.wh..wh..opq", "")
+        .regular("cache/b", "")
+        .build();
+
+    let base_dir = store.path().join(format!("sha256_{}", base.digest));
+    let top_dir = store.path().join(format!("sha256_{}", top.digest));
+
+    // The sandbox of the unpacker is applied to the current thread, so
+    // each image is unpacked in its own thread.
+    let target = tempfile::tempdir().unwrap();
+    let lower_dirs = std::thread::scope(|s| {
+        s.spawn(|| {
+            unpack(
+                "foo/overlay",
+                vec![base, top],
+                target.path(),
+                store.path(),
+                downloads.clone(),
+            )
+        })
+        .join()
+        .unwrap()
+    });
+
+    assert_eq!(lower_dirs, [top_dir.clone(), base_dir.clone()]);
+    assert!(!target.path().join("rootfs").exists());
+
+    assert_eq!(
+        fs::read_to_string(base_dir.join("etc/hostname")).unwrap(),
+        "abc"
+    );
+
+    // Whiteouts are converted for overlayfs.
+    let whiteout = fs::symlink_metadata(top_dir.join("etc/removed")).unwrap();
+    assert!(whiteout.file_type().is_char_device());
+    assert_eq!(whiteout.rdev(), 0);
+
+    assert!(!top_dir.join("cache/.wh..wh..opq").exists());
+    assert!(top_dir.join("cache/b").exists());
+
+    let mut value = [0; 8];
+    let len = rustix::fs::lgetxattr(top_dir.join("cache"), "user.overlay.opaque", &mut value)
+        .expect("Read opaque xattr");
+    assert_eq!(&value[..len], b"y");
+
+    // Layers in the store are reused by other images.
+    let target = tempfile::tempdir().unwrap();
+    let other = Blob::archive(MediaType::OciFsTar)
+        .regular("etc/other", "2")
+        .build();
+
+    let other_dir = store.path().join(format!("sha256_{}", other.digest));
+
+    let lower_dirs = unpack(
+        "foo/overlay-reuse",
+        vec![base_layer(), other],
+        target.path(),
+        store.path(),
+        downloads.clone(),
+    );
+
+    assert_eq!(lower_dirs, [other_dir, base_dir]);
+    assert_eq!(*downloads.0.lock().unwrap(), [2, 1]);
+}
+
+#[test]
+fn whiteouts_in_same_layer() {
+    let store = tempfile::tempdir().unwrap();
+
+    let base = base_layer();
+    let top = Blob::archive(MediaType::OciFsTar)
+        .regular("etc/hostname", "def")
+        .regular("etc/.wh.hostname", "")
+        .regular("cache/b", "")
+        .regular(".wh.cache", "")
+        .node("etc/removed", tar::EntryType::Char, (0, 0))
+        .build();
+
+    let top_dir = store.path().join(format!("sha256_{}", top.digest));
+
+    let target = tempfile::tempdir().unwrap();
+    unpack(
+        "foo/overlay-whiteouts",
+        vec![base, top],
+        target.path(),
+        store.path(),
+        Downloads::default(),
+    );
+
+    // The whiteout does not replace the file from the same layer.
+    assert_eq!(
+        fs::read_to_string(top_dir.join("etc/hostname")).unwrap(),
+        "def"
+    );
+
+    // A directory from the same layer hides the lower one.
+    assert!(top_dir.join("cache/b").exists());
+
+    let mut value = [0; 8];
+    let len = rustix::fs::lgetxattr(top_dir.join("cache"), "user.overlay.opaque", &mut value)
+        .expect("Read opaque xattr");
+    assert_eq!(&value[..len], b"y");
+
+    let whiteout = fs::symlink_metadata(top_dir.join("etc/removed")).unwrap();
+    assert!(whiteout.file_type().is_char_device());
+    assert_eq!(whiteout.rdev(), 0);
+}
+
+#[test]
+fn reject_mtree() {
+    let target = tempfile::tempdir().unwrap();
+
+    let result = Unpacker::new(Reference::try_from("localhost/foo:1").unwrap())
+        .overlay_layers(target.path().join("layers"))
+        .mtree(true)
+        .unpack(target.path());
+
+    assert!(matches!(result, Err(UnpackError::InvalidOptions(_))));
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+};
+
+use oci_unpack::{errors::UnpackError, IdMapping, MediaType, NoEventHandler, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+fn unpack(
+    repository: &'static str,
+    target: &std::path::Path,
+    configure: impl FnOnce(Unpacker<NoEventHandler>) -> Unpacker<NoEventHandler>,
+) -> Result<(), UnpackError> {
+    let layers = vec![Blob::archive(MediaType::OciFsTar)
+        .directory("etc")
+        .regular("etc/hostname", "root")
+        .owner(1000, 100)
+        .directory("home")
+        .regular("home/file", "app")
+        .symlink("home/link", "file")
+        .build()];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    let unpacker = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform());
+
+    configure(unpacker).unpack(target)?;
+
+    Ok(())
+}
+
+#[test]
+fn map_owners() {
+    let target = tempfile::tempdir().unwrap();
+
+    let uid = rustix::process::geteuid().as_raw();
+    let gid = rustix::process::getegid().as_raw();
+
+    // Without privileges, files can only be owned by the current user.
+    let (root_uid, root_gid, app_uid, app_gid) = match uid {
+        0 => (100000, 100000, 101000, 100100),
+        _ => (uid, gid, uid, gid),
+    };
+
+    let uid_map = vec![
+        IdMapping::new(0, root_uid, 1),
+        IdMapping::new(1000, app_uid, 1),
+    ];
+
+    let gid_map = vec![
+        IdMapping::new(0, root_gid, 1),
+        IdMapping::new(100, app_gid, 1),
+    ];
+
+    unpack("foo/idmap", target.path(), |u| {
+        u.uid_map(uid_map).gid_map(gid_map).require_chown(true)
+    })
+    .expect("Run unpacker");
+
+    let owner = |path| {
+        let metadata = fs::symlink_metadata(target.path().join("rootfs").join(path)).unwrap();
+        (metadata.uid(), metadata.gid())
+    };
+
+    assert_eq!(owner("etc"), (root_uid, root_gid));
+    assert_eq!(owner("etc/hostname"), (root_uid, root_gid));
+
+    assert_eq!(owner("home"), (app_uid, app_gid));
+    assert_eq!(owner("home/file"), (app_uid, app_gid));
+    assert_eq!(owner("home/link"), (app_uid, app_gid));
+}
+
+#[test]
+fn setgid_directories() {
+    let target = tempfile::tempdir().unwrap();
+
+    // New files in a setgid directory inherit its group, so it has to be
+    // changed even if the group in the image is the one of the process.
+    let gid = rustix::process::getegid().as_raw();
+    if rustix::process::geteuid().is_root() {
+        std::os::unix::fs::chown(target.path(), None, Some(gid + 1234)).unwrap();
+    }
+    fs::set_permissions(target.path(), fs::Permissions::from_mode(0o2755)).unwrap();
+
+    let layers = vec![Blob::archive(MediaType::OciFsTar)
+        .owner(0, 0)
+        .regular("file", "")
+        .build()];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/setgid", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/setgid:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .gid_map([IdMapping::new(0, gid, 1)])
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let metadata = fs::metadata(target.path().join("rootfs/file")).unwrap();
+    assert_eq!(metadata.gid(), gid);
+}
+
+#[test]
+fn reject_unmapped_ids() {
+    let target = tempfile::tempdir().unwrap();
+
+    let uid = rustix::process::geteuid().as_raw();
+    let uid_map = vec![IdMapping::new(0, uid, 1)];
+
+    let result = unpack("foo/unmapped", target.path(), |u| u.uid_map(uid_map));
+    match result {
+        Err(UnpackError::Io(e, _)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+        _ => panic!("Unexpected result: {result:?}"),
+    }
+}
+
+#[test]
+fn rootless_xattr() {
+    let target = tempfile::tempdir().unwrap();
+
+    unpack("foo/rootless", target.path(), |u| u.rootless_xattr(true)).expect("Run unpacker");
+
+    let xattr = |path| {
+        let path = target.path().join("rootfs").join(path);
+        let mut value = [0; 32];
+        rustix::fs::lgetxattr(&path, "user.rootlesscontainers", &mut value)
+            .map(|len| value[..len].to_vec())
+            .ok()
+    };
+
+    // uid = 1000, gid = 100
+    let resource = b"\x08\xe8\x07\x10\x64".to_vec();
+
+    assert_eq!(xattr("etc"), None);
+    assert_eq!(xattr("etc/hostname"), None);
+    assert_eq!(xattr("home"), Some(resource.clone()));
+    assert_eq!(xattr("home/file"), Some(resource));
+
+    // Files are not owned by the IDs in the image.
+    let metadata = fs::metadata(target.path().join("rootfs/home/file")).unwrap();
+    assert_eq!(metadata.uid(), rustix::process::geteuid().as_raw());
+}
@@ -0,0 +1,270 @@
+use std::fs;
+
+use oci_unpack::{errors::UnpackError, MediaType, Platform, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{start_registry_with_entries, start_registry_with_index, Entry, Image},
+};
+
+fn images() -> Vec<Image> {
+    let shared = || {
+        Blob::archive(MediaType::OciFsTarGzip)
+            .regular("shared", "base")
+            .build()
+    };
+
+    let image = |platform, name: &str| Image {
+        platform,
+        config: Blob::new(
+            MediaType::OciConfig,
+            format!(r#"{{"name": "{name}"}}"#).into_bytes(),
+        ),
+        layers: vec![
+            shared(),
+            Blob::archive(MediaType::OciFsTar)
+                .regular("name", name)
+                .build(),
+        ],
+    };
+
+    vec![
+        image("linux/amd64", "amd64"),
+        image("linux/arm/v6", "armv6"),
+        image("linux/arm/v7", "armv7"),
+        image("linux/arm64/v8", "arm64"),
+    ]
+}
+
+#[test]
+fn select_platform_variant() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (port, _) = start_registry_with_index("foo/bar", "0.1", images());
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    Unpacker::new(reference.unwrap())
+        .platform("linux/riscv64".parse().unwrap())
+        .platform("linux/arm".parse().unwrap())
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    assert_eq!(
+        fs::read(target.path().join("rootfs/name")).unwrap(),
+        b"armv7"
+    );
+}
+
+#[test]
+fn unpack_all_platforms() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (port, blob_requests) = start_registry_with_index("foo/bar", "0.1", images());
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let unpacked = Unpacker::new(reference.unwrap())
+        .unpack_platforms(target.path())
+        .expect("Run unpacker");
+
+    let found: Vec<_> = unpacked
+        .iter()
+        .map(|u| (u.platform.to_string(), u.path.clone()))
+        .collect();
+
+    let dir = |name| target.path().join(name);
+
+    assert_eq!(
+        found,
+        [
+            ("linux/amd64".to_string(), dir("linux-amd64")),
+            ("linux/arm/v6".to_string(), dir("linux-arm-v6")),
+            ("linux/arm/v7".to_string(), dir("linux-arm-v7")),
+            ("linux/arm64/v8".to_string(), dir("linux-arm64")),
+        ]
+    );
+
+    for (name, content) in [
+        ("linux-amd64", "amd64"),
+        ("linux-arm-v6", "armv6"),
+        ("linux-arm-v7", "armv7"),
+        ("linux-arm64", "arm64"),
+    ] {
+        let rootfs = dir(name).join("rootfs");
+        assert_eq!(fs::read(rootfs.join("shared")).unwrap(), b"base");
+        assert_eq!(fs::read(rootfs.join("name")).unwrap(), content.as_bytes());
+    }
+
+    // The shared layer must be downloaded only once.
+    assert!(blob_requests.lock().unwrap().values().all(|n| *n == 1));
+}
+
+#[test]
+fn unpack_filtered_platforms() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (port, _) = start_registry_with_index("foo/bar", "0.1", images());
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let unpacked = Unpacker::new(reference.unwrap())
+        .platform(Platform::new("linux", "amd64"))
+        .platform(Platform::new("linux", "aarch64"))
+        .unpack_platforms(target.path())
+        .expect("Run unpacker");
+
+    let mut dirs: Vec<_> = fs::read_dir(target.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+
+    dirs.sort();
+
+    assert_eq!(unpacked.len(), 2);
+    assert_eq!(dirs, ["linux-amd64", "linux-arm64"]);
+}
+
+#[test]
+fn unpack_duplicated_platforms() {
+    let target = tempfile::tempdir().unwrap();
+
+    // Add a second amd64 image, and an arm64 image without variant.
+    let mut entries = images();
+    let mut copies = images().into_iter();
+    let amd64 = copies.next().unwrap();
+    let arm64 = copies.nth(2).unwrap();
+    entries.push(Image {
+        platform: "linux/arm64",
+        ..arm64
+    });
+    entries.push(amd64);
+
+    let (port, _) = start_registry_with_index("foo/bar", "0.1", entries);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let unpacked = Unpacker::new(reference.unwrap())
+        .unpack_platforms(target.path())
+        .expect("Run unpacker");
+
+    let found: Vec<_> = unpacked.iter().map(|u| u.platform.to_string()).collect();
+    assert_eq!(
+        found,
+        [
+            "linux/amd64",
+            "linux/arm/v6",
+            "linux/arm/v7",
+            "linux/arm64/v8"
+        ]
+    );
+}
+
+fn nested_entries() -> Vec<Entry> {
+    let mut images = images().into_iter();
+    let amd64 = images.next().unwrap();
+    let armv6 = images.next().unwrap();
+    let armv7 = images.next().unwrap();
+
+    vec![
+        Entry::Artifact(None),
+        Entry::Image(amd64),
+        Entry::Index(
+            Some("linux/arm"),
+            vec![Entry::Index(
+                None,
+                vec![Entry::Image(armv6), Entry::Artifact(None)],
+            )],
+        ),
+        Entry::Index(None, vec![Entry::Image(armv7)]),
+    ]
+}
+
+#[test]
+fn unpack_nested_indexes() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (port, _) = start_registry_with_entries("foo/bar", "0.1", nested_entries());
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let unpacked = Unpacker::new(reference.unwrap())
+        .unpack_platforms(target.path())
+        .expect("Run unpacker");
+
+    let found: Vec<_> = unpacked.iter().map(|u| u.platform.to_string()).collect();
+    assert_eq!(found, ["linux/amd64", "linux/arm/v6", "linux/arm/v7"]);
+}
+
+#[test]
+fn select_platform_in_nested_index() {
+    let target = tempfile::tempdir().unwrap();
+
+    let (port, _) = start_registry_with_entries("foo/bar", "0.1", nested_entries());
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    Unpacker::new(reference.unwrap())
+        .platform("linux/arm/v6".parse().unwrap())
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    assert_eq!(
+        fs::read(target.path().join("rootfs/name")).unwrap(),
+        b"armv6"
+    );
+}
+
+#[test]
+fn skip_unknown_media_types() {
+    let target = tempfile::tempdir().unwrap();
+
+    let image = images().into_iter().next().unwrap();
+    let entries = vec![Entry::Artifact(Some("linux/amd64")), Entry::Image(image)];
+
+    let (port, _) = start_registry_with_entries("foo/bar", "0.1", entries);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    Unpacker::new(reference.unwrap())
+        .platform(Platform::new("linux", "amd64"))
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    assert_eq!(
+        fs::read(target.path().join("rootfs/name")).unwrap(),
+        b"amd64"
+    );
+}
+
+#[test]
+fn deep_nested_indexes() {
+    let target = tempfile::tempdir().unwrap();
+
+    let image = images().into_iter().next().unwrap();
+
+    let mut entries = vec![Entry::Image(image)];
+    for _ in 0..10 {
+        entries = vec![Entry::Index(None, entries)];
+    }
+
+    let (port, _) = start_registry_with_entries("foo/bar", "0.1", entries);
+
+    let reference = format!("127.0.0.1:{port}/foo/bar:0.1");
+    let reference = Reference::try_from(reference.as_str());
+
+    let result = Unpacker::new(reference.unwrap())
+        .platform(Platform::new("linux", "amd64"))
+        .unpack(target.path());
+
+    assert!(matches!(result, Err(UnpackError::IndexTooDeep)));
+}
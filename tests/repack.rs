@@ -0,0 +1,329 @@
+use std::{fs, io::Read, path::Path};
+
+use oci_unpack::{errors::RepackError, MediaType, Reference, Repacker, Unpacker};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+fn read_json(path: impl AsRef<Path>) -> Value {
+    serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+}
+
+/// Return the path of a blob in the layout.
+fn blob_path(layout: &Path, digest: &str) -> std::path::PathBuf {
+    layout
+        .join("blobs/sha256")
+        .join(digest.strip_prefix("sha256:").unwrap())
+}
+
+/// Return the names of the entries in a layer compressed with gzip,
+/// and the digest of the uncompressed archive.
+fn read_layer(path: &Path) -> (Vec<String>, String) {
+    let mut data = Vec::new();
+    flate2::read::GzDecoder::new(fs::File::open(path).unwrap())
+        .read_to_end(&mut data)
+        .unwrap();
+
+    let names = tar::Archive::new(&data[..])
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+
+    let diff_id = format!("sha256:{:x}", Sha256::digest(&data));
+
+    (names, diff_id)
+}
+
+/// Check that every blob referenced by the manifest is in the layout.
+fn assert_complete_layout(layout: &Path, manifest_digest: &str) {
+    let manifest = read_json(blob_path(layout, manifest_digest));
+
+    let layers = manifest["layers"].as_array().unwrap();
+    for descriptor in layers.iter().chain([&manifest["config"]]) {
+        let data = fs::read(blob_path(layout, descriptor["digest"].as_str().unwrap())).unwrap();
+        assert_eq!(descriptor["size"], data.len());
+        assert_eq!(
+            descriptor["digest"],
+            format!("sha256:{:x}", Sha256::digest(&data))
+        );
+    }
+}
+
+#[test]
+fn repack_changes() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layer = Blob::archive(MediaType::OciFsTar)
+        .directory("etc")
+        .regular("etc/hostname", "abc")
+        .regular("etc/keep", "1")
+        .regular("etc/removed", "2")
+        .directory("cache")
+        .regular("cache/a", "")
+        .regular("cache/b", "")
+        .directory("old")
+        .regular("old/file", "3")
+        .build();
+
+    let config = serde_json::json!({
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [ format!("sha256:{}", layer.digest) ],
+        },
+        "history": [ { "created_by": "base" } ],
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    let port = start_registry("foo/repack", "0.1", config, vec![layer]);
+    let reference = format!("127.0.0.1:{port}/foo/repack:0.1");
+
+    let unpacked = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    // Modify the root filesystem.
+
+    let rootfs = target.path().join("rootfs");
+
+    fs::write(rootfs.join("etc/hostname"), "new").unwrap();
+    fs::write(rootfs.join("etc/added"), "4").unwrap();
+    fs::remove_file(rootfs.join("etc/removed")).unwrap();
+    std::os::unix::fs::symlink("hostname", rootfs.join("etc/link")).unwrap();
+
+    fs::remove_file(rootfs.join("cache/a")).unwrap();
+    fs::remove_file(rootfs.join("cache/b")).unwrap();
+    fs::write(rootfs.join("cache/c"), "").unwrap();
+
+    fs::remove_dir_all(rootfs.join("old")).unwrap();
+    fs::write(rootfs.join("old"), "now a file").unwrap();
+
+    // The layout is in the target directory, because the sandbox of
+    // the unpacker does not allow writes in other directories.
+    let layout = target.path().join("layout");
+
+    let image = Repacker::new(target.path())
+        .created_by("test")
+        .tag("next")
+        .repack(&layout)
+        .expect("Run repacker");
+
+    let index = read_json(layout.join("index.json"));
+    assert_eq!(
+        index["manifests"][0]["digest"],
+        image.manifest_digest.source()
+    );
+    assert_eq!(
+        index["manifests"][0]["annotations"]["org.opencontainers.image.ref.name"],
+        "next"
+    );
+
+    assert!(layout.join("oci-layout").exists());
+    assert_complete_layout(&layout, image.manifest_digest.source());
+
+    let manifest = read_json(blob_path(&layout, image.manifest_digest.source()));
+    assert_eq!(manifest["config"]["digest"], image.config_digest.source());
+    assert_eq!(manifest["layers"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        manifest["layers"][1]["mediaType"],
+        "application/vnd.oci.image.layer.v1.tar+gzip"
+    );
+    assert_eq!(manifest["layers"][1]["digest"], image.layer_digest.source());
+
+    let config = read_json(blob_path(&layout, image.config_digest.source()));
+    assert_eq!(config["rootfs"]["diff_ids"][1], image.diff_id.source());
+    assert_eq!(config["history"][1]["created_by"], "test");
+
+    let (entries, diff_id) = read_layer(&blob_path(&layout, image.layer_digest.source()));
+    assert_eq!(diff_id, image.diff_id.source());
+
+    assert_eq!(
+        entries,
+        [
+            ".wh.old",
+            "cache/.wh..wh..opq",
+            "etc/.wh.removed",
+            "cache",
+            "cache/c",
+            "etc",
+            "etc/added",
+            "etc/hostname",
+            "etc/link",
+            "old",
+        ]
+    );
+
+    // The target directory is updated for the new image.
+
+    let old_mtree = format!("sha256_{}.mtree", unpacked.manifest_digest.hash_value());
+    let new_mtree = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    assert!(!target.path().join(old_mtree).exists());
+    assert!(target.path().join(new_mtree).exists());
+
+    // A second repack without changes generates an empty layer.
+
+    let second = Repacker::new(target.path())
+        .repack(&layout)
+        .expect("Run repacker");
+
+    let (entries, _) = read_layer(&blob_path(&layout, second.layer_digest.source()));
+    assert!(entries.is_empty(), "{entries:?}");
+
+    assert_complete_layout(&layout, second.manifest_digest.source());
+
+    // The new layers are kept for repacks to other layouts.
+    let other_layout = target.path().join("other-layout");
+    let third = Repacker::new(target.path())
+        .repack(&other_layout)
+        .expect("Run repacker");
+
+    assert_complete_layout(&other_layout, third.manifest_digest.source());
+
+    let config = read_json(blob_path(&layout, second.config_digest.source()));
+    assert_eq!(config["rootfs"]["diff_ids"].as_array().unwrap().len(), 3);
+
+    let index = read_json(layout.join("index.json"));
+    assert_eq!(index["manifests"].as_array().unwrap().len(), 2);
+
+    // No temporary files are left in the layout.
+    for entry in fs::read_dir(layout.join("blobs/sha256")).unwrap() {
+        let name = entry.unwrap().file_name();
+        assert!(!name.to_string_lossy().starts_with('.'), "{name:?}");
+    }
+}
+
+#[test]
+fn repack_rewritten_file() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layer = Blob::archive(MediaType::OciFsTar)
+        .directory("etc")
+        .regular("etc/hostname", "abc")
+        .build();
+
+    let config = serde_json::json!({
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [ format!("sha256:{}", layer.digest) ],
+        },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    let port = start_registry("foo/repack", "0.1", config, vec![layer]);
+    let reference = format!("127.0.0.1:{port}/foo/repack:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    // Write new contents with the same size, and restore the mtime,
+    // like `touch -r`.
+    let path = target.path().join("rootfs/etc/hostname");
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+
+    fs::write(&path, "xyz").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let layout = target.path().join("layout");
+    let image = Repacker::new(target.path())
+        .repack(&layout)
+        .expect("Run repacker");
+
+    let (entries, _) = read_layer(&blob_path(&layout, image.layer_digest.source()));
+    assert_eq!(entries, ["etc/hostname"]);
+}
+
+#[test]
+fn repack_mtime_nanoseconds() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "abc")
+        .build();
+
+    let config = serde_json::json!({
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [ format!("sha256:{}", layer.digest) ],
+        },
+    });
+
+    let config = Blob::new(MediaType::OciConfig, serde_json::to_vec(&config).unwrap());
+
+    let port = start_registry("foo/repack-mtime", "0.1", config, vec![layer]);
+    let reference = format!("127.0.0.1:{port}/foo/repack-mtime:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 123_456_789);
+
+    let path = target.path().join("rootfs/b");
+    fs::write(&path, "xyz").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let layout = target.path().join("layout");
+    let image = Repacker::new(target.path())
+        .repack(&layout)
+        .expect("Run repacker");
+
+    let mut data = Vec::new();
+    flate2::read::GzDecoder::new(
+        fs::File::open(blob_path(&layout, image.layer_digest.source())).unwrap(),
+    )
+    .read_to_end(&mut data)
+    .unwrap();
+
+    let mut archive = tar::Archive::new(&data[..]);
+    let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.path().unwrap().to_str(), Some("b"));
+    assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+
+    let records: Vec<_> = entry
+        .pax_extensions()
+        .unwrap()
+        .unwrap()
+        .map(|r| {
+            let r = r.unwrap();
+            (r.key().unwrap().to_owned(), r.value().unwrap().to_owned())
+        })
+        .collect();
+
+    assert_eq!(
+        records,
+        [("mtime".to_owned(), "1700000000.123456789".to_owned())]
+    );
+}
+
+#[test]
+fn missing_state() {
+    let target = tempfile::tempdir().unwrap();
+
+    let result = Repacker::new(target.path()).repack(target.path().join("layout"));
+    assert!(matches!(result, Err(RepackError::MissingState(_))));
+}
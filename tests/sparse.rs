@@ -0,0 +1,112 @@
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::{Blob, BlobArchive},
+    registry::{self, start_registry},
+};
+
+const REAL_SIZE: u64 = 8 * 1024 * 1024;
+
+const REGIONS: &[(u64, &[u8])] = &[
+    (0, b"first"),
+    (1024 * 1024, b"second"),
+    (2 * 1024 * 1024 + 17, b"third"),
+    (3 * 1024 * 1024, b"fourth"),
+    (5 * 1024 * 1024, b"fifth"),
+    (7 * 1024 * 1024 + 1, b"sixth"),
+];
+
+fn unpack(
+    repository: &'static str,
+    archive: BlobArchive,
+    extract_threads: usize,
+) -> tempfile::TempDir {
+    let target = tempfile::tempdir().unwrap();
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, vec![archive.build()]);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .extract_threads(extract_threads)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    target
+}
+
+/// Check that the file has the expected contents, and that the holes
+/// are not allocated.
+fn check_sparse(path: &Path, regions: &[(u64, &[u8])], real_size: u64) {
+    let mut expected = vec![0; real_size as usize];
+    for (offset, data) in regions {
+        let offset = *offset as usize;
+        expected[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    assert!(fs::read(path).unwrap() == expected, "Contents of {path:?}");
+
+    let metadata = fs::metadata(path).unwrap();
+    assert_eq!(metadata.len(), real_size);
+    assert!(
+        metadata.blocks() * 512 < real_size / 4,
+        "{path:?} uses {} blocks",
+        metadata.blocks()
+    );
+}
+
+#[test]
+fn gnu_sparse() {
+    let archive = Blob::archive(MediaType::OciFsTar)
+        .gnu_sparse("short", &REGIONS[..3], REAL_SIZE)
+        .gnu_sparse("extended", REGIONS, REAL_SIZE)
+        .gnu_sparse("trailing-hole", &REGIONS[..1], REAL_SIZE);
+
+    let target = unpack("foo/gnu-sparse", archive, 1);
+    let rootfs = target.path().join("rootfs");
+
+    check_sparse(&rootfs.join("short"), &REGIONS[..3], REAL_SIZE);
+    check_sparse(&rootfs.join("extended"), REGIONS, REAL_SIZE);
+    check_sparse(&rootfs.join("trailing-hole"), &REGIONS[..1], REAL_SIZE);
+}
+
+#[test]
+fn pax_sparse() {
+    let archive = Blob::archive(MediaType::OciFsTarGzip)
+        .directory("dir")
+        .pax_sparse("dir/v00", "0.0", REGIONS, REAL_SIZE)
+        .pax_sparse("dir/v01", "0.1", REGIONS, REAL_SIZE)
+        .pax_sparse("dir/v10", "1.0", REGIONS, REAL_SIZE);
+
+    let target = unpack("foo/pax-sparse", archive, 1);
+    let rootfs = target.path().join("rootfs");
+
+    for name in ["v00", "v01", "v10"] {
+        check_sparse(&rootfs.join("dir").join(name), REGIONS, REAL_SIZE);
+    }
+
+    assert!(!rootfs.join("GNUSparseFile.0").exists());
+}
+
+#[test]
+fn sparse_with_threads() {
+    // Files smaller than the buffer limit are written by the workers.
+    let size = 512 * 1024;
+    let regions: &[(u64, &[u8])] = &[(10, b"a"), (300 * 1024, b"b")];
+
+    let archive = Blob::archive(MediaType::OciFsTar)
+        .gnu_sparse("gnu", regions, size)
+        .pax_sparse("pax", "1.0", regions, size);
+
+    let target = unpack("foo/sparse-threads", archive, 4);
+    let rootfs = target.path().join("rootfs");
+
+    check_sparse(&rootfs.join("gnu"), regions, size);
+    check_sparse(&rootfs.join("pax"), regions, size);
+}
@@ -0,0 +1,151 @@
+use std::{
+    fs, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use oci_unpack::{errors::UnpackError, EventHandler, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Count the bytes received from the registry.
+#[derive(Clone, Default)]
+struct Received(Arc<AtomicUsize>);
+
+impl EventHandler for Received {
+    fn download_progress_bytes(&self, bytes: usize) {
+        self.0.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+fn unpack(
+    repository: &'static str,
+    layers: Vec<Blob>,
+    target: &std::path::Path,
+) -> Result<(), UnpackError> {
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry(repository, "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/{repository}:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .stream_layers(true)
+        .unpack(target)
+        .map(|_| ())
+}
+
+#[test]
+fn stream_layers() {
+    let target = tempfile::tempdir().unwrap();
+    let received = Received::default();
+
+    let layers = vec![
+        Blob::archive(MediaType::OciFsTarGzip)
+            .directory("etc")
+            .regular("etc/hostname", "abc")
+            .regular("etc/removed", "1")
+            .build(),
+        Blob::archive(MediaType::OciFsTar)
+            .regular("etc/.wh.removed", "")
+            .regular("etc/other", "2")
+            .build(),
+    ];
+
+    let total: usize = layers.iter().map(|l| l.size).sum();
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+    let port = start_registry("foo/streaming", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/streaming:0.1");
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .event_handler(received.clone())
+        .stream_layers(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    assert_eq!(
+        fs::read_to_string(rootfs.join("etc/hostname")).unwrap(),
+        "abc"
+    );
+    assert_eq!(fs::read_to_string(rootfs.join("etc/other")).unwrap(), "2");
+    assert!(!rootfs.join("etc/removed").exists());
+
+    assert_eq!(received.0.load(Ordering::SeqCst), total);
+}
+
+#[test]
+fn discard_rootfs_on_invalid_digest() {
+    let target = tempfile::tempdir().unwrap();
+
+    let mut layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "0123456789")
+        .build();
+
+    // Modify the contents of the file, so the archive is still valid,
+    // but its digest is not the expected one.
+    let mut data = layer.data.to_vec();
+    data[512] = b'x';
+    layer.data = data.into();
+
+    let result = unpack("foo/streaming-digest", vec![layer], target.path());
+
+    match result {
+        Err(UnpackError::Io(e, _)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        r => panic!("Unexpected result: {r:?}"),
+    }
+
+    assert!(!target.path().join("rootfs").exists());
+}
+
+#[test]
+fn invalid_size() {
+    let target = tempfile::tempdir().unwrap();
+
+    let mut layer = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "0123456789")
+        .build();
+
+    layer.size -= 1;
+
+    let result = unpack("foo/streaming-size", vec![layer], target.path());
+
+    assert!(matches!(result, Err(UnpackError::InvalidBlobSize(_))));
+    assert!(!target.path().join("rootfs").exists());
+}
+
+#[test]
+fn stream_only_first_layer() {
+    let target = tempfile::tempdir().unwrap();
+
+    let first = Blob::archive(MediaType::OciFsTar)
+        .regular("a", "first")
+        .build();
+
+    let mut second = Blob::archive(MediaType::OciFsTar)
+        .regular("b", "0123456789")
+        .build();
+
+    let mut data = second.data.to_vec();
+    data[512] = b'x';
+    second.data = data.into();
+
+    let result = unpack("foo/streaming-first", vec![first, second], target.path());
+    assert!(result.is_err());
+
+    // The second layer is downloaded to a file, so its digest is
+    // verified before it is extracted, and the files from the
+    // streamed layer are kept.
+    let rootfs = target.path().join("rootfs");
+    assert_eq!(fs::read_to_string(rootfs.join("a")).unwrap(), "first");
+    assert!(!rootfs.join("b").exists());
+}
@@ -0,0 +1,69 @@
+use std::{fs, os::unix::fs::MetadataExt};
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+#[test]
+fn nanosecond_timestamps() {
+    let target = tempfile::tempdir().unwrap();
+
+    let times = [("mtime", "1700000000.123456789"), ("atime", "1600000000.5")];
+
+    let layers = vec![Blob::archive(MediaType::OciFsTar)
+        .pax_records(&times)
+        .directory("etc")
+        .pax_records(&times)
+        .regular("etc/hostname", "abc")
+        .pax_records(&times)
+        .symlink("etc/link", "hostname")
+        .regular("etc/seconds", "")
+        .build()];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/timestamps", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/timestamps:0.1");
+
+    let image = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let times = |path| {
+        let metadata = fs::symlink_metadata(target.path().join("rootfs").join(path)).unwrap();
+        (
+            (metadata.mtime(), metadata.mtime_nsec()),
+            (metadata.atime(), metadata.atime_nsec()),
+        )
+    };
+
+    let expected = ((1700000000, 123456789), (1600000000, 500000000));
+
+    assert_eq!(times("etc"), expected);
+    assert_eq!(times("etc/hostname"), expected);
+    assert_eq!(times("etc/link"), expected);
+
+    // Without PAX records, atime is the same as mtime.
+    assert_eq!(times("etc/seconds"), ((0, 0), (0, 0)));
+
+    // The mtree manifest has the same timestamps.
+    let path = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    let mtree = fs::read_to_string(target.path().join(path)).unwrap();
+
+    for (path, time) in [
+        ("./etc ", "time=1700000000.123456789"),
+        ("./etc/hostname ", "time=1700000000.123456789"),
+        ("./etc/link ", "time=1700000000.123456789"),
+        ("./etc/seconds ", "time=0.000000000"),
+    ] {
+        let line = mtree.lines().find(|l| l.starts_with(path)).unwrap();
+        assert!(line.contains(time), "{line}");
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+
+use oci_unpack::{MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+#[test]
+fn whiteouts_after_entries() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layers = vec![
+        Blob::archive(MediaType::OciFsTar)
+            .regular("w/replaced", "lower")
+            .regular("w/removed", "lower")
+            .regular("opq/lower", "lower")
+            .regular("opq/dir/lower", "lower")
+            .regular("device", "lower")
+            .regular("kept", "lower")
+            .build(),
+        //
+        // Whiteouts after the entries of the same layer.
+        Blob::archive(MediaType::OciFsTarGzip)
+            .regular("w/replaced", "upper")
+            .regular("w/new", "upper")
+            .regular("w/.wh.replaced", "")
+            .regular("w/.wh.new", "")
+            .regular("w/.wh.removed", "")
+            .regular("opq/added", "upper")
+            .regular("opq/dir/added", "upper")
+            .regular("opq/.wh..wh..opq", "")
+            .node("device", tar::EntryType::Char, (0, 0))
+            //
+            // AUFS metadata.
+            .directory(".wh..wh.plnk")
+            .regular(".wh..wh.plnk/1.2", "")
+            .regular(".wh..wh.aufs", "")
+            .regular(".wh..wh.orph/.wh.kept", "")
+            .build(),
+    ];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+    let port = start_registry("foo/whiteouts", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/whiteouts:0.1");
+
+    let image = Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .mtree(true)
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let rootfs = target.path().join("rootfs");
+    let read = |path: &str| fs::read_to_string(rootfs.join(path)).unwrap();
+    let exists = |path: &str| fs::symlink_metadata(rootfs.join(path)).is_ok();
+
+    // Whiteouts don't remove the entries of the same layer.
+    assert_eq!(read("w/replaced"), "upper");
+    assert_eq!(read("w/new"), "upper");
+    assert!(!exists("w/removed"));
+
+    assert_eq!(read("opq/added"), "upper");
+    assert_eq!(read("opq/dir/added"), "upper");
+    assert!(!exists("opq/lower"));
+    assert!(!exists("opq/dir/lower"));
+
+    // Character devices with number 0/0 are whiteouts.
+    assert!(!exists("device"));
+
+    // AUFS metadata is ignored.
+    assert_eq!(read("kept"), "lower");
+    assert!(!exists(".wh..wh.plnk"));
+    assert!(!exists(".wh..wh.aufs"));
+    assert!(!exists(".wh..wh.orph"));
+
+    let manifest = format!("sha256_{}.mtree", image.manifest_digest.hash_value());
+    let mtree = fs::read_to_string(target.path().join(manifest)).unwrap();
+    let has_entry = |path: &str| mtree.lines().any(|l| l.starts_with(&format!("{path} ")));
+
+    assert!(has_entry("./w/new"));
+    assert!(has_entry("./opq/dir/added"));
+    assert!(!has_entry("./w/removed"));
+    assert!(!has_entry("./opq/dir/lower"));
+    assert!(!has_entry("./device"));
+}
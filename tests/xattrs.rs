@@ -0,0 +1,72 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use oci_unpack::{EventHandler, MediaType, Reference, Unpacker};
+
+pub mod common;
+
+use common::{
+    blobs::Blob,
+    registry::{self, start_registry},
+};
+
+/// Collect the xattrs reported as skipped by the unpacker.
+#[derive(Clone, Default)]
+struct Skipped(Arc<Mutex<Vec<(PathBuf, String)>>>);
+
+impl EventHandler for Skipped {
+    fn xattr_skipped(&self, path: &Path, name: &str, _: &dyn std::fmt::Display) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((path.to_owned(), name.to_owned()));
+    }
+}
+
+#[test]
+fn extract_xattrs() {
+    let target = tempfile::tempdir().unwrap();
+
+    let layers = vec![Blob::archive(MediaType::OciFsTar)
+        .xattrs(&[("user.dir", "1")])
+        .directory("etc")
+        .xattrs(&[("user.file", "2"), ("user.skipped", "3")])
+        .regular("etc/hostname", "abc")
+        .xattrs(&[("user.link", "4")])
+        .symlink("etc/link", "hostname")
+        .build()];
+
+    let config = Blob::new(MediaType::OciConfig, &b"{}"[..]);
+
+    let port = start_registry("foo/xattrs", "0.1", config, layers);
+    let reference = format!("127.0.0.1:{port}/foo/xattrs:0.1");
+
+    let skipped = Skipped::default();
+
+    Unpacker::new(Reference::try_from(reference.as_str()).unwrap())
+        .platform(registry::platform())
+        .event_handler(skipped.clone())
+        .skip_xattrs("user.skip")
+        .unpack(target.path())
+        .expect("Run unpacker");
+
+    let xattr = |path, name| {
+        let path = target.path().join("rootfs").join(path);
+        let mut value = [0; 32];
+        rustix::fs::lgetxattr(&path, name, &mut value)
+            .map(|len| value[..len].to_vec())
+            .ok()
+    };
+
+    assert_eq!(xattr("etc", "user.dir"), Some(b"1".to_vec()));
+    assert_eq!(xattr("etc/hostname", "user.file"), Some(b"2".to_vec()));
+    assert_eq!(xattr("etc/hostname", "user.skipped"), None);
+
+    // Linux does not allow user.* xattrs in symbolic links.
+    assert_eq!(
+        *skipped.0.lock().unwrap(),
+        [(PathBuf::from("etc/link"), "user.link".to_owned())]
+    );
+}